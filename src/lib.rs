@@ -1,25 +1,44 @@
 pub mod nodeclient {
+    use std::collections::HashMap;
     use std::fs::File;
     use std::io::{stdout, BufReader};
     use std::path::{Path, PathBuf};
     use std::str::FromStr;
     use std::string::ParseError;
-    use std::thread;
-    use std::thread::JoinHandle;
 
     use serde::Deserialize;
     use structopt::StructOpt;
 
-    use crate::nodeclient::leaderlog::handle_error;
+    use crate::nodeclient::discover::parse_seed;
+    use crate::nodeclient::leaderlog::{handle_error, read_byron_genesis, read_shelley_genesis};
+    use crate::nodeclient::pinghistory::parse_duration_secs;
+    use crate::nodeclient::pooltool::{NodeVersionSource, PooltoolClient};
+    use crate::nodeclient::propagation::Propagation;
 
+    pub mod discover;
+    pub mod export;
+    pub mod fsck;
+    pub mod headercheck;
+    pub mod health;
     pub mod leaderlog;
     pub mod math;
+    pub mod opcert;
+    pub mod outbox;
     pub mod ping;
+    pub mod pinghistory;
+    pub mod poolstats;
     pub mod pooltool;
+    pub mod propagation;
+    pub mod resolve;
+    pub mod serve;
     pub mod signing;
+    pub mod snapshot;
     pub mod sqlite;
+    pub mod stakeestimate;
     pub mod sync;
-    mod validate;
+    pub mod validate;
+    pub mod versions;
+    pub mod webhook;
 
     pub static APP_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"),);
 
@@ -42,19 +61,188 @@ pub mod nodeclient {
         }
     }
 
+    #[derive(Debug)]
+    pub enum OutputFormat {
+        Json,
+        Table,
+    }
+
+    impl FromStr for OutputFormat {
+        type Err = String;
+        fn from_str(output_format: &str) -> Result<Self, Self::Err> {
+            match output_format {
+                "json" => Ok(OutputFormat::Json),
+                "table" => Ok(OutputFormat::Table),
+                _ => Err(format!("Unknown output format: {}", output_format)),
+            }
+        }
+    }
+
     #[derive(Debug, StructOpt)]
     pub enum Command {
         Ping {
-            #[structopt(short, long, help = "cardano-node hostname to connect to")]
-            host: String,
+            #[structopt(
+                short,
+                long,
+                required_unless_one = &["topology", "report"],
+                conflicts_with = "topology",
+                help = "cardano-node hostname to connect to"
+            )]
+            host: Option<String>,
             #[structopt(short, long, default_value = "3001", help = "cardano-node port")]
             port: u16,
             #[structopt(long, default_value = "764824073", help = "network magic.")]
             network_magic: u32,
+            #[structopt(long, default_value = "0", help = "number of keep-alive round trips to time")]
+            keep_alive: u32,
+            #[structopt(long, help = "request the peer's current tip")]
+            tip: bool,
+            #[structopt(
+                parse(from_os_str),
+                long,
+                help = "byron genesis json file. Shows how far behind the peer's tip is"
+            )]
+            byron_genesis: Option<std::path::PathBuf>,
+            #[structopt(
+                parse(from_os_str),
+                long,
+                help = "shelley genesis json file. Shows how far behind the peer's tip is"
+            )]
+            shelley_genesis: Option<std::path::PathBuf>,
+            #[structopt(parse(from_os_str), long, help = "topology.json file. Pings all producers in it")]
+            topology: Option<std::path::PathBuf>,
+            #[structopt(
+                long,
+                default_value = "10",
+                help = "number of peers to ping at the same time with --topology"
+            )]
+            concurrency: usize,
+            #[structopt(long, help = "keep pinging every interval and save the results to the ping history")]
+            watch: bool,
+            #[structopt(
+                long,
+                default_value = "30s",
+                parse(try_from_str = parse_duration_secs),
+                help = "time between pings with --watch, e.g. 30s, 5m"
+            )]
+            interval: u64,
+            #[structopt(
+                long,
+                conflicts_with = "watch",
+                help = "print uptime and latency from the ping history"
+            )]
+            report: bool,
+            #[structopt(
+                long,
+                default_value = "24h",
+                parse(try_from_str = parse_duration_secs),
+                help = "how far back --report looks, e.g. 1h, 24h, 7d"
+            )]
+            window: u64,
+            #[structopt(
+                parse(from_os_str),
+                short,
+                long,
+                default_value = "./cncli.db",
+                help = "sqlite database file for the ping history"
+            )]
+            db: std::path::PathBuf,
+        },
+        Discover {
+            #[structopt(
+                long,
+                required_unless = "topology",
+                number_of_values = 1,
+                parse(try_from_str = parse_seed),
+                help = "seed relay to start from as host:port. May be repeated"
+            )]
+            seed: Vec<(String, u16)>,
+            #[structopt(
+                parse(from_os_str),
+                long,
+                help = "topology.json file whose producers are used as seeds"
+            )]
+            topology: Option<std::path::PathBuf>,
+            #[structopt(long, default_value = "764824073", help = "network magic.")]
+            network_magic: u32,
+            #[structopt(long, default_value = "100", help = "stop crawling after this many relays")]
+            max_peers: usize,
+            #[structopt(long, default_value = "10", help = "number of relays to ask each peer for")]
+            share_peers: u8,
+            #[structopt(long, default_value = "10", help = "number of relays to ping at the same time")]
+            concurrency: usize,
+            #[structopt(
+                parse(from_os_str),
+                long,
+                help = "write the reachable relays to this topology.json file"
+            )]
+            topology_out: Option<std::path::PathBuf>,
+        },
+        Export {
+            #[structopt(
+                parse(from_os_str),
+                short,
+                long,
+                default_value = "./cncli.db",
+                help = "sqlite database file"
+            )]
+            db: std::path::PathBuf,
+            #[structopt(long, default_value = "chain", possible_values = &["chain", "slots"], help = "table to export")]
+            table: export::ExportTable,
+            #[structopt(long, default_value = "csv", possible_values = &["csv", "jsonl", "parquet"], help = "export format")]
+            format: export::ExportFormat,
+            #[structopt(parse(from_os_str), short, long, help = "file to export to instead of stdout")]
+            output: Option<std::path::PathBuf>,
+            #[structopt(long, help = "first epoch to export")]
+            from_epoch: Option<i64>,
+            #[structopt(long, help = "last epoch to export")]
+            to_epoch: Option<i64>,
+            #[structopt(long, help = "only export the blocks or slots of this pool id")]
+            pool_id: Option<String>,
+            #[structopt(parse(from_os_str), long, help = "byron genesis json file")]
+            byron_genesis: Option<std::path::PathBuf>,
+            #[structopt(parse(from_os_str), long, help = "shelley genesis json file")]
+            shelley_genesis: Option<std::path::PathBuf>,
+        },
+        Fsck {
+            #[structopt(
+                parse(from_os_str),
+                short,
+                long,
+                default_value = "./cncli.db",
+                help = "sqlite database file"
+            )]
+            db: std::path::PathBuf,
+            #[structopt(
+                long,
+                help = "re-mark orphans and recompute eta_v and pool_id where they are inconsistent"
+            )]
+            repair: bool,
+            #[structopt(long, default_value = "100", help = "maximum number of issues to list")]
+            limit: usize,
         },
         Validate {
-            #[structopt(long, help = "full or partial block hash to validate")]
-            hash: String,
+            #[structopt(
+                long,
+                required_unless_one = &["block-number", "slot"],
+                conflicts_with_all = &["block-number", "slot"],
+                help = "full or partial block hash to validate"
+            )]
+            hash: Option<String>,
+            #[structopt(long, conflicts_with = "slot", help = "block number to validate")]
+            block_number: Option<i64>,
+            #[structopt(long, help = "slot number of the block to validate")]
+            slot: Option<i64>,
+            #[structopt(
+                parse(from_os_str),
+                short,
+                long,
+                default_value = "./cncli.db",
+                help = "sqlite database file"
+            )]
+            db: std::path::PathBuf,
+        },
+        VerifyLeader {
             #[structopt(
                 parse(from_os_str),
                 short,
@@ -63,6 +251,33 @@ pub mod nodeclient {
                 help = "sqlite database file"
             )]
             db: std::path::PathBuf,
+            #[structopt(parse(from_os_str), long, help = "byron genesis json file")]
+            byron_genesis: std::path::PathBuf,
+            #[structopt(parse(from_os_str), long, help = "shelley genesis json file")]
+            shelley_genesis: std::path::PathBuf,
+            #[structopt(long, help = "full or partial block hash to verify")]
+            hash: String,
+            #[structopt(
+                long,
+                required_unless_one = &["pool-stake", "active-stake"],
+                conflicts_with_all = &["pool-stake", "active-stake"],
+                help = "pool's share of the active stake snapshot, e.g. 0.0012"
+            )]
+            sigma: Option<f64>,
+            #[structopt(
+                long,
+                requires = "active-stake",
+                help = "pool active stake snapshot value in lovelace"
+            )]
+            pool_stake: Option<u64>,
+            #[structopt(
+                long,
+                requires = "pool-stake",
+                help = "total active stake snapshot value in lovelace"
+            )]
+            active_stake: Option<u64>,
+            #[structopt(long, help = "hex string of the extra entropy value")]
+            extra_entropy: Option<String>,
         },
         Sync {
             #[structopt(
@@ -81,6 +296,29 @@ pub mod nodeclient {
             network_magic: u32,
             #[structopt(long, help = "Exit at 100% sync'd.")]
             no_service: bool,
+            #[structopt(
+                parse(from_os_str),
+                long,
+                help = "webhook config file for tip and block notifications"
+            )]
+            webhook_config: Option<std::path::PathBuf>,
+            #[structopt(
+                long,
+                requires_all = &["byron-genesis", "shelley-genesis"],
+                possible_values = &["flag", "reject"],
+                help = "verify each block header and flag or reject invalid ones. The KES signature is not checked"
+            )]
+            verify_headers: Option<headercheck::HeaderCheckMode>,
+            #[structopt(parse(from_os_str), long, help = "byron genesis json file")]
+            byron_genesis: Option<std::path::PathBuf>,
+            #[structopt(parse(from_os_str), long, help = "shelley genesis json file")]
+            shelley_genesis: Option<std::path::PathBuf>,
+            #[structopt(
+                long,
+                number_of_values = 1,
+                help = "epoch:hex of extra entropy mixed into the nonce of an epoch"
+            )]
+            epoch_extra_entropy: Vec<String>,
         },
         Leaderlog {
             #[structopt(
@@ -120,6 +358,34 @@ pub mod nodeclient {
             )]
             timezone: String,
         },
+        StakeEstimate {
+            #[structopt(
+                parse(from_os_str),
+                short,
+                long,
+                default_value = "./cncli.db",
+                help = "sqlite database file"
+            )]
+            db: std::path::PathBuf,
+            #[structopt(parse(from_os_str), long, help = "byron genesis json file")]
+            byron_genesis: std::path::PathBuf,
+            #[structopt(parse(from_os_str), long, help = "shelley genesis json file")]
+            shelley_genesis: std::path::PathBuf,
+            #[structopt(
+                long,
+                default_value = "5",
+                help = "number of epochs to sample, ending with the current epoch"
+            )]
+            epochs: i64,
+            #[structopt(long, default_value = "0.95", help = "confidence level of the stake intervals")]
+            confidence: f64,
+            #[structopt(long, help = "total active stake in lovelace. Converts sigma estimates to lovelace")]
+            active_stake: Option<u64>,
+            #[structopt(long, help = "only show this many of the top ranked pools")]
+            limit: Option<usize>,
+            #[structopt(long, default_value = "json", help = "output format. json or table")]
+            output: OutputFormat,
+        },
         Sendtip {
             #[structopt(
                 parse(from_os_str),
@@ -131,9 +397,71 @@ pub mod nodeclient {
             #[structopt(
                 parse(from_os_str),
                 long,
-                help = "path to cardano-node executable for gathering version info"
+                help = "path to cardano-node executable for gathering version info, if not set in the config"
             )]
-            cardano_node: std::path::PathBuf,
+            cardano_node: Option<std::path::PathBuf>,
+            #[structopt(
+                parse(from_os_str),
+                short,
+                long,
+                default_value = "./cncli.db",
+                help = "sqlite database file for the outbox of failed submissions, only opened once a submission fails"
+            )]
+            db: std::path::PathBuf,
+            #[structopt(
+                parse(from_os_str),
+                long,
+                help = "byron genesis json file. Enables tip propagation delay measurement"
+            )]
+            byron_genesis: Option<std::path::PathBuf>,
+            #[structopt(
+                parse(from_os_str),
+                long,
+                help = "shelley genesis json file. Enables tip propagation delay measurement"
+            )]
+            shelley_genesis: Option<std::path::PathBuf>,
+            #[structopt(
+                parse(from_os_str),
+                long,
+                help = "json file to keep updated with the tip propagation delays of each relay"
+            )]
+            propagation_stats: Option<std::path::PathBuf>,
+        },
+        Outbox {
+            #[structopt(subcommand)]
+            command: OutboxCommand,
+        },
+        Snapshot {
+            #[structopt(subcommand)]
+            command: SnapshotCommand,
+        },
+        Serve {
+            #[structopt(
+                parse(from_os_str),
+                short,
+                long,
+                default_value = "./cncli.db",
+                help = "sqlite database file"
+            )]
+            db: std::path::PathBuf,
+            #[structopt(long, default_value = "127.0.0.1:8080", help = "address and port to listen on")]
+            bind: String,
+            #[structopt(parse(from_os_str), long, help = "byron genesis json file")]
+            byron_genesis: std::path::PathBuf,
+            #[structopt(parse(from_os_str), long, help = "shelley genesis json file")]
+            shelley_genesis: std::path::PathBuf,
+            #[structopt(
+                long = "tz",
+                default_value = "America/Los_Angeles",
+                help = "TimeZone string from the IANA database - https://en.wikipedia.org/wiki/List_of_tz_database_time_zones"
+            )]
+            timezone: String,
+            #[structopt(
+                long,
+                number_of_values = 1,
+                help = "epoch:hex of extra entropy mixed into the nonce of an epoch"
+            )]
+            epoch_extra_entropy: Vec<String>,
         },
         Sendslots {
             #[structopt(
@@ -194,6 +522,117 @@ pub mod nodeclient {
             )]
             ledger_set: LedgerSet,
         },
+        Opcert {
+            #[structopt(
+                parse(from_os_str),
+                short,
+                long,
+                default_value = "./cncli.db",
+                help = "sqlite database file"
+            )]
+            db: std::path::PathBuf,
+            #[structopt(parse(from_os_str), long, help = "byron genesis json file")]
+            byron_genesis: std::path::PathBuf,
+            #[structopt(parse(from_os_str), long, help = "shelley genesis json file")]
+            shelley_genesis: std::path::PathBuf,
+            #[structopt(long, help = "lower-case hex pool id")]
+            pool_id: String,
+        },
+        CheckOpcert {
+            #[structopt(
+                parse(from_os_str),
+                short,
+                long,
+                default_value = "./cncli.db",
+                help = "sqlite database file"
+            )]
+            db: std::path::PathBuf,
+            #[structopt(parse(from_os_str), long, help = "shelley genesis json file")]
+            shelley_genesis: std::path::PathBuf,
+            #[structopt(parse(from_os_str), long, help = "pool's node.cert operational certificate file")]
+            opcert_file: std::path::PathBuf,
+            #[structopt(long, help = "lower-case hex pool id")]
+            pool_id: String,
+        },
+        Versions {
+            #[structopt(
+                parse(from_os_str),
+                short,
+                long,
+                default_value = "./cncli.db",
+                help = "sqlite database file"
+            )]
+            db: std::path::PathBuf,
+            #[structopt(parse(from_os_str), long, help = "byron genesis json file")]
+            byron_genesis: std::path::PathBuf,
+            #[structopt(parse(from_os_str), long, help = "shelley genesis json file")]
+            shelley_genesis: std::path::PathBuf,
+            #[structopt(
+                long,
+                default_value = "1",
+                help = "number of epochs to report, ending with the current epoch"
+            )]
+            epochs: i64,
+            #[structopt(long, help = "lower-case hex pool id to report the last signalled version for")]
+            pool_id: Option<String>,
+        },
+        PoolStats {
+            #[structopt(
+                parse(from_os_str),
+                short,
+                long,
+                default_value = "./cncli.db",
+                help = "sqlite database file"
+            )]
+            db: std::path::PathBuf,
+            #[structopt(parse(from_os_str), long, help = "byron genesis json file")]
+            byron_genesis: std::path::PathBuf,
+            #[structopt(parse(from_os_str), long, help = "shelley genesis json file")]
+            shelley_genesis: std::path::PathBuf,
+            #[structopt(long, help = "lower-case hex pool id")]
+            pool_id: String,
+            #[structopt(
+                long,
+                help = "number of epochs to report, ending with the current epoch. Defaults to all epochs since the pool's first block"
+            )]
+            epochs: Option<i64>,
+            #[structopt(long, default_value = "json", help = "output format. json or table")]
+            output: OutputFormat,
+        },
+        ChainHealth {
+            #[structopt(
+                parse(from_os_str),
+                short,
+                long,
+                default_value = "./cncli.db",
+                help = "sqlite database file"
+            )]
+            db: std::path::PathBuf,
+            #[structopt(parse(from_os_str), long, help = "byron genesis json file")]
+            byron_genesis: std::path::PathBuf,
+            #[structopt(parse(from_os_str), long, help = "shelley genesis json file")]
+            shelley_genesis: std::path::PathBuf,
+            #[structopt(
+                long,
+                default_value = "1",
+                help = "number of epochs to report, ending with the current epoch"
+            )]
+            epochs: i64,
+            #[structopt(long, help = "first slot to report. Overrides --epochs")]
+            start_slot: Option<i64>,
+            #[structopt(long, help = "last slot to report. Defaults to the tip")]
+            end_slot: Option<i64>,
+            #[structopt(long, help = "window size in slots. Defaults to the stability window (3k/f)")]
+            window: Option<i64>,
+            #[structopt(
+                long,
+                default_value = "0.8",
+                help = "flag windows with a density below this fraction of activeSlotsCoeff"
+            )]
+            density_threshold: f64,
+            #[structopt(long, default_value = "json", help = "output format. json or table")]
+            output: OutputFormat,
+        },
         Challenge {
             #[structopt(long, help = "validating domain e.g. pooltool.io")]
             domain: String,
@@ -223,17 +662,284 @@ pub mod nodeclient {
         },
     }
 
+    #[derive(Debug, StructOpt)]
+    pub enum OutboxCommand {
+        #[structopt(about = "list pooltool submissions waiting to be retried")]
+        List {
+            #[structopt(
+                parse(from_os_str),
+                short,
+                long,
+                default_value = "./cncli.db",
+                help = "sqlite database file"
+            )]
+            db: std::path::PathBuf,
+        },
+        #[structopt(about = "retry every waiting pooltool submission now")]
+        Flush {
+            #[structopt(
+                parse(from_os_str),
+                short,
+                long,
+                default_value = "./cncli.db",
+                help = "sqlite database file"
+            )]
+            db: std::path::PathBuf,
+            #[structopt(
+                parse(from_os_str),
+                long,
+                default_value = "./pooltool.json",
+                help = "pooltool config file"
+            )]
+            config: std::path::PathBuf,
+        },
+        #[structopt(about = "discard waiting pooltool submissions")]
+        Drop {
+            #[structopt(
+                parse(from_os_str),
+                short,
+                long,
+                default_value = "./cncli.db",
+                help = "sqlite database file"
+            )]
+            db: std::path::PathBuf,
+            #[structopt(long, help = "id of the submission to discard")]
+            id: Option<i64>,
+            #[structopt(long, help = "discard all submissions")]
+            all: bool,
+        },
+    }
+
+    #[derive(Debug, StructOpt)]
+    pub enum SnapshotCommand {
+        #[structopt(about = "write a compressed, checksummed snapshot of the database")]
+        Create {
+            #[structopt(
+                parse(from_os_str),
+                short,
+                long,
+                default_value = "./cncli.db",
+                help = "sqlite database file"
+            )]
+            db: std::path::PathBuf,
+            #[structopt(
+                parse(from_os_str),
+                short,
+                long,
+                default_value = "./cncli-snapshot.tar.gz",
+                help = "snapshot file to write"
+            )]
+            output: std::path::PathBuf,
+            #[structopt(long, default_value = "764824073", help = "network magic of the database")]
+            network_magic: u32,
+        },
+        #[structopt(about = "verify a snapshot and restore the database from it")]
+        Restore {
+            #[structopt(
+                parse(from_os_str),
+                short,
+                long,
+                default_value = "./cncli.db",
+                help = "sqlite database file"
+            )]
+            db: std::path::PathBuf,
+            #[structopt(
+                parse(from_os_str),
+                short,
+                long,
+                default_value = "./cncli-snapshot.tar.gz",
+                help = "snapshot file to restore"
+            )]
+            input: std::path::PathBuf,
+            #[structopt(long, help = "refuse snapshots of another network magic")]
+            network_magic: Option<u32>,
+            #[structopt(long, help = "replace an existing database")]
+            force: bool,
+        },
+    }
+
     pub fn start(cmd: Command) {
         match cmd {
             Command::Ping {
                 ref host,
                 ref port,
                 ref network_magic,
+                ref keep_alive,
+                ref tip,
+                ref byron_genesis,
+                ref shelley_genesis,
+                ref topology,
+                ref concurrency,
+                ref watch,
+                ref interval,
+                ref report,
+                ref window,
+                ref db,
+            } => {
+                if *report {
+                    pinghistory::report(db, *window);
+                    return;
+                }
+                let genesis = match (byron_genesis, shelley_genesis) {
+                    (Some(byron_genesis), Some(shelley_genesis)) => {
+                        match (read_byron_genesis(byron_genesis), read_shelley_genesis(shelley_genesis)) {
+                            (Ok(byron), Ok(shelley)) => Some((byron, shelley)),
+                            (Err(error), _) | (_, Err(error)) => {
+                                handle_error(error);
+                                return;
+                            }
+                        }
+                    }
+                    (None, None) => None,
+                    _ => {
+                        handle_error("both --byron-genesis and --shelley-genesis are required!");
+                        return;
+                    }
+                };
+                let options = ping::PingOptions {
+                    keep_alive: *keep_alive,
+                    tip: *tip,
+                    genesis,
+                    ..Default::default()
+                };
+                if *watch {
+                    let peers = match (host, topology) {
+                        (_, Some(topology)) => match ping::read_topology(topology) {
+                            Ok(peers) => peers,
+                            Err(error) => {
+                                handle_error(error);
+                                return;
+                            }
+                        },
+                        (Some(host), None) => vec![(host.clone(), *port)],
+                        (None, None) => {
+                            handle_error("--host or --topology is required!");
+                            return;
+                        }
+                    };
+                    pinghistory::watch(db, &peers, *network_magic, *concurrency, *interval, &options);
+                    return;
+                }
+                match (host, topology) {
+                    (_, Some(topology)) => {
+                        if let Err(error) =
+                            ping::ping_topology(&mut stdout(), topology, *network_magic, *concurrency, &options)
+                        {
+                            handle_error(error);
+                        }
+                    }
+                    (Some(host), None) => {
+                        ping::ping_with_options(&mut stdout(), host.as_str(), *port, *network_magic, &options);
+                    }
+                    (None, None) => handle_error("--host or --topology is required!"),
+                }
+            }
+            Command::Discover {
+                ref seed,
+                ref topology,
+                ref network_magic,
+                ref max_peers,
+                ref share_peers,
+                ref concurrency,
+                ref topology_out,
+            } => {
+                let mut seeds = seed.clone();
+                if let Some(topology) = topology {
+                    match ping::read_topology(topology) {
+                        Ok(peers) => seeds.extend(peers),
+                        Err(error) => {
+                            handle_error(error);
+                            return;
+                        }
+                    }
+                }
+                discover::discover(
+                    seeds,
+                    *network_magic,
+                    *max_peers,
+                    *share_peers,
+                    *concurrency,
+                    topology_out,
+                );
+            }
+            Command::Export {
+                ref db,
+                ref table,
+                ref format,
+                ref output,
+                ref from_epoch,
+                ref to_epoch,
+                ref pool_id,
+                ref byron_genesis,
+                ref shelley_genesis,
+            } => {
+                export::export(
+                    db,
+                    &export::ExportOptions {
+                        table: *table,
+                        format: *format,
+                        from_epoch: *from_epoch,
+                        to_epoch: *to_epoch,
+                        pool_id: pool_id.clone(),
+                        byron_genesis: byron_genesis.clone(),
+                        shelley_genesis: shelley_genesis.clone(),
+                    },
+                    output,
+                );
+            }
+            Command::Fsck {
+                ref db,
+                ref repair,
+                ref limit,
             } => {
-                ping::ping(&mut stdout(), host.as_str(), *port, *network_magic);
+                fsck::fsck(db, *repair, *limit);
             }
-            Command::Validate { ref db, ref hash } => {
-                validate::validate_block(db, hash.as_str());
+            Command::Validate {
+                ref db,
+                ref hash,
+                ref block_number,
+                ref slot,
+            } => {
+                let block_query = match (hash, block_number, slot) {
+                    (Some(hash), _, _) => validate::BlockQuery::HashPrefix(hash),
+                    (None, Some(block_number), _) => validate::BlockQuery::BlockNumber(*block_number),
+                    (None, None, Some(slot)) => validate::BlockQuery::Slot(*slot),
+                    (None, None, None) => {
+                        handle_error("--hash, --block-number or --slot is required!");
+                        return;
+                    }
+                };
+                validate::validate_block(db, &block_query);
+            }
+            Command::VerifyLeader {
+                ref db,
+                ref byron_genesis,
+                ref shelley_genesis,
+                ref hash,
+                ref sigma,
+                ref pool_stake,
+                ref active_stake,
+                ref extra_entropy,
+            } => {
+                let leader_stake = match (sigma, pool_stake, active_stake) {
+                    (Some(sigma), _, _) => validate::LeaderStake::Sigma(*sigma),
+                    (None, Some(pool_stake), Some(active_stake)) => validate::LeaderStake::Snapshot {
+                        pool_stake: *pool_stake,
+                        active_stake: *active_stake,
+                    },
+                    _ => {
+                        handle_error("--sigma or both --pool-stake and --active-stake are required!");
+                        return;
+                    }
+                };
+                validate::verify_leader(
+                    db,
+                    byron_genesis,
+                    shelley_genesis,
+                    hash.as_str(),
+                    &leader_stake,
+                    extra_entropy,
+                );
             }
             Command::Sync {
                 ref db,
@@ -241,8 +947,49 @@ pub mod nodeclient {
                 ref port,
                 ref network_magic,
                 ref no_service,
+                ref webhook_config,
+                ref verify_headers,
+                ref byron_genesis,
+                ref shelley_genesis,
+                ref epoch_extra_entropy,
             } => {
-                sync::sync(db, host.as_str(), *port, *network_magic, *no_service);
+                let header_check = match verify_headers {
+                    Some(mode) => {
+                        let (byron, shelley) = match (
+                            read_byron_genesis(byron_genesis.as_ref().unwrap()),
+                            read_shelley_genesis(shelley_genesis.as_ref().unwrap()),
+                        ) {
+                            (Ok(byron), Ok(shelley)) => (byron, shelley),
+                            (Err(error), _) | (_, Err(error)) => {
+                                handle_error(error);
+                                return;
+                            }
+                        };
+                        let mut extra_entropy: HashMap<i64, String> = HashMap::new();
+                        for epoch_extra_entropy in epoch_extra_entropy.iter() {
+                            match headercheck::parse_epoch_extra_entropy(epoch_extra_entropy) {
+                                Ok((epoch, entropy)) => {
+                                    extra_entropy.insert(epoch, entropy);
+                                }
+                                Err(error) => {
+                                    handle_error(error);
+                                    return;
+                                }
+                            }
+                        }
+                        Some(headercheck::HeaderCheck::new(*mode, byron, shelley, extra_entropy))
+                    }
+                    None => None,
+                };
+                sync::sync(
+                    db,
+                    host.as_str(),
+                    *port,
+                    *network_magic,
+                    *no_service,
+                    webhook_config,
+                    header_check,
+                );
             }
             Command::Leaderlog {
                 ref db,
@@ -292,38 +1039,157 @@ pub mod nodeclient {
                 &"America/Los_Angeles".to_string(),
                 true,
             ),
+            Command::StakeEstimate {
+                ref db,
+                ref byron_genesis,
+                ref shelley_genesis,
+                ref epochs,
+                ref confidence,
+                ref active_stake,
+                ref limit,
+                ref output,
+            } => {
+                stakeestimate::stake_estimate(
+                    db,
+                    byron_genesis,
+                    shelley_genesis,
+                    *epochs,
+                    *confidence,
+                    active_stake,
+                    limit,
+                    output,
+                );
+            }
             Command::Sendtip {
                 ref config,
                 ref cardano_node,
+                ref db,
+                ref byron_genesis,
+                ref shelley_genesis,
+                ref propagation_stats,
             } => {
                 if !config.exists() {
                     handle_error("config not found!");
                     return;
                 }
-                if !cardano_node.exists() {
-                    handle_error("cardano-node not found!");
-                    return;
+                if let Some(cardano_node) = cardano_node {
+                    if !cardano_node.exists() {
+                        handle_error("cardano-node not found!");
+                        return;
+                    }
                 }
 
                 let pooltool_config: PooltoolConfig = get_pooltool_config(config);
-                let mut handles: Vec<JoinHandle<_>> = vec![];
+                let pooltool_client = match PooltoolClient::from_config(&pooltool_config) {
+                    Ok(pooltool_client) => pooltool_client,
+                    Err(error) => {
+                        handle_error(error);
+                        return;
+                    }
+                };
+                let mut pools: Vec<sync::SendtipPool> = vec![];
                 for pool in pooltool_config.pools.into_iter() {
-                    let api_key = pooltool_config.api_key.clone();
-                    let cardano_node_path = cardano_node.clone();
-                    handles.push(thread::spawn(move || {
-                        sync::sendtip(
-                            pool.name,
-                            pool.pool_id,
-                            pool.host,
-                            pool.port,
-                            api_key,
-                            &*cardano_node_path,
-                        );
-                    }));
+                    let mut node_version_sources: Vec<NodeVersionSource> = vec![];
+                    if let Some(node_version) = &pool.node_version {
+                        node_version_sources.push(NodeVersionSource::Config(node_version.clone()));
+                    }
+                    if let Some(prometheus_url) = &pool.prometheus_url {
+                        node_version_sources.push(NodeVersionSource::Prometheus(prometheus_url.clone()));
+                    }
+                    if let Some(cardano_node) = cardano_node {
+                        node_version_sources.push(NodeVersionSource::Binary(cardano_node.clone()));
+                    }
+                    if node_version_sources.is_empty() {
+                        handle_error(format!(
+                            "no cardano-node version for pool {}: set node_version or prometheus_url, or pass --cardano-node!",
+                            pool.name
+                        ));
+                        return;
+                    }
+                    pools.push(sync::SendtipPool {
+                        pool_name: pool.name,
+                        pool_id: pool.pool_id,
+                        host: pool.host,
+                        port: pool.port,
+                        network_magic: pool.network_magic,
+                        api_key: pooltool_config.api_key.clone(),
+                        node_version_sources,
+                    });
                 }
-
-                for handle in handles {
-                    handle.join().unwrap()
+                let propagation = match (byron_genesis, shelley_genesis) {
+                    (Some(byron_genesis), Some(shelley_genesis)) => {
+                        match (read_byron_genesis(byron_genesis), read_shelley_genesis(shelley_genesis)) {
+                            (Ok(byron), Ok(shelley)) => {
+                                Some(Propagation::new(byron, shelley, propagation_stats.clone()))
+                            }
+                            (Err(error), _) | (_, Err(error)) => {
+                                handle_error(error);
+                                return;
+                            }
+                        }
+                    }
+                    (None, None) if propagation_stats.is_none() => None,
+                    _ => {
+                        handle_error("--byron-genesis and --shelley-genesis are required to measure tip propagation!");
+                        return;
+                    }
+                };
+                sync::sendtip(pools, pooltool_client, db, propagation);
+            }
+            Command::Outbox { ref command } => match command {
+                OutboxCommand::List { ref db } => {
+                    outbox::list(db);
+                }
+                OutboxCommand::Flush { ref db, ref config } => {
+                    if !config.exists() {
+                        handle_error("config not found!");
+                        return;
+                    }
+                    let pooltool_config: PooltoolConfig = get_pooltool_config(config);
+                    match PooltoolClient::from_config(&pooltool_config) {
+                        Ok(pooltool_client) => outbox::flush(db, &pooltool_client),
+                        Err(error) => handle_error(error),
+                    }
+                }
+                OutboxCommand::Drop { ref db, ref id, all } => {
+                    outbox::drop_entries(db, id, *all);
+                }
+            },
+            Command::Snapshot { ref command } => match command {
+                SnapshotCommand::Create {
+                    ref db,
+                    ref output,
+                    ref network_magic,
+                } => {
+                    snapshot::create(db, output, *network_magic);
+                }
+                SnapshotCommand::Restore {
+                    ref db,
+                    ref input,
+                    ref network_magic,
+                    force,
+                } => {
+                    snapshot::restore(input, db, *network_magic, *force);
+                }
+            },
+            Command::Serve {
+                ref db,
+                ref bind,
+                ref byron_genesis,
+                ref shelley_genesis,
+                ref timezone,
+                ref epoch_extra_entropy,
+            } => {
+                let api = epoch_extra_entropy
+                    .iter()
+                    .map(|epoch_extra_entropy| headercheck::parse_epoch_extra_entropy(epoch_extra_entropy))
+                    .collect::<Result<HashMap<i64, String>, String>>()
+                    .and_then(|extra_entropy| {
+                        serve::Api::new(db, byron_genesis, shelley_genesis, timezone, extra_entropy)
+                    });
+                match api {
+                    Ok(api) => serve::serve(bind, api),
+                    Err(error) => handle_error(error),
                 }
             }
             Command::Sendslots {
@@ -347,6 +1213,68 @@ pub mod nodeclient {
             } => {
                 leaderlog::status(db, byron_genesis, shelley_genesis);
             }
+            Command::Opcert {
+                ref db,
+                ref byron_genesis,
+                ref shelley_genesis,
+                ref pool_id,
+            } => {
+                opcert::opcert(db, byron_genesis, shelley_genesis, pool_id);
+            }
+            Command::CheckOpcert {
+                ref db,
+                ref shelley_genesis,
+                ref opcert_file,
+                ref pool_id,
+            } => {
+                if !opcert_file.exists() {
+                    handle_error("opcert file not found!");
+                    return;
+                }
+                opcert::check_opcert(db, shelley_genesis, opcert_file, pool_id);
+            }
+            Command::Versions {
+                ref db,
+                ref byron_genesis,
+                ref shelley_genesis,
+                ref epochs,
+                ref pool_id,
+            } => {
+                versions::versions(db, byron_genesis, shelley_genesis, *epochs, pool_id);
+            }
+            Command::PoolStats {
+                ref db,
+                ref byron_genesis,
+                ref shelley_genesis,
+                ref pool_id,
+                ref epochs,
+                ref output,
+            } => {
+                poolstats::pool_stats(db, byron_genesis, shelley_genesis, pool_id, epochs, output);
+            }
+            Command::ChainHealth {
+                ref db,
+                ref byron_genesis,
+                ref shelley_genesis,
+                ref epochs,
+                ref start_slot,
+                ref end_slot,
+                ref window,
+                ref density_threshold,
+                ref output,
+            } => {
+                health::chain_health(
+                    db,
+                    byron_genesis,
+                    shelley_genesis,
+                    *epochs,
+                    start_slot,
+                    end_slot,
+                    window,
+                    *density_threshold,
+                    output,
+                );
+            }
             Command::Challenge { ref domain } => {
                 signing::create_challenge(domain);
             }
@@ -380,15 +1308,40 @@ pub mod nodeclient {
 
     #[derive(Debug, Deserialize)]
     pub struct PooltoolConfig {
-        api_key: String,
-        pools: Vec<Pool>,
+        pub api_key: String,
+        pub pools: Vec<Pool>,
+        #[serde(default = "default_pooltool_base_url")]
+        pub base_url: String,
+        #[serde(default = "default_pooltool_timeout")]
+        pub timeout: u64,
+        #[serde(default)]
+        pub proxy: Option<String>,
+    }
+
+    fn default_pooltool_base_url() -> String {
+        "https://api.pooltool.io".to_string()
+    }
+
+    // seconds
+    fn default_pooltool_timeout() -> u64 {
+        30
     }
 
     #[derive(Debug, Deserialize)]
-    struct Pool {
-        name: String,
-        pool_id: String,
-        host: String,
-        port: u16,
+    pub struct Pool {
+        pub name: String,
+        pub pool_id: String,
+        pub host: String,
+        pub port: u16,
+        #[serde(default = "default_network_magic")]
+        pub network_magic: u32,
+        #[serde(default, alias = "version")]
+        pub node_version: Option<String>,
+        #[serde(default)]
+        pub prometheus_url: Option<String>,
+    }
+
+    fn default_network_magic() -> u32 {
+        764824073
     }
 }
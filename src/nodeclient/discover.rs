@@ -0,0 +1,173 @@
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{stdout, BufWriter};
+use std::path::Path;
+
+use log::info;
+use serde::Serialize;
+
+use crate::nodeclient::leaderlog::handle_error;
+use crate::nodeclient::ping::{self, PingOptions};
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Discovery {
+    status: String,
+    network_magic: u32,
+    reachable: usize,
+    unreachable: usize,
+    peers: Vec<DiscoveredPeer>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DiscoveredPeer {
+    host: String,
+    port: u16,
+    network_magic: u32,
+    connect_duration_ms: u128,
+    duration_ms: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    network_version: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    diffusion_mode: Option<String>,
+    /// Whether the handshake agreed on peer sharing
+    peer_sharing: bool,
+    /// How many relays this peer shared with us
+    shared_peers: usize,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct Topology {
+    producers: Vec<TopologyProducer>,
+}
+
+#[derive(Debug, Serialize)]
+struct TopologyProducer {
+    addr: String,
+    port: u16,
+    valency: u32,
+}
+
+/// Parse a seed relay given as host:port, [ipv6]:port or just a host on port 3001
+pub fn parse_seed(seed: &str) -> Result<(String, u16), String> {
+    let mut parts = seed.rsplitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some(port), Some(host)) if !host.ends_with(':') && (!host.contains(':') || host.ends_with(']')) => {
+            let port = port.parse().map_err(|_| format!("invalid seed port: {}", seed))?;
+            Ok((host.trim_start_matches('[').trim_end_matches(']').to_string(), port))
+        }
+        _ => Ok((seed.trim_start_matches('[').trim_end_matches(']').to_string(), 3001)),
+    }
+}
+
+/// Crawl the relays reachable from the seeds with the peer-sharing protocol, pinging each of them
+pub(crate) fn discover(
+    seeds: Vec<(String, u16)>,
+    network_magic: u32,
+    max_peers: usize,
+    share_peers: u8,
+    concurrency: usize,
+    topology_out: &Option<std::path::PathBuf>,
+) {
+    let options = PingOptions {
+        share_peers,
+        ..Default::default()
+    };
+    let mut seen: BTreeSet<(String, u16)> = BTreeSet::new();
+    let mut frontier: Vec<(String, u16)> = vec![];
+    for seed in seeds.into_iter() {
+        if seen.insert(seed.clone()) {
+            frontier.push(seed);
+        }
+    }
+
+    let mut peers: Vec<DiscoveredPeer> = vec![];
+    let mut unreachable = 0;
+    while !frontier.is_empty() {
+        info!("Pinging {} peers, {} found so far", frontier.len(), peers.len());
+        let mut next_frontier: Vec<(String, u16)> = vec![];
+        let results = match ping::ping_peers(&frontier, network_magic, concurrency, &options) {
+            Ok(results) => results,
+            Err(error) => {
+                handle_error(error);
+                return;
+            }
+        };
+        for result in results.into_iter() {
+            match result {
+                Ok(ping_success) => {
+                    for shared_peer in ping_success.shared_peers.iter() {
+                        let peer = (shared_peer.ip().to_string(), shared_peer.port());
+                        if seen.len() < max_peers && seen.insert(peer.clone()) {
+                            next_frontier.push(peer);
+                        }
+                    }
+                    peers.push(DiscoveredPeer {
+                        host: ping_success.host,
+                        port: ping_success.port,
+                        network_magic,
+                        connect_duration_ms: ping_success.connect_duration_ms,
+                        duration_ms: ping_success.duration_ms,
+                        network_version: ping_success.network_version,
+                        diffusion_mode: ping_success.diffusion_mode,
+                        peer_sharing: ping_success.peer_sharing,
+                        shared_peers: ping_success.shared_peers.len(),
+                    });
+                }
+                Err(ping_error) => {
+                    info!(
+                        "{}:{} unreachable: {}",
+                        ping_error.host, ping_error.port, ping_error.error_message
+                    );
+                    unreachable += 1;
+                }
+            }
+        }
+        frontier = next_frontier;
+    }
+    peers.sort_by(|a, b| {
+        a.duration_ms
+            .cmp(&b.duration_ms)
+            .then(a.host.cmp(&b.host))
+            .then(a.port.cmp(&b.port))
+    });
+
+    if let Some(topology_out) = topology_out {
+        if let Err(error) = write_topology(topology_out, &peers) {
+            handle_error(format!("topology error: {}", error));
+            return;
+        }
+    }
+
+    serde_json::ser::to_writer_pretty(
+        &mut stdout(),
+        &Discovery {
+            status: "ok".to_string(),
+            network_magic,
+            reachable: peers.len(),
+            unreachable,
+            peers,
+        },
+    )
+    .unwrap();
+}
+
+fn write_topology(topology_out: &Path, peers: &[DiscoveredPeer]) -> Result<(), String> {
+    let file = File::create(topology_out).map_err(|error| format!("{}: {}", topology_out.display(), error))?;
+    serde_json::ser::to_writer_pretty(
+        BufWriter::new(file),
+        &Topology {
+            producers: peers
+                .iter()
+                .map(|peer| TopologyProducer {
+                    addr: peer.host.clone(),
+                    port: peer.port,
+                    valency: 1,
+                })
+                .collect(),
+        },
+    )
+    .map_err(|error| error.to_string())
+}
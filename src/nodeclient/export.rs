@@ -0,0 +1,420 @@
+use std::fs::File;
+use std::io::{stdout, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use rusqlite::{named_params, Connection, OpenFlags, Row, NO_PARAMS};
+use serde::ser::{SerializeMap, Serializer};
+use serde::Serialize;
+
+use crate::nodeclient::leaderlog::{
+    get_first_slot_of_epoch, get_first_slot_of_epoch_number, handle_error, read_byron_genesis, read_shelley_genesis,
+    ByronGenesis, ShelleyGenesis,
+};
+use crate::nodeclient::sqlite::SqLiteBlockStore;
+
+#[cfg(feature = "parquet")]
+mod parquet_writer;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportTable {
+    Chain,
+    Slots,
+}
+
+impl FromStr for ExportTable {
+    type Err = String;
+    fn from_str(table: &str) -> Result<Self, Self::Err> {
+        match table {
+            "chain" => Ok(ExportTable::Chain),
+            "slots" => Ok(ExportTable::Slots),
+            _ => Err(format!("Unknown table: {}", table)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    Csv,
+    Jsonl,
+    Parquet,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "csv" => Ok(ExportFormat::Csv),
+            "jsonl" => Ok(ExportFormat::Jsonl),
+            "parquet" => Ok(ExportFormat::Parquet),
+            _ => Err(format!("Unknown export format: {}", format)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum ColumnType {
+    Integer,
+    Text,
+    NullableText,
+    Boolean,
+}
+
+pub(crate) struct Column {
+    pub(crate) name: &'static str,
+    pub(crate) column_type: ColumnType,
+}
+
+const fn column(name: &'static str, column_type: ColumnType) -> Column {
+    Column { name, column_type }
+}
+
+// The epoch of a chain row is not stored, it is calculated from the slot
+const EPOCH: &str = "epoch";
+
+const CHAIN_COLUMNS: [Column; 23] = [
+    column("block_number", ColumnType::Integer),
+    column("slot_number", ColumnType::Integer),
+    column(EPOCH, ColumnType::Integer),
+    column("hash", ColumnType::Text),
+    column("prev_hash", ColumnType::Text),
+    column("pool_id", ColumnType::Text),
+    column("eta_v", ColumnType::Text),
+    column("node_vkey", ColumnType::Text),
+    column("node_vrf_vkey", ColumnType::Text),
+    column("eta_vrf_0", ColumnType::Text),
+    column("eta_vrf_1", ColumnType::Text),
+    column("leader_vrf_0", ColumnType::Text),
+    column("leader_vrf_1", ColumnType::Text),
+    column("block_size", ColumnType::Integer),
+    column("block_body_hash", ColumnType::Text),
+    column("pool_opcert", ColumnType::Text),
+    column("opcert_counter", ColumnType::Integer),
+    column("opcert_kes_period", ColumnType::Integer),
+    column("opcert_signature", ColumnType::Text),
+    column("protocol_major_version", ColumnType::Integer),
+    column("protocol_minor_version", ColumnType::Integer),
+    column("orphaned", ColumnType::Boolean),
+    column("header_error", ColumnType::NullableText),
+];
+
+const SLOTS_COLUMNS: [Column; 5] = [
+    column(EPOCH, ColumnType::Integer),
+    column("pool_id", ColumnType::Text),
+    column("slot_qty", ColumnType::Integer),
+    column("slots", ColumnType::Text),
+    column("hash", ColumnType::Text),
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Field {
+    Integer(i64),
+    Text(String),
+    Null,
+    Boolean(bool),
+}
+
+/// Where the rows of an export go
+pub(crate) trait RowWriter {
+    fn write_row(&mut self, row: &[Field]) -> Result<(), String>;
+    fn finish(self: Box<Self>) -> Result<(), String>;
+}
+
+pub struct ExportOptions {
+    pub table: ExportTable,
+    pub format: ExportFormat,
+    pub from_epoch: Option<i64>,
+    pub to_epoch: Option<i64>,
+    pub pool_id: Option<String>,
+    /// The genesis files are needed to calculate the epoch of chain rows
+    pub byron_genesis: Option<PathBuf>,
+    pub shelley_genesis: Option<PathBuf>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ExportResult {
+    status: String,
+    table: String,
+    rows: usize,
+    output: String,
+}
+
+pub(crate) fn export(db_path: &Path, options: &ExportOptions, output: &Option<PathBuf>) {
+    if !db_path.exists() {
+        handle_error("database not found!");
+        return;
+    }
+    let out: Box<dyn Write + Send> = match output {
+        Some(output) => match File::create(output) {
+            Ok(file) => Box::new(BufWriter::new(file)),
+            Err(error) => {
+                handle_error(format!("{}: {}", output.display(), error));
+                return;
+            }
+        },
+        None if options.format == ExportFormat::Parquet => {
+            handle_error("--output is required for parquet exports!");
+            return;
+        }
+        None => Box::new(BufWriter::new(stdout())),
+    };
+
+    match export_table(db_path, options, out) {
+        Ok(rows) => {
+            // The rows themselves went to stdout when there is no output file
+            if let Some(output) = output {
+                serde_json::ser::to_writer_pretty(
+                    &mut stdout(),
+                    &ExportResult {
+                        status: "ok".to_string(),
+                        table: match options.table {
+                            ExportTable::Chain => "chain".to_string(),
+                            ExportTable::Slots => "slots".to_string(),
+                        },
+                        rows,
+                        output: output.to_string_lossy().to_string(),
+                    },
+                )
+                .unwrap();
+            }
+        }
+        Err(error) => handle_error(error),
+    }
+}
+
+/// Stream the rows of a table matching the epoch range and pool id to out one at a time, returning how many
+/// rows were written
+pub fn export_table(db_path: &Path, options: &ExportOptions, out: Box<dyn Write + Send>) -> Result<usize, String> {
+    let genesis = match (&options.byron_genesis, &options.shelley_genesis) {
+        (Some(byron_genesis), Some(shelley_genesis)) => Some((
+            read_byron_genesis(byron_genesis).map_err(|error| format!("byron genesis: {}", error))?,
+            read_shelley_genesis(shelley_genesis).map_err(|error| format!("shelley genesis: {}", error))?,
+        )),
+        (None, None) if options.table == ExportTable::Slots => None,
+        (None, None) => {
+            return Err("--byron-genesis and --shelley-genesis are required to export the chain table!".to_string())
+        }
+        _ => return Err("both --byron-genesis and --shelley-genesis are required!".to_string()),
+    };
+    let columns: &'static [Column] = match options.table {
+        ExportTable::Chain => &CHAIN_COLUMNS,
+        ExportTable::Slots => &SLOTS_COLUMNS,
+    };
+    let writer = row_writer(options.format, columns, out)?;
+
+    // Exporting never writes to the db, an older one has to be upgraded by sync first
+    let db = Connection::open_with_flags(db_path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .map_err(|error| format!("cannot open {}: {}", db_path.display(), error))?;
+    let rows = check_db_version(&db).and_then(|_| match &genesis {
+        Some((byron, shelley)) if options.table == ExportTable::Chain => {
+            export_chain(&db, options, byron, shelley, writer)
+        }
+        _ => export_slots(&db, options, writer),
+    });
+    if let Err(error) = db.close() {
+        return Err(format!("db close error: {}", error.1));
+    }
+    rows
+}
+
+fn check_db_version(db: &Connection) -> Result<(), String> {
+    let db_version: i64 = db
+        .query_row("SELECT version FROM db_version", NO_PARAMS, |row| row.get(0))
+        .map_err(|error| format!("not a cncli database: {}", error))?;
+    if db_version < SqLiteBlockStore::DB_VERSION {
+        return Err(format!(
+            "database is at version {}, run sync to upgrade it to version {} before exporting",
+            db_version,
+            SqLiteBlockStore::DB_VERSION
+        ));
+    }
+    Ok(())
+}
+
+fn row_writer(
+    format: ExportFormat,
+    columns: &'static [Column],
+    out: Box<dyn Write + Send>,
+) -> Result<Box<dyn RowWriter>, String> {
+    match format {
+        ExportFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(out);
+            writer
+                .write_record(columns.iter().map(|column| column.name))
+                .map_err(|error| error.to_string())?;
+            Ok(Box::new(CsvWriter { writer }))
+        }
+        ExportFormat::Jsonl => Ok(Box::new(JsonlWriter { columns, out })),
+        #[cfg(feature = "parquet")]
+        ExportFormat::Parquet => Ok(Box::new(parquet_writer::ParquetWriter::new(columns, out)?)),
+        #[cfg(not(feature = "parquet"))]
+        ExportFormat::Parquet => {
+            Err("cncli was built without parquet support, rebuild with --features parquet".to_string())
+        }
+    }
+}
+
+fn export_chain(
+    db: &Connection,
+    options: &ExportOptions,
+    byron: &ByronGenesis,
+    shelley: &ShelleyGenesis,
+    mut writer: Box<dyn RowWriter>,
+) -> Result<usize, String> {
+    let from_slot = match options.from_epoch {
+        Some(from_epoch) => get_first_slot_of_epoch_number(byron, shelley, from_epoch),
+        None => i64::MIN,
+    };
+    let to_slot = match options.to_epoch {
+        Some(to_epoch) => get_first_slot_of_epoch_number(byron, shelley, to_epoch + 1),
+        None => i64::MAX,
+    };
+    let sql_columns: Vec<&str> = CHAIN_COLUMNS
+        .iter()
+        .map(|column| column.name)
+        .filter(|name| *name != EPOCH)
+        .collect();
+    let mut stmt = db
+        .prepare(&format!(
+            "SELECT {} FROM chain WHERE slot_number >= :from_slot AND slot_number < :to_slot \
+            AND (:pool_id IS NULL OR pool_id = :pool_id) ORDER BY slot_number, id",
+            sql_columns.join(",")
+        ))
+        .map_err(|error| error.to_string())?;
+    let mut rows = stmt
+        .query_named(named_params! {
+            ":from_slot": from_slot,
+            ":to_slot": to_slot,
+            ":pool_id": options.pool_id,
+        })
+        .map_err(|error| error.to_string())?;
+
+    let mut count = 0;
+    while let Some(row) = rows.next().map_err(|error| error.to_string())? {
+        let slot_number: i64 = row.get("slot_number").map_err(|error| error.to_string())?;
+        let epoch = get_first_slot_of_epoch(byron, shelley, slot_number).0;
+        let mut fields: Vec<Field> = Vec::with_capacity(CHAIN_COLUMNS.len());
+        let mut index = 0;
+        for column in CHAIN_COLUMNS.iter() {
+            if column.name == EPOCH {
+                fields.push(Field::Integer(epoch));
+            } else {
+                fields.push(read_field(row, index, column.column_type)?);
+                index += 1;
+            }
+        }
+        writer.write_row(&fields)?;
+        count += 1;
+    }
+    writer.finish()?;
+    Ok(count)
+}
+
+fn export_slots(db: &Connection, options: &ExportOptions, mut writer: Box<dyn RowWriter>) -> Result<usize, String> {
+    let sql_columns: Vec<&str> = SLOTS_COLUMNS.iter().map(|column| column.name).collect();
+    let mut stmt = db
+        .prepare(&format!(
+            "SELECT {} FROM slots WHERE epoch >= :from_epoch AND epoch <= :to_epoch \
+            AND (:pool_id IS NULL OR pool_id = :pool_id) ORDER BY epoch, pool_id",
+            sql_columns.join(",")
+        ))
+        .map_err(|error| error.to_string())?;
+    let mut rows = stmt
+        .query_named(named_params! {
+            ":from_epoch": options.from_epoch.unwrap_or(i64::MIN),
+            ":to_epoch": options.to_epoch.unwrap_or(i64::MAX),
+            ":pool_id": options.pool_id,
+        })
+        .map_err(|error| error.to_string())?;
+
+    let mut count = 0;
+    while let Some(row) = rows.next().map_err(|error| error.to_string())? {
+        let fields = SLOTS_COLUMNS
+            .iter()
+            .enumerate()
+            .map(|(index, column)| read_field(row, index, column.column_type))
+            .collect::<Result<Vec<Field>, String>>()?;
+        writer.write_row(&fields)?;
+        count += 1;
+    }
+    writer.finish()?;
+    Ok(count)
+}
+
+fn read_field(row: &Row, index: usize, column_type: ColumnType) -> Result<Field, String> {
+    match column_type {
+        ColumnType::Integer => row.get(index).map(Field::Integer),
+        ColumnType::Text => row.get(index).map(Field::Text),
+        ColumnType::NullableText => row
+            .get::<_, Option<String>>(index)
+            .map(|text| text.map_or(Field::Null, Field::Text)),
+        ColumnType::Boolean => row.get(index).map(Field::Boolean),
+    }
+    .map_err(|error| error.to_string())
+}
+
+struct CsvWriter {
+    writer: csv::Writer<Box<dyn Write + Send>>,
+}
+
+impl RowWriter for CsvWriter {
+    fn write_row(&mut self, row: &[Field]) -> Result<(), String> {
+        self.writer
+            .write_record(row.iter().map(|field| match field {
+                Field::Integer(value) => value.to_string(),
+                Field::Text(value) => value.clone(),
+                Field::Null => String::new(),
+                Field::Boolean(value) => value.to_string(),
+            }))
+            .map_err(|error| error.to_string())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<(), String> {
+        self.writer.flush().map_err(|error| error.to_string())
+    }
+}
+
+struct JsonlWriter {
+    columns: &'static [Column],
+    out: Box<dyn Write + Send>,
+}
+
+// A row as a json object with its keys in column order
+struct JsonRow<'a> {
+    columns: &'a [Column],
+    row: &'a [Field],
+}
+
+impl Serialize for JsonRow<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.columns.len()))?;
+        for (column, field) in self.columns.iter().zip(self.row.iter()) {
+            match field {
+                Field::Integer(value) => map.serialize_entry(column.name, value)?,
+                Field::Text(value) => map.serialize_entry(column.name, value)?,
+                Field::Null => map.serialize_entry(column.name, &None::<String>)?,
+                Field::Boolean(value) => map.serialize_entry(column.name, value)?,
+            }
+        }
+        map.end()
+    }
+}
+
+impl RowWriter for JsonlWriter {
+    fn write_row(&mut self, row: &[Field]) -> Result<(), String> {
+        serde_json::to_writer(
+            &mut self.out,
+            &JsonRow {
+                columns: self.columns,
+                row,
+            },
+        )
+        .map_err(|error| error.to_string())?;
+        self.out.write_all(b"\n").map_err(|error| error.to_string())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<(), String> {
+        self.out.flush().map_err(|error| error.to_string())
+    }
+}
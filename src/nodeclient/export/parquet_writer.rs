@@ -0,0 +1,136 @@
+use std::io::Write;
+use std::sync::Arc;
+
+use parquet::basic::Compression;
+use parquet::data_type::{BoolType, ByteArray, ByteArrayType, Int64Type};
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::parser::parse_message_type;
+
+use crate::nodeclient::export::{Column, ColumnType, Field, RowWriter};
+
+// Rows buffered in memory before they are written out as a row group
+const ROW_GROUP_SIZE: usize = 50_000;
+
+enum ColumnBuffer {
+    Integer(Vec<i64>),
+    Text(Vec<ByteArray>),
+    // The values that are not null and a definition level per row, 0 for null and 1 for a value
+    NullableText(Vec<ByteArray>, Vec<i16>),
+    Boolean(Vec<bool>),
+}
+
+pub(crate) struct ParquetWriter {
+    writer: SerializedFileWriter<Box<dyn Write + Send>>,
+    buffers: Vec<ColumnBuffer>,
+    buffered_rows: usize,
+}
+
+impl ParquetWriter {
+    pub(crate) fn new(columns: &[Column], out: Box<dyn Write + Send>) -> Result<ParquetWriter, String> {
+        let fields: Vec<String> = columns
+            .iter()
+            .map(|column| match column.column_type {
+                ColumnType::Integer => format!("REQUIRED INT64 {};", column.name),
+                ColumnType::Text => format!("REQUIRED BYTE_ARRAY {} (UTF8);", column.name),
+                ColumnType::NullableText => format!("OPTIONAL BYTE_ARRAY {} (UTF8);", column.name),
+                ColumnType::Boolean => format!("REQUIRED BOOLEAN {};", column.name),
+            })
+            .collect();
+        let schema = parse_message_type(&format!("message cncli {{ {} }}", fields.join(" ")))
+            .map_err(|error| error.to_string())?;
+        let properties = WriterProperties::builder().set_compression(Compression::SNAPPY).build();
+        let writer = SerializedFileWriter::new(out, Arc::new(schema), Arc::new(properties))
+            .map_err(|error| error.to_string())?;
+        Ok(ParquetWriter {
+            writer,
+            buffers: columns
+                .iter()
+                .map(|column| match column.column_type {
+                    ColumnType::Integer => ColumnBuffer::Integer(vec![]),
+                    ColumnType::Text => ColumnBuffer::Text(vec![]),
+                    ColumnType::NullableText => ColumnBuffer::NullableText(vec![], vec![]),
+                    ColumnType::Boolean => ColumnBuffer::Boolean(vec![]),
+                })
+                .collect(),
+            buffered_rows: 0,
+        })
+    }
+
+    fn flush_row_group(&mut self) -> Result<(), String> {
+        if self.buffered_rows == 0 {
+            return Ok(());
+        }
+        let mut row_group = self.writer.next_row_group().map_err(|error| error.to_string())?;
+        for buffer in self.buffers.iter_mut() {
+            let mut column = match row_group.next_column().map_err(|error| error.to_string())? {
+                Some(column) => column,
+                None => return Err("parquet schema has fewer columns than the table".to_string()),
+            };
+            match buffer {
+                ColumnBuffer::Integer(values) => column.typed::<Int64Type>().write_batch(values, None, None),
+                ColumnBuffer::Text(values) => column.typed::<ByteArrayType>().write_batch(values, None, None),
+                ColumnBuffer::NullableText(values, definition_levels) => {
+                    column
+                        .typed::<ByteArrayType>()
+                        .write_batch(values, Some(definition_levels), None)
+                }
+                ColumnBuffer::Boolean(values) => column.typed::<BoolType>().write_batch(values, None, None),
+            }
+            .map_err(|error| error.to_string())?;
+            column.close().map_err(|error| error.to_string())?;
+            buffer.clear();
+        }
+        row_group.close().map_err(|error| error.to_string())?;
+        self.buffered_rows = 0;
+        Ok(())
+    }
+}
+
+impl ColumnBuffer {
+    fn push(&mut self, field: &Field) -> Result<(), String> {
+        match (self, field) {
+            (ColumnBuffer::Integer(values), Field::Integer(value)) => values.push(*value),
+            (ColumnBuffer::Text(values), Field::Text(value)) => values.push(ByteArray::from(value.as_str())),
+            (ColumnBuffer::NullableText(values, definition_levels), Field::Text(value)) => {
+                values.push(ByteArray::from(value.as_str()));
+                definition_levels.push(1);
+            }
+            (ColumnBuffer::NullableText(_, definition_levels), Field::Null) => definition_levels.push(0),
+            (ColumnBuffer::Boolean(values), Field::Boolean(value)) => values.push(*value),
+            (_, field) => return Err(format!("unexpected value {:?} for parquet column", field)),
+        }
+        Ok(())
+    }
+
+    fn clear(&mut self) {
+        match self {
+            ColumnBuffer::Integer(values) => values.clear(),
+            ColumnBuffer::Text(values) => values.clear(),
+            ColumnBuffer::NullableText(values, definition_levels) => {
+                values.clear();
+                definition_levels.clear();
+            }
+            ColumnBuffer::Boolean(values) => values.clear(),
+        }
+    }
+}
+
+impl RowWriter for ParquetWriter {
+    fn write_row(&mut self, row: &[Field]) -> Result<(), String> {
+        for (buffer, field) in self.buffers.iter_mut().zip(row.iter()) {
+            buffer.push(field)?;
+        }
+        self.buffered_rows += 1;
+        if self.buffered_rows >= ROW_GROUP_SIZE {
+            self.flush_row_group()?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<(), String> {
+        self.flush_row_group()?;
+        self.writer.close().map_err(|error| error.to_string())?;
+        Ok(())
+    }
+}
@@ -0,0 +1,327 @@
+use std::collections::BTreeMap;
+use std::io::stdout;
+use std::path::Path;
+
+use log::info;
+use rusqlite::{named_params, Connection, Error, OptionalExtension, NO_PARAMS};
+use serde::Serialize;
+
+use crate::nodeclient::leaderlog::handle_error;
+use crate::nodeclient::sqlite::{next_eta_v, pool_id};
+
+pub const BROKEN_LINK: &str = "broken_link";
+pub const DUPLICATE_BLOCK: &str = "duplicate_block";
+pub const BLOCK_GAP: &str = "block_gap";
+pub const SLOT_NOT_INCREASING: &str = "slot_not_increasing";
+pub const ETA_V_MISMATCH: &str = "eta_v_mismatch";
+pub const POOL_ID_MISMATCH: &str = "pool_id_mismatch";
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FsckReport {
+    pub status: String,
+    pub checked: i64,
+    pub issue_count: usize,
+    pub issue_kinds: BTreeMap<String, usize>,
+    pub issues: Vec<FsckIssue>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repaired: Option<FsckRepair>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FsckIssue {
+    pub kind: String,
+    pub block_number: i64,
+    pub slot_number: i64,
+    pub hash: String,
+    pub message: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FsckRepair {
+    pub orphans_remarked: usize,
+    pub eta_v_fixed: usize,
+    pub pool_id_fixed: usize,
+    /// Issues left after repairing, such as gaps that need a resync
+    pub remaining_issues: usize,
+}
+
+// Issues found in one pass over the chain
+struct ChainCheck {
+    checked: i64,
+    issue_count: usize,
+    issue_kinds: BTreeMap<String, usize>,
+    issues: Vec<FsckIssue>,
+    limit: usize,
+}
+
+impl ChainCheck {
+    fn add(&mut self, kind: &str, block_number: i64, slot_number: i64, hash: &str, message: String) {
+        self.issue_count += 1;
+        *self.issue_kinds.entry(kind.to_string()).or_default() += 1;
+        if self.issues.len() < self.limit {
+            self.issues.push(FsckIssue {
+                kind: kind.to_string(),
+                block_number,
+                slot_number,
+                hash: hash.to_string(),
+                message,
+            });
+        }
+    }
+}
+
+pub(crate) fn fsck(db_path: &Path, repair: bool, limit: usize) {
+    if !db_path.exists() {
+        handle_error("database not found!");
+        return;
+    }
+    match fsck_db(db_path, repair, limit) {
+        Ok(fsck_report) => {
+            serde_json::ser::to_writer_pretty(&mut stdout(), &fsck_report).unwrap();
+        }
+        Err(error) => handle_error(error),
+    }
+}
+
+/// Check the non-orphaned blocks are one consistent chain, optionally repairing what can be derived from the
+/// blocks themselves. At most `limit` issues are listed.
+pub fn fsck_db(db_path: &Path, repair: bool, limit: usize) -> Result<FsckReport, String> {
+    let db = Connection::open(db_path).map_err(|error| error.to_string())?;
+    let chain_check = check_chain(&db, limit).map_err(|error| error.to_string())?;
+
+    let repaired = if repair && chain_check.issue_count > 0 {
+        let mut fsck_repair = FsckRepair::default();
+        let links_broken = [BROKEN_LINK, DUPLICATE_BLOCK, SLOT_NOT_INCREASING]
+            .iter()
+            .any(|kind| chain_check.issue_kinds.contains_key(*kind));
+        if links_broken {
+            fsck_repair.orphans_remarked = remark_orphans(&db).map_err(|error| error.to_string())?;
+        }
+        let (eta_v_fixed, pool_id_fixed) = fix_derived_columns(&db).map_err(|error| error.to_string())?;
+        fsck_repair.eta_v_fixed = eta_v_fixed;
+        fsck_repair.pool_id_fixed = pool_id_fixed;
+        fsck_repair.remaining_issues = check_chain(&db, 0).map_err(|error| error.to_string())?.issue_count;
+        Some(fsck_repair)
+    } else {
+        None
+    };
+
+    if let Err(error) = db.close() {
+        return Err(format!("db close error: {}", error.1));
+    }
+
+    Ok(FsckReport {
+        status: match &repaired {
+            _ if chain_check.issue_count == 0 => "ok",
+            Some(fsck_repair) if fsck_repair.remaining_issues == 0 => "repaired",
+            _ => "inconsistent",
+        }
+        .to_string(),
+        checked: chain_check.checked,
+        issue_count: chain_check.issue_count,
+        issue_kinds: chain_check.issue_kinds,
+        issues: chain_check.issues,
+        repaired,
+    })
+}
+
+fn check_chain(db: &Connection, limit: usize) -> Result<ChainCheck, Error> {
+    let mut chain_check = ChainCheck {
+        checked: 0,
+        issue_count: 0,
+        issue_kinds: BTreeMap::new(),
+        issues: vec![],
+        limit,
+    };
+    let mut stmt = db.prepare(
+        "SELECT block_number, slot_number, hash, prev_hash, eta_v, eta_vrf_0, node_vkey, pool_id \
+        FROM chain WHERE orphaned = 0 ORDER BY block_number, slot_number, id",
+    )?;
+    let mut rows = stmt.query(NO_PARAMS)?;
+    // block number, slot, hash and eta_v of the previous block
+    let mut prev: Option<(i64, i64, String, Vec<u8>)> = None;
+    while let Some(row) = rows.next()? {
+        let block_number: i64 = row.get(0)?;
+        let slot_number: i64 = row.get(1)?;
+        let hash: String = row.get(2)?;
+        let prev_hash: String = row.get(3)?;
+        let eta_v: String = row.get(4)?;
+        let eta_vrf_0: String = row.get(5)?;
+        let node_vkey: String = row.get(6)?;
+        let block_pool_id: String = row.get(7)?;
+        chain_check.checked += 1;
+
+        let expected_pool_id = hex::encode(pool_id(&hex::decode(&node_vkey).unwrap_or_default()));
+        if block_pool_id != expected_pool_id {
+            chain_check.add(
+                POOL_ID_MISMATCH,
+                block_number,
+                slot_number,
+                &hash,
+                format!("pool_id {} should be {}", block_pool_id, expected_pool_id),
+            );
+        }
+
+        if let Some((prev_block_number, prev_slot_number, prev_block_hash, prev_eta_v)) = &prev {
+            if block_number == *prev_block_number {
+                chain_check.add(
+                    DUPLICATE_BLOCK,
+                    block_number,
+                    slot_number,
+                    &hash,
+                    format!("block {} is also on the chain as {}", block_number, prev_block_hash),
+                );
+            } else if block_number > prev_block_number + 1 {
+                chain_check.add(
+                    BLOCK_GAP,
+                    block_number,
+                    slot_number,
+                    &hash,
+                    format!("blocks {} to {} are missing", prev_block_number + 1, block_number - 1),
+                );
+            } else if prev_hash != *prev_block_hash {
+                chain_check.add(
+                    BROKEN_LINK,
+                    block_number,
+                    slot_number,
+                    &hash,
+                    format!("prev_hash {} should be {}", prev_hash, prev_block_hash),
+                );
+            }
+            if slot_number <= *prev_slot_number {
+                chain_check.add(
+                    SLOT_NOT_INCREASING,
+                    block_number,
+                    slot_number,
+                    &hash,
+                    format!("slot {} is not after slot {}", slot_number, prev_slot_number),
+                );
+            }
+            // The nonce of the first block can't be checked without the blocks before it. Carry the expected nonce
+            // forward so one bad eta_v is reported once.
+            if block_number == prev_block_number + 1 {
+                let expected_eta_v = next_eta_v(prev_eta_v, &hex::decode(&eta_vrf_0).unwrap_or_default());
+                if eta_v != hex::encode(&expected_eta_v) {
+                    chain_check.add(
+                        ETA_V_MISMATCH,
+                        block_number,
+                        slot_number,
+                        &hash,
+                        format!("eta_v {} should be {}", eta_v, hex::encode(&expected_eta_v)),
+                    );
+                }
+                prev = Some((block_number, slot_number, hash, expected_eta_v));
+                continue;
+            }
+        }
+        prev = Some((block_number, slot_number, hash, hex::decode(&eta_v).unwrap_or_default()));
+    }
+    Ok(chain_check)
+}
+
+// Follow prev_hash back from the tip and make exactly the blocks on that path the non-orphaned ones. Blocks below
+// where the path ends, e.g. at a gap, are left alone.
+fn remark_orphans(db: &Connection) -> Result<usize, Error> {
+    let tip: Option<(i64, i64, String)> = db
+        .query_row(
+            "SELECT id, block_number, prev_hash FROM chain WHERE orphaned = 0 \
+            ORDER BY block_number DESC, slot_number DESC LIMIT 1",
+            NO_PARAMS,
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .optional()?;
+    let (mut id, mut lowest_block_number, mut prev_hash) = match tip {
+        Some(tip) => tip,
+        None => return Ok(0),
+    };
+
+    let tx = db.unchecked_transaction()?;
+    tx.execute("CREATE TEMP TABLE fsck_chain (id INTEGER PRIMARY KEY)", NO_PARAMS)?;
+    {
+        let mut insert_stmt = tx.prepare("INSERT OR IGNORE INTO fsck_chain (id) VALUES (?1)")?;
+        let mut prev_stmt =
+            tx.prepare("SELECT id, block_number, prev_hash FROM chain WHERE hash = ?1 ORDER BY orphaned, id LIMIT 1")?;
+        loop {
+            insert_stmt.execute(&[&id])?;
+            match prev_stmt
+                .query_row(&[&prev_hash], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+                .optional()?
+            {
+                Some((prev_id, prev_block_number, prev_prev_hash)) if prev_block_number < lowest_block_number => {
+                    id = prev_id;
+                    lowest_block_number = prev_block_number;
+                    prev_hash = prev_prev_hash;
+                }
+                _ => break,
+            }
+        }
+    }
+    info!("Chain followed back to block {}", lowest_block_number);
+    let remarked = tx.execute_named(
+        "UPDATE chain SET orphaned = CASE WHEN id IN (SELECT id FROM fsck_chain) THEN 0 ELSE 1 END \
+        WHERE block_number >= :lowest_block_number \
+        AND orphaned != CASE WHEN id IN (SELECT id FROM fsck_chain) THEN 0 ELSE 1 END",
+        named_params! { ":lowest_block_number": lowest_block_number },
+    )?;
+    tx.execute("DROP TABLE fsck_chain", NO_PARAMS)?;
+    tx.commit()?;
+    Ok(remarked)
+}
+
+// Recompute the rolling eta_v and the pool_id of the non-orphaned blocks
+fn fix_derived_columns(db: &Connection) -> Result<(usize, usize), Error> {
+    let mut eta_v_fixes: Vec<(i64, String)> = vec![];
+    let mut pool_id_fixes: Vec<(i64, String)> = vec![];
+    {
+        let mut stmt = db.prepare(
+            "SELECT id, block_number, eta_v, eta_vrf_0, node_vkey, pool_id \
+            FROM chain WHERE orphaned = 0 ORDER BY block_number, slot_number, id",
+        )?;
+        let mut rows = stmt.query(NO_PARAMS)?;
+        let mut prev: Option<(i64, Vec<u8>)> = None;
+        while let Some(row) = rows.next()? {
+            let id: i64 = row.get(0)?;
+            let block_number: i64 = row.get(1)?;
+            let eta_v: String = row.get(2)?;
+            let eta_vrf_0: String = row.get(3)?;
+            let node_vkey: String = row.get(4)?;
+            let block_pool_id: String = row.get(5)?;
+
+            let expected_pool_id = hex::encode(pool_id(&hex::decode(&node_vkey).unwrap_or_default()));
+            if block_pool_id != expected_pool_id {
+                pool_id_fixes.push((id, expected_pool_id));
+            }
+
+            let eta_v = match &prev {
+                Some((prev_block_number, prev_eta_v)) if block_number == prev_block_number + 1 => {
+                    let expected_eta_v = next_eta_v(prev_eta_v, &hex::decode(&eta_vrf_0).unwrap_or_default());
+                    if eta_v != hex::encode(&expected_eta_v) {
+                        eta_v_fixes.push((id, hex::encode(&expected_eta_v)));
+                    }
+                    expected_eta_v
+                }
+                _ => hex::decode(&eta_v).unwrap_or_default(),
+            };
+            prev = Some((block_number, eta_v));
+        }
+    }
+
+    let tx = db.unchecked_transaction()?;
+    for (id, eta_v) in eta_v_fixes.iter() {
+        tx.execute_named(
+            "UPDATE chain SET eta_v = :eta_v WHERE id = :id",
+            named_params! { ":eta_v": eta_v, ":id": id },
+        )?;
+    }
+    for (id, block_pool_id) in pool_id_fixes.iter() {
+        tx.execute_named(
+            "UPDATE chain SET pool_id = :pool_id WHERE id = :id",
+            named_params! { ":pool_id": block_pool_id, ":id": id },
+        )?;
+    }
+    tx.commit()?;
+    Ok((eta_v_fixes.len(), pool_id_fixes.len()))
+}
@@ -0,0 +1,239 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use cardano_ouroboros_network::BlockHeader;
+use log::debug;
+use rusqlite::Connection;
+
+use crate::nodeclient::leaderlog::libsodium::{
+    sodium_crypto_sign_verify_detached, sodium_crypto_vrf_proof_to_hash, sodium_crypto_vrf_verify,
+};
+use crate::nodeclient::leaderlog::{
+    calculate_epoch_nonce, get_first_slot_of_epoch, mk_eta_seed, mk_seed, ByronGenesis, ShelleyGenesis,
+};
+use crate::nodeclient::opcert::opcert_signable;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HeaderCheckMode {
+    /// Save invalid headers with the reason in chain.header_error
+    Flag,
+    /// Refuse to save invalid headers
+    Reject,
+}
+
+impl FromStr for HeaderCheckMode {
+    type Err = String;
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode {
+            "flag" => Ok(HeaderCheckMode::Flag),
+            "reject" => Ok(HeaderCheckMode::Reject),
+            _ => Err(format!("Unknown header check mode: {}", mode)),
+        }
+    }
+}
+
+/// Parse extra entropy given as epoch:hex
+pub fn parse_epoch_extra_entropy(epoch_extra_entropy: &str) -> Result<(i64, String), String> {
+    let mut parts = epoch_extra_entropy.splitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some(epoch), Some(entropy)) if hex::decode(entropy).is_ok() => match epoch.parse() {
+            Ok(epoch) => Ok((epoch, entropy.to_string())),
+            Err(_) => Err(format!("invalid extra entropy epoch: {}", epoch_extra_entropy)),
+        },
+        _ => Err(format!("extra entropy must be epoch:hex, got {}", epoch_extra_entropy)),
+    }
+}
+
+/// How sync checks headers, shared by the block store of each connection
+#[derive(Clone)]
+pub struct HeaderCheck {
+    mode: HeaderCheckMode,
+    byron: Arc<ByronGenesis>,
+    shelley: Arc<ShelleyGenesis>,
+    epoch_extra_entropy: Arc<HashMap<i64, String>>,
+    // The header that made sync stop, in reject mode
+    rejected: Arc<Mutex<Option<String>>>,
+}
+
+impl HeaderCheck {
+    pub fn new(
+        mode: HeaderCheckMode,
+        byron: ByronGenesis,
+        shelley: ShelleyGenesis,
+        epoch_extra_entropy: HashMap<i64, String>,
+    ) -> HeaderCheck {
+        HeaderCheck {
+            mode,
+            byron: Arc::new(byron),
+            shelley: Arc::new(shelley),
+            epoch_extra_entropy: Arc::new(epoch_extra_entropy),
+            rejected: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Why a block store rejected a header, sync has to stop once one did
+    pub fn rejected(&self) -> Option<String> {
+        self.rejected.lock().unwrap().clone()
+    }
+
+    pub fn verifier(&self) -> HeaderVerifier {
+        HeaderVerifier {
+            mode: self.mode,
+            byron: self.byron.clone(),
+            shelley: self.shelley.clone(),
+            epoch_extra_entropy: self.epoch_extra_entropy.clone(),
+            epoch_nonces: HashMap::new(),
+            rejected: self.rejected.clone(),
+        }
+    }
+}
+
+/// Checks the headers we get from a peer before they are saved. The KES signature over the header
+/// body can't be checked as the chainsync client does not hand us the signature or the raw header
+/// body, so only the KES period of the opcert is checked against the slot.
+pub struct HeaderVerifier {
+    pub(crate) mode: HeaderCheckMode,
+    byron: Arc<ByronGenesis>,
+    shelley: Arc<ShelleyGenesis>,
+    epoch_extra_entropy: Arc<HashMap<i64, String>>,
+    // Only nonces that could be calculated, the db may have the blocks for the others later
+    epoch_nonces: HashMap<i64, Vec<u8>>,
+    rejected: Arc<Mutex<Option<String>>>,
+}
+
+impl HeaderVerifier {
+    /// How many of the headers are in the same epoch as the first one. The nonce of an epoch is
+    /// calculated from the blocks before it, so those have to be saved before its headers are checked.
+    pub(crate) fn epoch_len(&self, headers: &[BlockHeader]) -> usize {
+        let epoch = |header: &BlockHeader| get_first_slot_of_epoch(&self.byron, &self.shelley, header.slot_number).0;
+        match headers.first() {
+            Some(first) => headers
+                .iter()
+                .take_while(|header| epoch(header) == epoch(first))
+                .count(),
+            None => 0,
+        }
+    }
+
+    /// Tell sync to stop, the relay would send the same header again after every reconnect
+    pub(crate) fn reject(&self, error: String) {
+        *self.rejected.lock().unwrap() = Some(error);
+    }
+
+    /// Why the header is invalid, if it is
+    pub(crate) fn verify(&mut self, db: &Connection, header: &BlockHeader) -> Result<(), String> {
+        check_sizes(header)?;
+        sodium_crypto_sign_verify_detached(
+            &header.node_vkey,
+            &header.unknown_2,
+            &opcert_signable(&header.pool_opcert, header.unknown_0, header.unknown_1),
+        )
+        .map_err(|_| "invalid opcert signature".to_string())?;
+        check_kes_period(
+            header.slot_number,
+            header.unknown_1,
+            self.shelley.slots_per_kes_period,
+            self.shelley.max_kes_evolutions,
+        )?;
+
+        // The proof alone determines the output, whatever the epoch nonce is
+        check_vrf_output("eta", &header.eta_vrf_0, &header.eta_vrf_1)?;
+        check_vrf_output("leader", &header.leader_vrf_0, &header.leader_vrf_1)?;
+        match self.epoch_nonce(db, header.slot_number) {
+            Some(epoch_nonce) => {
+                sodium_crypto_vrf_verify(
+                    &header.node_vrf_vkey,
+                    &header.eta_vrf_1,
+                    &mk_eta_seed(header.slot_number, &epoch_nonce),
+                )
+                .map_err(|_| "invalid eta VRF proof".to_string())?;
+                sodium_crypto_vrf_verify(
+                    &header.node_vrf_vkey,
+                    &header.leader_vrf_1,
+                    &mk_seed(header.slot_number, &epoch_nonce),
+                )
+                .map_err(|_| "invalid leader VRF proof".to_string())?;
+            }
+            None => debug!(
+                "No epoch nonce for slot {}, skipping VRF proof checks",
+                header.slot_number
+            ),
+        }
+        Ok(())
+    }
+
+    fn epoch_nonce(&mut self, db: &Connection, slot_number: i64) -> Option<Vec<u8>> {
+        let (epoch, first_slot_of_epoch) = get_first_slot_of_epoch(&self.byron, &self.shelley, slot_number);
+        if let Some(epoch_nonce) = self.epoch_nonces.get(&epoch) {
+            return Some(epoch_nonce.clone());
+        }
+        let extra_entropy = self.epoch_extra_entropy.get(&epoch).cloned();
+        match calculate_epoch_nonce(db, &self.byron, &self.shelley, first_slot_of_epoch, &extra_entropy) {
+            Ok(epoch_nonce) => {
+                debug!("epoch {} nonce: {}", epoch, hex::encode(&epoch_nonce));
+                self.epoch_nonces.insert(epoch, epoch_nonce.clone());
+                Some(epoch_nonce)
+            }
+            Err(error) => {
+                debug!("Cannot calculate the nonce of epoch {} yet: {}", epoch, error);
+                None
+            }
+        }
+    }
+}
+
+/// Check the length of each hash, key, signature and VRF value of a header
+pub fn check_sizes(header: &BlockHeader) -> Result<(), String> {
+    let sizes: [(&str, &[u8], usize); 9] = [
+        ("block_body_hash", &header.block_body_hash, 32),
+        ("node_vkey", &header.node_vkey, 32),
+        ("node_vrf_vkey", &header.node_vrf_vkey, 32),
+        ("pool_opcert", &header.pool_opcert, 32),
+        ("opcert_signature", &header.unknown_2, 64),
+        ("eta_vrf_0", &header.eta_vrf_0, 64),
+        ("eta_vrf_1", &header.eta_vrf_1, 80),
+        ("leader_vrf_0", &header.leader_vrf_0, 64),
+        ("leader_vrf_1", &header.leader_vrf_1, 80),
+    ];
+    for (name, value, size) in sizes.iter() {
+        if value.len() != *size {
+            return Err(format!("{} is {} bytes, expected {}", name, value.len(), size));
+        }
+    }
+    if header.block_size <= 0 {
+        return Err(format!("invalid block size {}", header.block_size));
+    }
+    Ok(())
+}
+
+/// The slot must be in one of the max_kes_evolutions KES periods starting at the opcert's KES period
+pub fn check_kes_period(
+    slot_number: i64,
+    opcert_kes_period: i64,
+    slots_per_kes_period: i64,
+    max_kes_evolutions: i64,
+) -> Result<(), String> {
+    let kes_period = slot_number / slots_per_kes_period;
+    if kes_period < opcert_kes_period {
+        return Err(format!(
+            "opcert KES period {} is after the KES period {} of the slot",
+            opcert_kes_period, kes_period
+        ));
+    }
+    if kes_period >= opcert_kes_period + max_kes_evolutions {
+        return Err(format!(
+            "opcert KES period {} expired at KES period {}",
+            opcert_kes_period,
+            opcert_kes_period + max_kes_evolutions
+        ));
+    }
+    Ok(())
+}
+
+fn check_vrf_output(name: &str, output: &[u8], proof: &[u8]) -> Result<(), String> {
+    match sodium_crypto_vrf_proof_to_hash(proof) {
+        Ok(proof_hash) if proof_hash == output => Ok(()),
+        _ => Err(format!("{} VRF output does not match its proof", name)),
+    }
+}
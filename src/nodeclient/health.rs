@@ -0,0 +1,270 @@
+use std::io::stdout;
+use std::path::Path;
+
+use log::debug;
+use rusqlite::{Connection, Error};
+use serde::Serialize;
+
+use crate::nodeclient::leaderlog::{
+    get_first_slot_of_epoch, get_first_slot_of_epoch_number, get_stability_window, get_tip_slot_number, handle_error,
+    read_byron_genesis, read_shelley_genesis,
+};
+use crate::nodeclient::OutputFormat;
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChainHealth {
+    pub status: String,
+    pub start_slot: i64,
+    pub end_slot: i64,
+    pub active_slots_coeff: f64,
+    pub density_threshold: f64,
+    pub summary: WindowHealth,
+    pub low_density_windows: i64,
+    pub windows: Vec<WindowHealth>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WindowHealth {
+    pub start_slot: i64,
+    pub end_slot: i64,
+    pub slots: i64,
+    pub blocks: i64,
+    pub orphans: i64,
+    pub density: f64,
+    pub density_ratio: f64,
+    pub longest_empty_slots: i64,
+    pub fork_rate: f64,
+    pub avg_block_size: f64,
+    pub low_density: bool,
+}
+
+struct ChainBlock {
+    slot_number: i64,
+    block_size: i64,
+    orphaned: bool,
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn chain_health(
+    db_path: &Path,
+    byron_genesis: &Path,
+    shelley_genesis: &Path,
+    epochs: i64,
+    start_slot: &Option<i64>,
+    end_slot: &Option<i64>,
+    window: &Option<i64>,
+    density_threshold: f64,
+    output_format: &OutputFormat,
+) {
+    if !db_path.exists() {
+        handle_error("database not found!");
+        return;
+    }
+
+    match calculate_chain_health(
+        db_path,
+        byron_genesis,
+        shelley_genesis,
+        epochs,
+        start_slot,
+        end_slot,
+        window,
+        density_threshold,
+    ) {
+        Ok(chain_health) => match output_format {
+            OutputFormat::Json => {
+                serde_json::ser::to_writer_pretty(&mut stdout(), &chain_health).unwrap();
+            }
+            OutputFormat::Table => print_chain_health_table(&chain_health),
+        },
+        Err(error) => handle_error(error),
+    }
+}
+
+/// Chain density, orphans and gaps in each window of the slot range and over all of it
+#[allow(clippy::too_many_arguments)]
+pub fn calculate_chain_health(
+    db_path: &Path,
+    byron_genesis: &Path,
+    shelley_genesis: &Path,
+    epochs: i64,
+    start_slot: &Option<i64>,
+    end_slot: &Option<i64>,
+    window: &Option<i64>,
+    density_threshold: f64,
+) -> Result<ChainHealth, String> {
+    let byron = read_byron_genesis(byron_genesis).map_err(|error| error.to_string())?;
+    debug!("{:?}", byron);
+    let shelley = read_shelley_genesis(shelley_genesis).map_err(|error| error.to_string())?;
+    debug!("{:?}", shelley);
+
+    let db = Connection::open(db_path).map_err(|error| error.to_string())?;
+    let tip_slot_number = get_tip_slot_number(&db).map_err(|error| error.to_string())?;
+    debug!("tip_slot_number: {}", tip_slot_number);
+
+    // Default to the last number of epochs up to the tip unless an explicit slot range is given
+    let (tip_epoch, _) = get_first_slot_of_epoch(&byron, &shelley, tip_slot_number);
+    let start_slot =
+        start_slot.unwrap_or_else(|| get_first_slot_of_epoch_number(&byron, &shelley, tip_epoch - epochs + 1));
+    let end_slot = end_slot.unwrap_or(tip_slot_number).min(tip_slot_number) + 1;
+    if start_slot >= end_slot {
+        return Err(format!("Invalid slot range: {} - {}", start_slot, end_slot - 1));
+    }
+    let window = window.unwrap_or_else(|| get_stability_window(&byron, &shelley));
+    if window <= 0 {
+        return Err("window must be greater than zero!".to_string());
+    }
+    debug!("start_slot: {}, end_slot: {}, window: {}", start_slot, end_slot, window);
+
+    let blocks = get_chain_blocks(&db, start_slot, end_slot).map_err(|error| error.to_string())?;
+    if let Err(error) = db.close() {
+        return Err(format!("db close error: {}", error.1));
+    }
+
+    let active_slots_coeff = shelley.active_slots_coeff;
+    let mut windows: Vec<WindowHealth> = vec![];
+    let mut window_start = start_slot;
+    // The blocks are sorted by slot, so each window takes the next run of them
+    let mut first_block = 0;
+    while window_start < end_slot {
+        let window_end = (window_start + window).min(end_slot);
+        let end_block = first_block
+            + blocks[first_block..]
+                .iter()
+                .take_while(|block| block.slot_number < window_end)
+                .count();
+        windows.push(window_health(
+            window_start,
+            window_end,
+            &blocks[first_block..end_block],
+            active_slots_coeff,
+            density_threshold,
+        ));
+        first_block = end_block;
+        window_start = window_end;
+    }
+
+    Ok(ChainHealth {
+        status: "ok".to_string(),
+        start_slot,
+        end_slot: end_slot - 1,
+        active_slots_coeff,
+        density_threshold,
+        summary: window_health(start_slot, end_slot, &blocks, active_slots_coeff, density_threshold),
+        low_density_windows: windows.iter().filter(|window| window.low_density).count() as i64,
+        windows,
+    })
+}
+
+fn window_health(
+    start_slot: i64,
+    end_slot: i64,
+    blocks: &[ChainBlock],
+    active_slots_coeff: f64,
+    density_threshold: f64,
+) -> WindowHealth {
+    let slots = end_slot - start_slot;
+    let on_chain: Vec<&ChainBlock> = blocks.iter().filter(|block| !block.orphaned).collect();
+    let orphans = (blocks.len() - on_chain.len()) as i64;
+
+    // Empty slots between consecutive blocks, including the edges of the window
+    let mut longest_empty_slots = 0;
+    let mut prev_slot = start_slot - 1;
+    for block in on_chain.iter() {
+        longest_empty_slots = longest_empty_slots.max(block.slot_number - prev_slot - 1);
+        prev_slot = block.slot_number;
+    }
+    longest_empty_slots = longest_empty_slots.max(end_slot - prev_slot - 1);
+
+    let density = on_chain.len() as f64 / slots as f64;
+    let density_ratio = density / active_slots_coeff;
+    let total_size: i64 = on_chain.iter().map(|block| block.block_size).sum();
+
+    WindowHealth {
+        start_slot,
+        end_slot: end_slot - 1,
+        slots,
+        blocks: on_chain.len() as i64,
+        orphans,
+        density: round(density, 6),
+        density_ratio: round(density_ratio, 4),
+        longest_empty_slots,
+        fork_rate: if blocks.is_empty() {
+            0.0
+        } else {
+            round(orphans as f64 / blocks.len() as f64, 6)
+        },
+        avg_block_size: if on_chain.is_empty() {
+            0.0
+        } else {
+            round(total_size as f64 / on_chain.len() as f64, 2)
+        },
+        low_density: density_ratio < density_threshold,
+    }
+}
+
+fn get_chain_blocks(db: &Connection, start_slot: i64, end_slot: i64) -> Result<Vec<ChainBlock>, Error> {
+    let mut stmt = db.prepare(
+        "SELECT slot_number, block_size, orphaned FROM chain \
+        WHERE slot_number >= ?1 AND slot_number < ?2 ORDER BY slot_number ASC",
+    )?;
+    let blocks = stmt
+        .query_map(&[&start_slot, &end_slot], |row| {
+            Ok(ChainBlock {
+                slot_number: row.get(0)?,
+                block_size: row.get(1)?,
+                orphaned: row.get(2)?,
+            })
+        })?
+        .collect::<Result<Vec<ChainBlock>, Error>>();
+    blocks
+}
+
+fn round(value: f64, decimals: i32) -> f64 {
+    let factor = 10f64.powi(decimals);
+    (value * factor).round() / factor
+}
+
+fn print_chain_health_table(chain_health: &ChainHealth) {
+    println!(
+        "Slots {} - {}, activeSlotsCoeff: {}, density threshold: {}",
+        chain_health.start_slot, chain_health.end_slot, chain_health.active_slots_coeff, chain_health.density_threshold
+    );
+    println!();
+    println!(
+        "{:>10} {:>10} {:>7} {:>8} {:>9} {:>8} {:>11} {:>9} {:>12} {:>4}",
+        "StartSlot",
+        "EndSlot",
+        "Blocks",
+        "Orphans",
+        "Density",
+        "Ratio",
+        "EmptySlots",
+        "ForkRate",
+        "AvgBlockSize",
+        "Low"
+    );
+    for window in chain_health
+        .windows
+        .iter()
+        .chain(std::iter::once(&chain_health.summary))
+    {
+        println!(
+            "{:>10} {:>10} {:>7} {:>8} {:>9.6} {:>8.4} {:>11} {:>9.6} {:>12.2} {:>4}",
+            window.start_slot,
+            window.end_slot,
+            window.blocks,
+            window.orphans,
+            window.density,
+            window.density_ratio,
+            window.longest_empty_slots,
+            window.fork_rate,
+            window.avg_block_size,
+            if window.low_density { "*" } else { "" },
+        );
+    }
+    println!();
+    println!("Low density windows: {}", chain_health.low_density_windows);
+}
@@ -22,6 +22,8 @@ use crate::nodeclient::leaderlog::deserialize::cbor_hex;
 use crate::nodeclient::leaderlog::ledgerstate::calculate_ledger_state_sigma_d_and_extra_entropy;
 use crate::nodeclient::leaderlog::libsodium::{sodium_crypto_vrf_proof_to_hash, sodium_crypto_vrf_prove};
 use crate::nodeclient::math::{ln, normalize, round, taylor_exp_cmp, TaylorCmp};
+use crate::nodeclient::outbox;
+use crate::nodeclient::pooltool::{PooltoolClient, PooltoolSendSlots, SENDSLOTS};
 use crate::nodeclient::{LedgerSet, PooltoolConfig};
 
 mod deserialize;
@@ -37,7 +39,7 @@ struct LeaderLogError {
 
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
-struct ByronGenesis {
+pub struct ByronGenesis {
     start_time: i64,
     protocol_consts: ProtocolConsts,
     block_version_data: BlockVersionData,
@@ -58,11 +60,30 @@ struct BlockVersionData {
 
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
-struct ShelleyGenesis {
-    active_slots_coeff: f64,
+pub struct ShelleyGenesis {
+    pub(crate) active_slots_coeff: f64,
     network_magic: u32,
     slot_length: i64,
-    epoch_length: i64,
+    pub(crate) epoch_length: i64,
+    #[serde(default = "default_security_param")]
+    pub(crate) security_param: i64,
+    #[serde(rename = "slotsPerKESPeriod", default = "default_slots_per_kes_period")]
+    pub(crate) slots_per_kes_period: i64,
+    #[serde(rename = "maxKESEvolutions", default = "default_max_kes_evolutions")]
+    pub(crate) max_kes_evolutions: i64,
+}
+
+// Mainnet values, for genesis files written without them
+fn default_security_param() -> i64 {
+    2160
+}
+
+fn default_slots_per_kes_period() -> i64 {
+    129600
+}
+
+fn default_max_kes_evolutions() -> i64 {
+    62
 }
 
 #[derive(Debug, Deserialize)]
@@ -92,6 +113,17 @@ struct LeaderLog {
     assigned_slots: Vec<Slot>,
 }
 
+/// The slots saved by leaderlog, without the stake figures that were used to calculate them
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SavedLeaderLog {
+    status: String,
+    epoch: i64,
+    pool_id: String,
+    epoch_slots: i64,
+    assigned_slots: Vec<Slot>,
+}
+
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
 struct Slot {
@@ -101,26 +133,12 @@ struct Slot {
     at: String,
 }
 
-#[derive(Debug, Serialize)]
-#[serde(rename_all = "camelCase")]
-struct PooltoolSendSlots {
-    api_key: String,
-    pool_id: String,
-    epoch: i64,
-    slot_qty: i64,
-    hash: String,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    override_time: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    prev_slots: Option<String>,
-}
-
-fn read_byron_genesis(byron_genesis: &Path) -> Result<ByronGenesis, Error> {
+pub fn read_byron_genesis(byron_genesis: &Path) -> Result<ByronGenesis, Error> {
     let buf = BufReader::new(File::open(byron_genesis)?);
     Ok(serde_json::from_reader(buf)?)
 }
 
-fn read_shelley_genesis(shelley_genesis: &Path) -> Result<ShelleyGenesis, Error> {
+pub fn read_shelley_genesis(shelley_genesis: &Path) -> Result<ShelleyGenesis, Error> {
     let buf = BufReader::new(File::open(shelley_genesis)?);
     Ok(serde_json::from_reader(buf)?)
 }
@@ -130,7 +148,7 @@ pub(crate) fn read_vrf_key(vrf_key_path: &Path) -> Result<VrfKey, Error> {
     Ok(serde_json::from_reader(buf)?)
 }
 
-fn get_tip_slot_number(db: &Connection) -> Result<i64, rusqlite::Error> {
+pub(crate) fn get_tip_slot_number(db: &Connection) -> Result<i64, rusqlite::Error> {
     db.query_row("SELECT MAX(slot_number) FROM chain", NO_PARAMS, |row| row.get(0))
 }
 
@@ -186,7 +204,7 @@ fn get_shelley_transition_epoch(network_magic: u32) -> i64 {
     }
 }
 
-fn get_first_slot_of_epoch(byron: &ByronGenesis, shelley: &ShelleyGenesis, current_slot: i64) -> (i64, i64) {
+pub(crate) fn get_first_slot_of_epoch(byron: &ByronGenesis, shelley: &ShelleyGenesis, current_slot: i64) -> (i64, i64) {
     let shelley_transition_epoch = get_shelley_transition_epoch(shelley.network_magic);
     let byron_epoch_length = 10 * byron.protocol_consts.k;
     let byron_slots = byron_epoch_length * shelley_transition_epoch;
@@ -198,7 +216,20 @@ fn get_first_slot_of_epoch(byron: &ByronGenesis, shelley: &ShelleyGenesis, curre
     (epoch, first_slot_of_epoch)
 }
 
-fn slot_to_naivedatetime(byron: &ByronGenesis, shelley: &ShelleyGenesis, slot: i64) -> NaiveDateTime {
+pub(crate) fn get_first_slot_of_epoch_number(byron: &ByronGenesis, shelley: &ShelleyGenesis, epoch: i64) -> i64 {
+    let shelley_transition_epoch = get_shelley_transition_epoch(shelley.network_magic);
+    let byron_epoch_length = 10 * byron.protocol_consts.k;
+    let byron_slots = byron_epoch_length * shelley_transition_epoch;
+
+    byron_slots + (epoch - shelley_transition_epoch) * shelley.epoch_length
+}
+
+// The number of slots in which k blocks are expected to be made (3k/f)
+pub(crate) fn get_stability_window(byron: &ByronGenesis, shelley: &ShelleyGenesis) -> i64 {
+    ((3 * byron.protocol_consts.k) as f64 / shelley.active_slots_coeff).ceil() as i64
+}
+
+pub(crate) fn slot_to_naivedatetime(byron: &ByronGenesis, shelley: &ShelleyGenesis, slot: i64) -> NaiveDateTime {
     let shelley_transition_epoch = get_shelley_transition_epoch(shelley.network_magic);
     let network_start_time = NaiveDateTime::from_timestamp(byron.start_time, 0);
     let byron_epoch_length = 10 * byron.protocol_consts.k;
@@ -239,7 +270,29 @@ const UC_NONCE: [u8; 32] = [
     0xc7, 0xc5, 0xc2, 0xbd, 0x68, 0x28, 0xe1, 0x4a, 0x7d, 0x25, 0xfa, 0x3a, 0x60,
 ];
 
-fn mk_seed(slot: i64, eta0: &[u8]) -> Vec<u8> {
+//
+// The blake2b hash of the 8 byte long value of 0
+// 81e47a19e6b29b0a65b9591762ce5143ed30d0261e5d24a3201752506b20f15c
+// Sometimes called seedEta in the haskell code
+//
+const ETA_NONCE: [u8; 32] = [
+    0x81, 0xe4, 0x7a, 0x19, 0xe6, 0xb2, 0x9b, 0x0a, 0x65, 0xb9, 0x59, 0x17, 0x62, 0xce, 0x51, 0x43, 0xed, 0x30, 0xd0,
+    0x26, 0x1e, 0x5d, 0x24, 0xa3, 0x20, 0x17, 0x52, 0x50, 0x6b, 0x20, 0xf1, 0x5c,
+];
+
+// The value 2^512
+pub const CERT_NAT_MAX: &str = "13407807929942597099574024998205846127479365820592393377723561443721764030073546976801874298166903427690031858186486050853753882811946569946433649006084096";
+
+pub(crate) fn mk_seed(slot: i64, eta0: &[u8]) -> Vec<u8> {
+    mk_seed_with_nonce(slot, eta0, &UC_NONCE)
+}
+
+// The seed the eta_vrf proof of a block is made for
+pub(crate) fn mk_eta_seed(slot: i64, eta0: &[u8]) -> Vec<u8> {
+    mk_seed_with_nonce(slot, eta0, &ETA_NONCE)
+}
+
+fn mk_seed_with_nonce(slot: i64, eta0: &[u8], nonce: &[u8]) -> Vec<u8> {
     trace!("mk_seed() start slot {}", slot);
     let mut concat = [0u8; 8 + 32];
     NetworkEndian::write_i64(&mut concat, slot);
@@ -254,7 +307,7 @@ fn mk_seed(slot: i64, eta0: &[u8]) -> Vec<u8> {
         .as_bytes()
         .to_owned();
 
-    UC_NONCE
+    nonce
         .iter()
         .enumerate()
         .map(|(i, byte)| byte ^ slot_to_seed[i])
@@ -287,6 +340,20 @@ fn is_slot_leader(
     trace!("seed: {}", hex::encode(&seed));
     let cert_nat: BigInt = vrf_eval_certified(&*seed, pool_vrf_skey)?;
     trace!("cert_nat: {}", &cert_nat);
+    Ok(is_leader_value_below_threshold(cert_nat, sigma, cert_nat_max, c))
+}
+
+// Compare a certified vrf leader value against the leader threshold for the pool's stake
+// @param cert_nat The leader vrf output as a natural number
+// @param sigma The controlled stake proportion for the pool
+// @param cert_nat_max The value 2^512
+// @param c 1-activeSlotsCoeff - usually 0.95
+pub fn is_leader_value_below_threshold(
+    cert_nat: BigInt,
+    sigma: &BigDecimal,
+    cert_nat_max: &BigDecimal,
+    c: &BigDecimal,
+) -> bool {
     let denominator = cert_nat_max - BigDecimal::from(cert_nat);
     let recip_q: BigDecimal = normalize(cert_nat_max / denominator);
     trace!("recip_q: {}", &recip_q);
@@ -295,9 +362,65 @@ fn is_slot_leader(
     trace!("x: {}", &x);
 
     match taylor_exp_cmp(3, &recip_q, &x) {
-        TaylorCmp::Above => Ok(false),
-        TaylorCmp::Below => Ok(true),
-        TaylorCmp::MaxReached => Ok(false),
+        TaylorCmp::Above => false,
+        TaylorCmp::Below => true,
+        TaylorCmp::MaxReached => false,
+    }
+}
+
+// Calculate the epoch nonce for the epoch starting at first_slot_of_epoch
+// @param first_slot_of_epoch The first slot of the epoch we want the nonce for
+// @param extra_entropy Optional hex string of extra entropy to mix into the nonce
+pub(crate) fn calculate_epoch_nonce(
+    db: &Connection,
+    byron: &ByronGenesis,
+    shelley: &ShelleyGenesis,
+    first_slot_of_epoch: i64,
+    extra_entropy: &Option<String>,
+) -> Result<Vec<u8>, rusqlite::Error> {
+    let first_slot_of_prev_epoch = first_slot_of_epoch - shelley.epoch_length;
+    debug!("first_slot_of_prev_epoch: {}", first_slot_of_prev_epoch);
+    let stability_window = get_stability_window(byron, shelley);
+    let stability_window_start = first_slot_of_epoch - stability_window;
+    debug!("stability_window: {}", stability_window);
+    debug!("stability_window_start: {}", stability_window_start);
+
+    let nc = get_eta_v_before_slot(db, stability_window_start)?;
+    debug!("nc: {}", nc);
+    let nh = get_prev_hash_before_slot(db, first_slot_of_prev_epoch)?;
+    debug!("nh: {}", nh);
+    Ok(mk_epoch_nonce(&nc, &nh, extra_entropy))
+}
+
+// Hash the candidate nonce with the hash of the last block of the previous epoch, then mix in any extra entropy
+// @param nc The hex eta_v of the last block before the stability window
+// @param nh The hex prev_hash of the last block before the previous epoch started
+fn mk_epoch_nonce(nc: &str, nh: &str, extra_entropy: &Option<String>) -> Vec<u8> {
+    let mut nc_nh = String::new();
+    nc_nh.push_str(nc);
+    nc_nh.push_str(nh);
+    let epoch_nonce = Params::new()
+        .hash_length(32)
+        .to_state()
+        .update(&*hex::decode(nc_nh).unwrap())
+        .finalize()
+        .as_bytes()
+        .to_owned();
+
+    match extra_entropy {
+        None => epoch_nonce,
+        Some(entropy) => {
+            let mut nonce_entropy = String::new();
+            nonce_entropy.push_str(&*hex::encode(&epoch_nonce));
+            nonce_entropy.push_str(&*entropy);
+            Params::new()
+                .hash_length(32)
+                .to_state()
+                .update(&*hex::decode(nonce_entropy).unwrap())
+                .finalize()
+                .as_bytes()
+                .to_owned()
+        }
     }
 }
 
@@ -389,8 +512,7 @@ pub(crate) fn calculate_leader_logs(
                     let first_slot_of_prev_epoch = first_slot_of_epoch - shelley.epoch_length;
                     debug!("first_slot_of_epoch: {}", first_slot_of_epoch);
                     debug!("first_slot_of_prev_epoch: {}", first_slot_of_prev_epoch);
-                    let stability_window: i64 =
-                        ((3 * byron.protocol_consts.k) as f64 / shelley.active_slots_coeff).ceil() as i64;
+                    let stability_window = get_stability_window(&byron, &shelley);
                     let stability_window_start = first_slot_of_epoch - stability_window;
                     debug!("stability_window: {}", stability_window);
                     debug!("stability_window_start: {}", stability_window_start);
@@ -403,32 +525,7 @@ pub(crate) fn calculate_leader_logs(
                                     match get_prev_hash_before_slot(&db, first_slot_of_prev_epoch) {
                                         Ok(nh) => {
                                             debug!("nh: {}", nh);
-                                            let mut nc_nh = String::new();
-                                            nc_nh.push_str(&*nc);
-                                            nc_nh.push_str(&*nh);
-                                            let epoch_nonce = Params::new()
-                                                .hash_length(32)
-                                                .to_state()
-                                                .update(&*hex::decode(nc_nh).unwrap())
-                                                .finalize()
-                                                .as_bytes()
-                                                .to_owned();
-
-                                            let epoch_nonce = match &ledger_info.extra_entropy {
-                                                None => { epoch_nonce }
-                                                Some(entropy) => {
-                                                    let mut nonce_entropy = String::new();
-                                                    nonce_entropy.push_str(&*hex::encode(&epoch_nonce));
-                                                    nonce_entropy.push_str(&*entropy);
-                                                    Params::new()
-                                                        .hash_length(32)
-                                                        .to_state()
-                                                        .update(&*hex::decode(nonce_entropy).unwrap())
-                                                        .finalize()
-                                                        .as_bytes()
-                                                        .to_owned()
-                                                }
-                                            };
+                                            let epoch_nonce = mk_epoch_nonce(&nc, &nh, &ledger_info.extra_entropy);
 
                                             debug!("epoch_nonce: {}", hex::encode(&epoch_nonce));
                                             if is_just_nonce {
@@ -476,7 +573,7 @@ pub(crate) fn calculate_leader_logs(
                                                         assigned_slots: vec![],
                                                     };
 
-                                                    let cert_nat_max: BigDecimal = BigDecimal::from_str("13407807929942597099574024998205846127479365820592393377723561443721764030073546976801874298166903427690031858186486050853753882811946569946433649006084096").unwrap(); // 2^512
+                                                    let cert_nat_max: BigDecimal = BigDecimal::from_str(CERT_NAT_MAX).unwrap();
                                                     let c: BigDecimal = ln(&(BigDecimal::one()
                                                         - BigDecimal::from_f64(shelley.active_slots_coeff).unwrap()));
 
@@ -576,43 +673,78 @@ pub(crate) fn calculate_leader_logs(
 }
 
 pub(crate) fn status(db_path: &Path, byron_genesis: &Path, shelley_genesis: &Path) {
-    if !db_path.exists() {
-        handle_error("database not found!");
-        return;
+    match check_synced(db_path, byron_genesis, shelley_genesis) {
+        Ok(()) => print_status_synced(),
+        Err(error) => handle_error(error),
     }
-    let db = Connection::open(db_path).unwrap();
+}
 
-    match read_byron_genesis(byron_genesis) {
-        Ok(byron) => {
-            debug!("{:?}", byron);
-            match read_shelley_genesis(shelley_genesis) {
-                Ok(shelley) => {
-                    debug!("{:?}", shelley);
-                    match get_tip_slot_number(&db) {
-                        Ok(tip_slot_number) => {
-                            debug!("tip_slot_number: {}", tip_slot_number);
-                            let tip_time = slot_to_naivedatetime(&byron, &shelley, tip_slot_number).timestamp();
-                            let system_time = Utc::now().timestamp();
-                            if system_time - tip_time < 120 {
-                                print_status_synced();
-                            } else {
-                                handle_error("db not fully synced!")
-                            }
-                        }
-                        Err(error) => handle_error(error),
-                    }
-                }
-                Err(error) => handle_error(error),
-            }
-        }
-        Err(error) => handle_error(error),
+/// Ok when the tip of the db is less than two minutes behind the system time
+pub(crate) fn check_synced(db_path: &Path, byron_genesis: &Path, shelley_genesis: &Path) -> Result<(), String> {
+    if !db_path.exists() {
+        return Err("database not found!".to_string());
     }
+    let byron = read_byron_genesis(byron_genesis).map_err(|error| error.to_string())?;
+    debug!("{:?}", byron);
+    let shelley = read_shelley_genesis(shelley_genesis).map_err(|error| error.to_string())?;
+    debug!("{:?}", shelley);
 
+    let db = Connection::open(db_path).map_err(|error| error.to_string())?;
+    let synced = check_tip_synced(&db, &byron, &shelley);
     if let Err(error) = db.close() {
-        handle_error(format!("db close error: {}", error.1));
+        return Err(format!("db close error: {}", error.1));
+    }
+    synced
+}
+
+/// Ok when the tip of the open db is less than two minutes behind the system time
+pub(crate) fn check_tip_synced(db: &Connection, byron: &ByronGenesis, shelley: &ShelleyGenesis) -> Result<(), String> {
+    let tip_slot_number = get_tip_slot_number(db).map_err(|error| error.to_string())?;
+    debug!("tip_slot_number: {}", tip_slot_number);
+    let tip_time = slot_to_naivedatetime(byron, shelley, tip_slot_number).timestamp();
+    let system_time = Utc::now().timestamp();
+    if system_time - tip_time < 120 {
+        Ok(())
+    } else {
+        Err("db not fully synced!".to_string())
     }
 }
 
+/// The assigned slots a leaderlog run saved for the pool in the epoch, None when there is no leaderlog
+pub(crate) fn get_saved_leader_log(
+    db: &Connection,
+    byron: &ByronGenesis,
+    shelley: &ShelleyGenesis,
+    epoch: i64,
+    pool_id: &str,
+    tz: &Tz,
+) -> Result<Option<SavedLeaderLog>, String> {
+    let slots = match get_prev_slots(db, epoch, pool_id).map_err(|error| error.to_string())? {
+        Some(slots) => slots,
+        None => return Ok(None),
+    };
+    let slots: Vec<i64> =
+        serde_json::from_str(&slots).map_err(|error| format!("invalid slots for epoch {}: {}", epoch, error))?;
+    let first_slot_of_epoch = get_first_slot_of_epoch_number(byron, shelley, epoch);
+    let assigned_slots: Vec<Slot> = slots
+        .iter()
+        .enumerate()
+        .map(|(i, slot)| Slot {
+            no: (i + 1) as i64,
+            slot: *slot,
+            slot_in_epoch: slot - first_slot_of_epoch,
+            at: slot_to_timestamp(byron, shelley, *slot, tz),
+        })
+        .collect();
+    Ok(Some(SavedLeaderLog {
+        status: "ok".to_string(),
+        epoch,
+        pool_id: pool_id.to_string(),
+        epoch_slots: assigned_slots.len() as i64,
+        assigned_slots,
+    }))
+}
+
 pub(crate) fn send_slots(
     db_path: &Path,
     byron_genesis: &Path,
@@ -624,6 +756,17 @@ pub(crate) fn send_slots(
         handle_error("database not found!");
         return;
     }
+    let pooltool_client = match PooltoolClient::from_config(&pooltool_config) {
+        Ok(pooltool_client) => pooltool_client,
+        Err(error) => {
+            handle_error(error);
+            return;
+        }
+    };
+    // Retry anything a previous run failed to send
+    for pool in pooltool_config.pools.iter() {
+        outbox::retry_due(db_path, &pooltool_client, SENDSLOTS, &pool.pool_id);
+    }
     let db = Connection::open(db_path).unwrap();
 
     match read_byron_genesis(byron_genesis) {
@@ -647,7 +790,7 @@ pub(crate) fn send_slots(
                                             debug!("hash: {}", &hash);
                                             match get_prev_slots(&db, epoch - 1, &pool.pool_id) {
                                                 Ok(prev_slots) => {
-                                                    let request = serde_json::ser::to_string(&PooltoolSendSlots {
+                                                    let send_slots = PooltoolSendSlots {
                                                         api_key: pooltool_config.api_key.clone(),
                                                         pool_id: pool.pool_id.clone(),
                                                         epoch,
@@ -655,32 +798,20 @@ pub(crate) fn send_slots(
                                                         hash,
                                                         override_time: override_time.clone(),
                                                         prev_slots,
-                                                    })
-                                                    .unwrap();
-                                                    info!("Sending: {}", &request);
-                                                    match reqwest::blocking::Client::builder().build() {
-                                                        Ok(client) => {
-                                                            let pooltool_result = client
-                                                                .post("https://api.pooltool.io/v0/sendslots")
-                                                                .body(request)
-                                                                .send();
-
-                                                            match pooltool_result {
-                                                                Ok(response) => match response.text() {
-                                                                    Ok(text) => {
-                                                                        info!("Pooltool Response: {}", text);
-                                                                    }
-                                                                    Err(error) => {
-                                                                        error!("PoolTool error: {}", error);
-                                                                    }
-                                                                },
-                                                                Err(error) => {
-                                                                    error!("PoolTool error: {}", error);
-                                                                }
-                                                            }
+                                                    };
+                                                    match pooltool_client.send_slots(&send_slots) {
+                                                        Ok(text) => {
+                                                            info!("Pooltool Response: {}", text);
                                                         }
-                                                        Err(err) => {
-                                                            error!("Could not set up the reqwest client!: {}", err)
+                                                        Err(error) => {
+                                                            error!("PoolTool error: {}", error);
+                                                            outbox::save_failed(
+                                                                db_path,
+                                                                SENDSLOTS,
+                                                                &pool.pool_id,
+                                                                &serde_json::ser::to_string(&send_slots).unwrap(),
+                                                                &error,
+                                                            );
                                                         }
                                                     }
                                                 }
@@ -8,6 +8,9 @@ extern "C" {
 
     // int crypto_vrf_verify(unsigned char *output, const unsigned char *pk, const unsigned char *proof, const unsigned char *m, unsigned long long mlen)
     fn crypto_vrf_verify(output: *mut u8, pk: *const u8, proof: *const u8, m: *const u8, mlen: u64) -> i32;
+
+    // int crypto_sign_verify_detached(const unsigned char *sig, const unsigned char *m, unsigned long long mlen, const unsigned char *pk);
+    fn crypto_sign_verify_detached(sig: *const u8, m: *const u8, mlen: u64, pk: *const u8) -> i32;
 }
 
 pub(crate) fn sodium_crypto_vrf_prove(secret_key: &[u8], seed: &[u8]) -> Result<Vec<u8>, String> {
@@ -32,6 +35,9 @@ pub(crate) fn sodium_crypto_vrf_prove(secret_key: &[u8], seed: &[u8]) -> Result<
 }
 
 pub(crate) fn sodium_crypto_vrf_proof_to_hash(proof: &[u8]) -> Result<Vec<u8>, String> {
+    if proof.len() != 80 {
+        return Err("libsodium crypto_vrf_proof_to_hash() called with an invalid proof length".to_string());
+    }
     let mut hash: Vec<u8> = Vec::with_capacity(64);
     unsafe {
         let rc = crypto_vrf_proof_to_hash(hash.as_mut_ptr(), proof.as_ptr());
@@ -48,6 +54,9 @@ pub(crate) fn sodium_crypto_vrf_proof_to_hash(proof: &[u8]) -> Result<Vec<u8>, S
 }
 
 pub(crate) fn sodium_crypto_vrf_verify(public_key: &[u8], signature: &[u8], seed: &[u8]) -> Result<Vec<u8>, String> {
+    if public_key.len() != 32 || signature.len() != 80 {
+        return Err("libsodium crypto_vrf_verify() called with an invalid key or proof length".to_string());
+    }
     let mut verification: Vec<u8> = Vec::with_capacity(64);
     unsafe {
         let rc = crypto_vrf_verify(
@@ -68,3 +77,31 @@ pub(crate) fn sodium_crypto_vrf_verify(public_key: &[u8], signature: &[u8], seed
         }
     }
 }
+
+pub(crate) fn sodium_crypto_sign_verify_detached(
+    public_key: &[u8],
+    signature: &[u8],
+    message: &[u8],
+) -> Result<(), String> {
+    if public_key.len() != 32 || signature.len() != 64 {
+        return Err(
+            "libsodium crypto_sign_verify_detached() called with an invalid key or signature length".to_string(),
+        );
+    }
+    unsafe {
+        let rc = crypto_sign_verify_detached(
+            signature.as_ptr(),
+            message.as_ptr(),
+            message.len() as u64,
+            public_key.as_ptr(),
+        );
+        if rc != 0 {
+            Err(format!(
+                "libsodium crypto_sign_verify_detached() failed, returned {}, expected 0",
+                rc
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
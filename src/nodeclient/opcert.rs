@@ -0,0 +1,290 @@
+use std::fs::File;
+use std::io::{stdout, BufReader};
+use std::path::Path;
+
+use blake2b_simd::Params;
+use chrono::{SecondsFormat, TimeZone, Utc};
+use log::debug;
+use rusqlite::{Connection, Error};
+use serde::{Deserialize, Serialize};
+use serde_cbor::{de, Value};
+
+use crate::nodeclient::leaderlog::{
+    get_tip_slot_number, handle_error, read_byron_genesis, read_shelley_genesis, slot_to_naivedatetime,
+};
+
+#[derive(Debug, Deserialize)]
+struct OpcertFile {
+    #[serde(rename(deserialize = "type"))]
+    cert_type: String,
+    #[serde(rename(deserialize = "cborHex"))]
+    cbor_hex: String,
+}
+
+#[derive(Debug)]
+pub struct Opcert {
+    pub hot_vkey: Vec<u8>,
+    pub counter: i64,
+    pub kes_period: i64,
+    pub signature: Vec<u8>,
+    pub cold_vkey: Vec<u8>,
+}
+
+pub(crate) struct OnChainOpcert {
+    pub(crate) block_number: i64,
+    pub(crate) slot_number: i64,
+    pub(crate) counter: i64,
+    pub(crate) kes_period: i64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OpcertStatus {
+    pub status: String,
+    pub pool_id: String,
+    pub block_number: i64,
+    pub slot_number: i64,
+    pub opcert_counter: i64,
+    pub opcert_kes_period: i64,
+    pub current_kes_period: i64,
+    pub kes_expiry_period: i64,
+    pub kes_expiry_date: String,
+    pub kes_remaining_days: f64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CheckOpcert {
+    status: String,
+    pool_id: String,
+    opcert_counter: i64,
+    opcert_kes_period: i64,
+    on_chain_counter: Option<i64>,
+    on_chain_kes_period: Option<i64>,
+    current_kes_period: i64,
+    counter_valid: bool,
+    kes_period_valid: bool,
+    errors: Vec<String>,
+}
+
+pub(crate) fn opcert(db_path: &Path, byron_genesis: &Path, shelley_genesis: &Path, pool_id: &str) {
+    if !db_path.exists() {
+        handle_error("database not found!");
+        return;
+    }
+
+    match opcert_status(db_path, byron_genesis, shelley_genesis, pool_id) {
+        Ok(opcert_status) => {
+            serde_json::ser::to_writer_pretty(&mut stdout(), &opcert_status).unwrap();
+        }
+        Err(error) => handle_error(error),
+    }
+}
+
+/// The opcert of the pool's latest block on the chain and when its KES key expires
+pub fn opcert_status(
+    db_path: &Path,
+    byron_genesis: &Path,
+    shelley_genesis: &Path,
+    pool_id: &str,
+) -> Result<OpcertStatus, String> {
+    let byron = read_byron_genesis(byron_genesis).map_err(|error| error.to_string())?;
+    debug!("{:?}", byron);
+    let shelley = read_shelley_genesis(shelley_genesis).map_err(|error| error.to_string())?;
+    debug!("{:?}", shelley);
+
+    let db = Connection::open(db_path).map_err(|error| error.to_string())?;
+    let tip_slot_number = get_tip_slot_number(&db).map_err(|error| error.to_string())?;
+    debug!("tip_slot_number: {}", tip_slot_number);
+    let opcert = get_latest_opcert(&db, pool_id).map_err(|error| match error {
+        Error::QueryReturnedNoRows => format!("No blocks found for pool {}", pool_id),
+        _ => error.to_string(),
+    })?;
+    if let Err(error) = db.close() {
+        return Err(format!("db close error: {}", error.1));
+    }
+
+    // KES keys can evolve max_kes_evolutions times from the opcert's starting period before they expire
+    let current_kes_period = tip_slot_number / shelley.slots_per_kes_period;
+    let kes_expiry_period = opcert.kes_period + shelley.max_kes_evolutions;
+    let kes_expiry_time = slot_to_naivedatetime(&byron, &shelley, kes_expiry_period * shelley.slots_per_kes_period);
+    let kes_remaining_secs = (kes_expiry_time - Utc::now().naive_utc()).num_seconds();
+
+    Ok(OpcertStatus {
+        status: "ok".to_string(),
+        pool_id: pool_id.to_string(),
+        block_number: opcert.block_number,
+        slot_number: opcert.slot_number,
+        opcert_counter: opcert.counter,
+        opcert_kes_period: opcert.kes_period,
+        current_kes_period,
+        kes_expiry_period,
+        kes_expiry_date: Utc
+            .from_utc_datetime(&kes_expiry_time)
+            .to_rfc3339_opts(SecondsFormat::Secs, true),
+        kes_remaining_days: (kes_remaining_secs as f64 / 86400.0 * 100.0).round() / 100.0,
+    })
+}
+
+pub(crate) fn get_latest_opcert(db: &Connection, pool_id: &str) -> Result<OnChainOpcert, Error> {
+    db.query_row(
+        "SELECT block_number, slot_number, opcert_counter, opcert_kes_period FROM chain \
+        WHERE pool_id = ?1 AND orphaned = 0 ORDER BY slot_number DESC LIMIT 1",
+        &[&pool_id],
+        |row| {
+            Ok(OnChainOpcert {
+                block_number: row.get(0)?,
+                slot_number: row.get(1)?,
+                counter: row.get(2)?,
+                kes_period: row.get(3)?,
+            })
+        },
+    )
+}
+
+pub(crate) fn check_opcert(db_path: &Path, shelley_genesis: &Path, opcert_file: &Path, pool_id: &str) {
+    if !db_path.exists() {
+        handle_error("database not found!");
+        return;
+    }
+
+    match check_opcert_file(db_path, shelley_genesis, opcert_file, pool_id) {
+        Ok(check_opcert) => {
+            serde_json::ser::to_writer_pretty(&mut stdout(), &check_opcert).unwrap();
+        }
+        Err(error) => handle_error(error),
+    }
+}
+
+fn check_opcert_file(
+    db_path: &Path,
+    shelley_genesis: &Path,
+    opcert_file: &Path,
+    pool_id: &str,
+) -> Result<CheckOpcert, String> {
+    let shelley = read_shelley_genesis(shelley_genesis).map_err(|error| error.to_string())?;
+    debug!("{:?}", shelley);
+    let opcert = read_opcert(opcert_file)?;
+    debug!("{:?}", opcert);
+
+    // blake2b 224 of the cold vkey is the pool_id
+    let opcert_pool_id = hex::encode(
+        Params::new()
+            .hash_length(28)
+            .to_state()
+            .update(&*opcert.cold_vkey)
+            .finalize()
+            .as_bytes(),
+    );
+    if opcert_pool_id != pool_id {
+        return Err(format!(
+            "opcert was issued by pool {}, not pool {}",
+            opcert_pool_id, pool_id
+        ));
+    }
+
+    let db = Connection::open(db_path).map_err(|error| error.to_string())?;
+    let tip_slot_number = get_tip_slot_number(&db).map_err(|error| error.to_string())?;
+    debug!("tip_slot_number: {}", tip_slot_number);
+    let on_chain_opcert = match get_latest_opcert(&db, pool_id) {
+        Ok(on_chain_opcert) => Some(on_chain_opcert),
+        Err(Error::QueryReturnedNoRows) => None,
+        Err(error) => return Err(error.to_string()),
+    };
+    if let Err(error) = db.close() {
+        return Err(format!("db close error: {}", error.1));
+    }
+
+    let mut errors: Vec<String> = vec![];
+
+    // The counter must be equal to the last on-chain counter or exactly one more. A pool that has not
+    // made a block yet starts from a counter of 0.
+    let on_chain_counter = on_chain_opcert
+        .as_ref()
+        .map_or(0, |on_chain_opcert| on_chain_opcert.counter);
+    let counter_valid = opcert.counter == on_chain_counter || opcert.counter == on_chain_counter + 1;
+    if !counter_valid {
+        errors.push(format!(
+            "opcert counter {} is invalid. It must be {} or {}",
+            opcert.counter,
+            on_chain_counter,
+            on_chain_counter + 1
+        ));
+    }
+
+    // The KES period must have started and the KES key must not have evolved past max_kes_evolutions
+    let current_kes_period = tip_slot_number / shelley.slots_per_kes_period;
+    let kes_period_valid =
+        opcert.kes_period <= current_kes_period && current_kes_period < opcert.kes_period + shelley.max_kes_evolutions;
+    if opcert.kes_period > current_kes_period {
+        errors.push(format!(
+            "opcert KES period {} is after the current KES period {}",
+            opcert.kes_period, current_kes_period
+        ));
+    } else if !kes_period_valid {
+        errors.push(format!(
+            "opcert KES period {} expired at KES period {}",
+            opcert.kes_period,
+            opcert.kes_period + shelley.max_kes_evolutions
+        ));
+    }
+
+    Ok(CheckOpcert {
+        status: if errors.is_empty() {
+            "ok".to_string()
+        } else {
+            "invalid".to_string()
+        },
+        pool_id: pool_id.to_string(),
+        opcert_counter: opcert.counter,
+        opcert_kes_period: opcert.kes_period,
+        on_chain_counter: on_chain_opcert.as_ref().map(|on_chain_opcert| on_chain_opcert.counter),
+        on_chain_kes_period: on_chain_opcert
+            .as_ref()
+            .map(|on_chain_opcert| on_chain_opcert.kes_period),
+        current_kes_period,
+        counter_valid,
+        kes_period_valid,
+        errors,
+    })
+}
+
+fn read_opcert(opcert_path: &Path) -> Result<Opcert, String> {
+    let buf = BufReader::new(File::open(opcert_path).map_err(|error| error.to_string())?);
+    let opcert_file: OpcertFile = serde_json::from_reader(buf).map_err(|error| error.to_string())?;
+    if opcert_file.cert_type != "NodeOperationalCertificate" {
+        return Err("Opcert must be of type: NodeOperationalCertificate".to_string());
+    }
+    parse_opcert(&*hex::decode(opcert_file.cbor_hex).map_err(|error| error.to_string())?)
+}
+
+// Parse the cbor of an operational certificate: [[hot_vkey, counter, kes_period, signature], cold_vkey]
+pub fn parse_opcert(cbor: &[u8]) -> Result<Opcert, String> {
+    let value: Value = de::from_slice(cbor).map_err(|error| error.to_string())?;
+    match value {
+        Value::Array(opcert) => match &opcert[..] {
+            [Value::Array(cert), Value::Bytes(cold_vkey)] => match &cert[..] {
+                [Value::Bytes(hot_vkey), Value::Integer(counter), Value::Integer(kes_period), Value::Bytes(signature)] => {
+                    Ok(Opcert {
+                        hot_vkey: hot_vkey.clone(),
+                        counter: *counter as i64,
+                        kes_period: *kes_period as i64,
+                        signature: signature.clone(),
+                        cold_vkey: cold_vkey.clone(),
+                    })
+                }
+                _ => Err("Invalid opcert cbor!".to_string()),
+            },
+            _ => Err("Invalid opcert cbor!".to_string()),
+        },
+        _ => Err("Invalid opcert cbor!".to_string()),
+    }
+}
+
+/// The bytes the cold key signs in an operational certificate: hot_vkey + counter + kes_period as big-endian u64s
+pub fn opcert_signable(hot_vkey: &[u8], counter: i64, kes_period: i64) -> Vec<u8> {
+    let mut signable = hot_vkey.to_vec();
+    signable.extend_from_slice(&(counter as u64).to_be_bytes());
+    signable.extend_from_slice(&(kes_period as u64).to_be_bytes());
+    signable
+}
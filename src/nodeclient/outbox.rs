@@ -0,0 +1,282 @@
+use std::io::stdout;
+use std::path::Path;
+
+use chrono::Utc;
+use log::{error, info, warn};
+use rusqlite::{named_params, Connection, Error, NO_PARAMS};
+use serde::Serialize;
+
+use crate::nodeclient::leaderlog::handle_error;
+use crate::nodeclient::pooltool::{PooltoolClient, SENDSTATS};
+use crate::nodeclient::sqlite::SqLiteBlockStore;
+
+// Retries back off from one minute, doubling up to a maximum of six hours
+const BACKOFF_BASE_SECS: i64 = 60;
+const BACKOFF_MAX_SECS: i64 = 6 * 60 * 60;
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OutboxEntry {
+    pub id: i64,
+    pub endpoint: String,
+    pub pool_id: String,
+    pub payload: String,
+    pub attempts: i64,
+    pub last_error: String,
+    pub created_at: i64,
+    pub next_attempt_at: i64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct OutboxList {
+    status: String,
+    entries: Vec<OutboxEntry>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct OutboxDrop {
+    status: String,
+    dropped: usize,
+}
+
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OutboxFlush {
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub status: String,
+    pub sent: i64,
+    pub failed: i64,
+}
+
+/// Pooltool payloads that could not be sent, persisted in the cncli db so they can be retried
+pub struct Outbox {
+    db: Connection,
+}
+
+impl Outbox {
+    pub fn open(db_path: &Path) -> Result<Outbox, Error> {
+        // Make sure the outbox table exists before we use it
+        let block_store = SqLiteBlockStore::new(db_path)?;
+        Ok(Outbox { db: block_store.db })
+    }
+
+    /// Save a failed payload. Only the newest tip of a pool is kept, the ones before it are stale. Tips
+    /// are never retried on their backoff schedule, the next tip sendtip sends supersedes them.
+    pub fn enqueue(&self, endpoint: &str, pool_id: &str, payload: &str, last_error: &str) -> Result<(), Error> {
+        let now = Utc::now().timestamp();
+        if endpoint == SENDSTATS {
+            self.discard(endpoint, pool_id)?;
+        }
+        self.db.execute_named(
+            "INSERT INTO outbox (endpoint, pool_id, payload, attempts, last_error, created_at, next_attempt_at) \
+            VALUES (:endpoint, :pool_id, :payload, 1, :last_error, :created_at, :next_attempt_at)",
+            named_params! {
+                ":endpoint": endpoint,
+                ":pool_id": pool_id,
+                ":payload": payload,
+                ":last_error": last_error,
+                ":created_at": now,
+                ":next_attempt_at": now + backoff_secs(1),
+            },
+        )?;
+        Ok(())
+    }
+
+    pub fn list(&self) -> Result<Vec<OutboxEntry>, Error> {
+        self.query_entries("SELECT * FROM outbox ORDER BY id ASC", &[])
+    }
+
+    /// Retry entries whose backoff has expired, or all of them if forced. An endpoint and pool id
+    /// limit the retries to the payloads a single sender is responsible for.
+    pub fn flush(
+        &self,
+        client: &PooltoolClient,
+        endpoint: Option<&str>,
+        pool_id: Option<&str>,
+        force: bool,
+    ) -> Result<OutboxFlush, Error> {
+        let now = Utc::now().timestamp();
+        let entries = self.query_entries(
+            "SELECT * FROM outbox \
+            WHERE (:endpoint IS NULL OR endpoint = :endpoint) \
+            AND (:pool_id IS NULL OR pool_id = :pool_id) \
+            AND (:force OR next_attempt_at <= :now) \
+            ORDER BY id ASC",
+            named_params! {
+                ":endpoint": endpoint,
+                ":pool_id": pool_id,
+                ":force": force,
+                ":now": now,
+            },
+        )?;
+
+        let mut outbox_flush = OutboxFlush::default();
+        for entry in entries.iter() {
+            match client.post_payload(&entry.endpoint, entry.payload.clone()) {
+                Ok(text) => {
+                    info!("Pooltool outbox ({}, {}): json: {}", entry.id, &entry.endpoint, text);
+                    self.db.execute("DELETE FROM outbox WHERE id = ?1", &[&entry.id])?;
+                    outbox_flush.sent += 1;
+                }
+                Err(error) => {
+                    warn!("Pooltool outbox ({}, {}) error: {}", entry.id, &entry.endpoint, error);
+                    let attempts = entry.attempts + 1;
+                    self.db.execute_named(
+                        "UPDATE outbox SET attempts = :attempts, last_error = :last_error, \
+                        next_attempt_at = :next_attempt_at WHERE id = :id",
+                        named_params! {
+                            ":attempts": attempts,
+                            ":last_error": error,
+                            ":next_attempt_at": Utc::now().timestamp() + backoff_secs(attempts),
+                            ":id": entry.id,
+                        },
+                    )?;
+                    outbox_flush.failed += 1;
+                }
+            }
+        }
+        Ok(outbox_flush)
+    }
+
+    /// Remove the waiting payloads of a sender, e.g. a tip that a newer one has superseded
+    pub fn discard(&self, endpoint: &str, pool_id: &str) -> Result<usize, Error> {
+        self.db.execute_named(
+            "DELETE FROM outbox WHERE endpoint = :endpoint AND pool_id = :pool_id",
+            named_params! {
+                ":endpoint": endpoint,
+                ":pool_id": pool_id,
+            },
+        )
+    }
+
+    /// Remove a single entry, or every entry if no id is given
+    pub fn drop_entries(&self, id: &Option<i64>) -> Result<usize, Error> {
+        match id {
+            Some(id) => self.db.execute("DELETE FROM outbox WHERE id = ?1", &[id]),
+            None => self.db.execute("DELETE FROM outbox", NO_PARAMS),
+        }
+    }
+
+    fn query_entries(&self, sql: &str, params: &[(&str, &dyn rusqlite::ToSql)]) -> Result<Vec<OutboxEntry>, Error> {
+        let mut stmt = self.db.prepare(sql)?;
+        let entries = stmt
+            .query_map_named(params, |row| {
+                Ok(OutboxEntry {
+                    id: row.get("id")?,
+                    endpoint: row.get("endpoint")?,
+                    pool_id: row.get("pool_id")?,
+                    payload: row.get("payload")?,
+                    attempts: row.get("attempts")?,
+                    last_error: row.get("last_error")?,
+                    created_at: row.get("created_at")?,
+                    next_attempt_at: row.get("next_attempt_at")?,
+                })
+            })?
+            .collect::<Result<Vec<OutboxEntry>, Error>>();
+        entries
+    }
+}
+
+/// Seconds to wait before the next retry of a payload that has failed this many times
+pub fn backoff_secs(attempts: i64) -> i64 {
+    let exponent = (attempts - 1).clamp(0, 20) as u32;
+    (BACKOFF_BASE_SECS * 2i64.pow(exponent)).min(BACKOFF_MAX_SECS)
+}
+
+/// Save a payload that failed to send so a later run can retry it
+pub(crate) fn save_failed(db_path: &Path, endpoint: &str, pool_id: &str, payload: &str, last_error: &str) {
+    match Outbox::open(db_path) {
+        Ok(outbox) => match outbox.enqueue(endpoint, pool_id, payload, last_error) {
+            Ok(_) => info!("Saved {} payload for {} to the outbox", endpoint, pool_id),
+            Err(error) => error!("Could not save to the outbox: {}", error),
+        },
+        Err(error) => error!("Could not open the outbox: {}", error),
+    }
+}
+
+/// Retry the due payloads of a sender, logging rather than failing so sending can carry on
+pub(crate) fn retry_due(db_path: &Path, client: &PooltoolClient, endpoint: &str, pool_id: &str) {
+    if !db_path.exists() {
+        return;
+    }
+    match Outbox::open(db_path) {
+        Ok(outbox) => match outbox.flush(client, Some(endpoint), Some(pool_id), false) {
+            Ok(outbox_flush) => {
+                if outbox_flush.sent > 0 || outbox_flush.failed > 0 {
+                    info!(
+                        "Pooltool outbox retried for {}: sent: {}, failed: {}",
+                        pool_id, outbox_flush.sent, outbox_flush.failed
+                    );
+                }
+            }
+            Err(error) => error!("Could not flush the outbox: {}", error),
+        },
+        Err(error) => error!("Could not open the outbox: {}", error),
+    }
+}
+
+pub(crate) fn list(db_path: &Path) {
+    if !db_path.exists() {
+        handle_error("database not found!");
+        return;
+    }
+    match Outbox::open(db_path).and_then(|outbox| outbox.list()) {
+        Ok(entries) => {
+            serde_json::ser::to_writer_pretty(
+                &mut stdout(),
+                &OutboxList {
+                    status: "ok".to_string(),
+                    entries,
+                },
+            )
+            .unwrap();
+        }
+        Err(error) => handle_error(error),
+    }
+}
+
+pub(crate) fn flush(db_path: &Path, client: &PooltoolClient) {
+    if !db_path.exists() {
+        handle_error("database not found!");
+        return;
+    }
+    match Outbox::open(db_path).and_then(|outbox| outbox.flush(client, None, None, true)) {
+        Ok(outbox_flush) => {
+            serde_json::ser::to_writer_pretty(
+                &mut stdout(),
+                &OutboxFlush {
+                    status: "ok".to_string(),
+                    ..outbox_flush
+                },
+            )
+            .unwrap();
+        }
+        Err(error) => handle_error(error),
+    }
+}
+
+pub(crate) fn drop_entries(db_path: &Path, id: &Option<i64>, all: bool) {
+    if !db_path.exists() {
+        handle_error("database not found!");
+        return;
+    }
+    if id.is_none() && !all {
+        handle_error("either --id or --all is required!");
+        return;
+    }
+    match Outbox::open(db_path).and_then(|outbox| outbox.drop_entries(id)) {
+        Ok(dropped) => {
+            serde_json::ser::to_writer_pretty(
+                &mut stdout(),
+                &OutboxDrop {
+                    status: "ok".to_string(),
+                    dropped,
+                },
+            )
+            .unwrap();
+        }
+        Err(error) => handle_error(error),
+    }
+}
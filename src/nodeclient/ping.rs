@@ -1,83 +1,871 @@
-use std::io::Write;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{BufReader, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
-use cardano_ouroboros_network::mux;
+use cardano_ouroboros_network::{mux, Agency, Protocol};
+use chrono::Utc;
 use futures::executor::block_on;
 use log::debug;
-use serde::Serialize;
+use rayon::prelude::*;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_cbor::Value;
+
+use crate::nodeclient::leaderlog::{slot_to_naivedatetime, ByronGenesis, ShelleyGenesis};
+use crate::nodeclient::resolve;
+
+const HANDSHAKE_PROTOCOL_ID: u16 = 0;
+const KEEP_ALIVE_PROTOCOL_ID: u16 = 8;
+const CHAIN_SYNC_PROTOCOL_ID: u16 = 2;
+const PEER_SHARING_PROTOCOL_ID: u16 = 10;
+// Node-to-node versions that can negotiate peer sharing, older ones are proposed as a fallback
+const PEER_SHARING_VERSIONS: std::ops::RangeInclusive<i128> = 11..=14;
+const FALLBACK_VERSIONS: std::ops::RangeInclusive<i128> = 7..=10;
 
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
-struct PingSuccess {
+pub(crate) struct PingSuccess {
     status: String,
-    host: String,
-    port: u16,
-    connect_duration_ms: u128,
-    duration_ms: u128,
+    pub(crate) host: String,
+    pub(crate) port: u16,
+    pub(crate) connect_duration_ms: u128,
+    pub(crate) duration_ms: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) network_version: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) diffusion_mode: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    keep_alive: Option<KeepAliveStats>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tip: Option<PeerTip>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    address: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    addresses: Vec<AddressPing>,
+    /// Whether the handshake negotiated peer sharing
+    #[serde(skip)]
+    pub(crate) peer_sharing: bool,
+    /// Relays the peer shared with us
+    #[serde(skip)]
+    pub(crate) shared_peers: Vec<SocketAddr>,
 }
 
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
-struct PingError {
+pub(crate) struct PingError {
     status: String,
-    host: String,
+    pub(crate) host: String,
+    pub(crate) port: u16,
+    pub(crate) error_message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    address: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    addresses: Vec<AddressPing>,
+}
+
+/// The outcome for one of the addresses of a host that resolves to several
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AddressPing {
+    address: String,
+    status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    connect_duration_ms: Option<u128>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration_ms: Option<u128>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error_message: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TopologyPing {
+    results: Vec<PingResult>,
+    summary: TopologySummary,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum PingResult {
+    Ok(PingSuccess),
+    Error(PingError),
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TopologySummary {
+    total: usize,
+    ok: usize,
+    unreachable: Vec<String>,
+    wrong_magic: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Topology {
+    producers: Vec<TopologyProducer>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TopologyProducer {
+    addr: String,
     port: u16,
-    error_message: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct KeepAliveStats {
+    count: usize,
+    min_ms: f64,
+    avg_ms: f64,
+    max_ms: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PeerTip {
+    slot_number: i64,
+    block_number: i64,
+    hash: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seconds_behind: Option<i64>,
+}
+
+/// Optional diagnostics to run after the handshake
+#[derive(Default)]
+pub(crate) struct PingOptions {
+    /// Number of keep-alive round trips to time
+    pub(crate) keep_alive: u32,
+    /// Ask the peer for its tip
+    pub(crate) tip: bool,
+    /// Needed to tell how far the peer's tip is behind the wall clock
+    pub(crate) genesis: Option<(ByronGenesis, ShelleyGenesis)>,
+    /// Number of peers to ask for with the peer-sharing protocol
+    pub(crate) share_peers: u8,
 }
 
 pub fn ping<W: Write>(out: &mut W, host: &str, port: u16, network_magic: u32) {
-    block_on(async {
-        let start = Instant::now();
-        match mux::connection::connect(host, port).await {
-            Ok(channel) => {
-                let connect_duration = start.elapsed();
-                match channel.handshake(network_magic).await {
-                    Ok(data) => {
-                        let total_duration = start.elapsed();
-                        debug!("{}", data);
-                        ping_json_success(out, connect_duration, total_duration, host, port);
-                    }
-                    Err(error) => {
-                        ping_json_error(out, error, host, port);
-                    }
-                }
-            }
-            Err(error) => {
-                ping_json_error(out, format!("{}", error), host, port);
-            }
-        }
-    });
+    ping_with_options(out, host, port, network_magic, &PingOptions::default());
 }
 
-fn ping_json_success<W: Write>(
+pub(crate) fn ping_with_options<W: Write>(
     out: &mut W,
-    connect_duration: Duration,
-    total_duration: Duration,
     host: &str,
     port: u16,
+    network_magic: u32,
+    options: &PingOptions,
 ) {
-    serde_json::ser::to_writer_pretty(
-        out,
-        &PingSuccess {
-            status: "ok".to_string(),
-            host: host.to_string(),
-            port,
-            connect_duration_ms: connect_duration.as_millis(),
-            duration_ms: total_duration.as_millis(),
-        },
-    )
-    .unwrap();
-}
-
-fn ping_json_error<W: Write>(out: &mut W, message: String, host: &str, port: u16) {
-    serde_json::ser::to_writer_pretty(
-        out,
-        &PingError {
-            status: "error".to_string(),
-            host: host.to_string(),
-            port,
-            error_message: message,
-        },
-    )
-    .unwrap();
+    match block_on(ping_peer(host, port, network_magic, options)) {
+        Ok(ping_success) => ping_json_success(out, &ping_success),
+        Err(ping_error) => ping_json_error(out, &ping_error),
+    }
+}
+
+async fn ping_peer(host: &str, port: u16, network_magic: u32, options: &PingOptions) -> Result<PingSuccess, PingError> {
+    let addresses = match resolve::resolve(host, port) {
+        Ok(addresses) => addresses,
+        Err(error) => return Err(ping_error(error, host, port)),
+    };
+    if addresses.len() == 1 {
+        return ping_address(host, addresses[0], network_magic, options).await;
+    }
+
+    // Report on every address, with the first one that answered as the overall result
+    let mut results = vec![];
+    for address in addresses.into_iter() {
+        let mut result = ping_address(host, address, network_magic, options).await;
+        match &mut result {
+            Ok(ping_success) => ping_success.address = Some(address.to_string()),
+            Err(ping_error) => ping_error.address = Some(address.to_string()),
+        }
+        results.push(result);
+    }
+    let addresses: Vec<AddressPing> = results
+        .iter()
+        .map(|result| match result {
+            Ok(ping_success) => AddressPing {
+                address: ping_success.address.clone().unwrap_or_default(),
+                status: ping_success.status.clone(),
+                connect_duration_ms: Some(ping_success.connect_duration_ms),
+                duration_ms: Some(ping_success.duration_ms),
+                error_message: None,
+            },
+            Err(ping_error) => AddressPing {
+                address: ping_error.address.clone().unwrap_or_default(),
+                status: ping_error.status.clone(),
+                connect_duration_ms: None,
+                duration_ms: None,
+                error_message: Some(ping_error.error_message.clone()),
+            },
+        })
+        .collect();
+    let best = results.iter().position(|result| result.is_ok()).unwrap_or(0);
+    match results.swap_remove(best) {
+        Ok(ping_success) => Ok(PingSuccess {
+            addresses,
+            ..ping_success
+        }),
+        Err(ping_error) => Err(PingError {
+            addresses,
+            ..ping_error
+        }),
+    }
+}
+
+async fn ping_address(
+    host: &str,
+    address: SocketAddr,
+    network_magic: u32,
+    options: &PingOptions,
+) -> Result<PingSuccess, PingError> {
+    let port = address.port();
+    let start = Instant::now();
+    let channel = match mux::connection::connect(&address.ip().to_string(), port).await {
+        Ok(channel) => channel,
+        Err(error) => return Err(ping_error(format!("{}", error), host, port)),
+    };
+    let connect_duration = start.elapsed();
+    // Peer sharing has to be agreed on in the handshake, which the default one can't do
+    let peer_sharing = Arc::new(Mutex::new(false));
+    let handshake = if options.share_peers > 0 {
+        channel
+            .execute(PeerSharingHandshake::new(network_magic, peer_sharing.clone()))
+            .await
+    } else {
+        channel.handshake(network_magic).await
+    };
+    let data = match handshake {
+        Ok(data) => data,
+        Err(error) => return Err(ping_error(error, host, port)),
+    };
+    let peer_sharing = *peer_sharing.lock().unwrap();
+    let total_duration = start.elapsed();
+    debug!("{}", data);
+    let (network_version, diffusion_mode) = parse_handshake_data(&data);
+    let mut ping_success = PingSuccess {
+        status: "ok".to_string(),
+        host: host.to_string(),
+        port,
+        connect_duration_ms: connect_duration.as_millis(),
+        duration_ms: total_duration.as_millis(),
+        network_version,
+        diffusion_mode,
+        keep_alive: None,
+        tip: None,
+        address: None,
+        addresses: vec![],
+        peer_sharing,
+        shared_peers: vec![],
+    };
+
+    if options.keep_alive > 0 {
+        let round_trips = Arc::new(Mutex::new(vec![]));
+        if let Err(error) = channel
+            .execute(KeepAliveProtocol::new(options.keep_alive, round_trips.clone()))
+            .await
+        {
+            return Err(ping_error(format!("keep-alive error: {}", error), host, port));
+        }
+        ping_success.keep_alive = keep_alive_stats(&round_trips.lock().unwrap());
+    }
+
+    if options.tip {
+        let tip = Arc::new(Mutex::new(None));
+        if let Err(error) = channel.execute(TipProtocol::new(tip.clone())).await {
+            return Err(ping_error(format!("chain-sync error: {}", error), host, port));
+        }
+        ping_success.tip = tip.lock().unwrap().clone().map(|tip: PeerTip| {
+            let seconds_behind = options.genesis.as_ref().map(|(byron, shelley)| {
+                Utc::now().timestamp() - slot_to_naivedatetime(byron, shelley, tip.slot_number).timestamp()
+            });
+            PeerTip { seconds_behind, ..tip }
+        });
+    }
+
+    // A peer that didn't agree to peer sharing closes the connection on a request
+    if options.share_peers > 0 && peer_sharing {
+        let shared_peers = Arc::new(Mutex::new(vec![]));
+        // A failed request doesn't make the peer any less reachable
+        match channel
+            .execute(PeerSharingProtocol::new(options.share_peers, shared_peers.clone()))
+            .await
+        {
+            Ok(_) => ping_success.shared_peers = shared_peers.lock().unwrap().clone(),
+            Err(error) => debug!("{}: peer-sharing error: {}", address, error),
+        }
+    }
+
+    Ok(ping_success)
+}
+
+/// Ping every producer in a topology file, at most `concurrency` at a time
+pub(crate) fn ping_topology<W: Write>(
+    out: &mut W,
+    topology: &Path,
+    network_magic: u32,
+    concurrency: usize,
+    options: &PingOptions,
+) -> Result<(), String> {
+    let peers = read_topology(topology)?;
+    let mut results = ping_peers(&peers, network_magic, concurrency, options)?;
+
+    // Fastest first, failures last
+    results.sort_by_key(|result| match result {
+        Ok(ping_success) => (
+            0,
+            ping_success.duration_ms,
+            ping_success.host.clone(),
+            ping_success.port,
+        ),
+        Err(ping_error) => (1, 0, ping_error.host.clone(), ping_error.port),
+    });
+
+    let mut summary = TopologySummary {
+        total: results.len(),
+        ok: 0,
+        unreachable: vec![],
+        wrong_magic: vec![],
+    };
+    for result in results.iter() {
+        match result {
+            Ok(_) => summary.ok += 1,
+            Err(ping_error) if is_wrong_magic(&ping_error.error_message) => summary
+                .wrong_magic
+                .push(format!("{}:{}", ping_error.host, ping_error.port)),
+            Err(ping_error) => summary
+                .unreachable
+                .push(format!("{}:{}", ping_error.host, ping_error.port)),
+        }
+    }
+
+    let results = results
+        .into_iter()
+        .map(|result| match result {
+            Ok(ping_success) => PingResult::Ok(ping_success),
+            Err(ping_error) => PingResult::Error(ping_error),
+        })
+        .collect();
+    serde_json::ser::to_writer_pretty(out, &TopologyPing { results, summary }).map_err(|error| error.to_string())
+}
+
+/// Ping the peers, at most `concurrency` at a time. The network crate does blocking io, so every ping runs
+/// on a thread of its own pool rather than sharing one executor. Results are in the order of the peers
+#[allow(clippy::result_large_err)]
+pub(crate) fn ping_peers(
+    peers: &[(String, u16)],
+    network_magic: u32,
+    concurrency: usize,
+    options: &PingOptions,
+) -> Result<Vec<Result<PingSuccess, PingError>>, String> {
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(concurrency.max(1))
+        .build()
+        .map_err(|error| error.to_string())?;
+    Ok(pool.install(|| {
+        peers
+            .par_iter()
+            .map(|(host, port)| block_on(ping_peer(host, *port, network_magic, options)))
+            .collect()
+    }))
+}
+
+/// Read the (address, port) of each producer in a cardano-node topology.json
+pub fn read_topology(topology: &Path) -> Result<Vec<(String, u16)>, String> {
+    let file = File::open(topology).map_err(|error| format!("{}: {}", topology.display(), error))?;
+    let topology: Topology =
+        serde_json::from_reader(BufReader::new(file)).map_err(|error| format!("{}: {}", topology.display(), error))?;
+    Ok(topology
+        .producers
+        .into_iter()
+        .map(|producer| (producer.addr, producer.port))
+        .collect())
+}
+
+// A peer on another network refuses our version data
+fn is_wrong_magic(error_message: &str) -> bool {
+    error_message.contains("version data mismatch") || error_message.contains("Refuse")
+}
+
+/// Pull the negotiated node-to-node version and diffusion mode out of the handshake result,
+/// e.g. [Integer(1), Integer(7), Array([Integer(764824073), Bool(false)])]
+pub fn parse_handshake_data(data: &str) -> (Option<u64>, Option<String>) {
+    let integers: Vec<u64> = Regex::new("Integer\\((\\d+)\\)")
+        .unwrap()
+        .captures_iter(data)
+        .filter_map(|cap| cap[1].parse().ok())
+        .collect();
+    // The first integer is the accept message id
+    let network_version = integers.get(1).copied();
+    // Versions before 4 have no diffusion mode and are always initiator and responder
+    let diffusion_mode = network_version.map(|_| match Regex::new("Bool\\((true|false)\\)").unwrap().captures(data) {
+        Some(cap) if &cap[1] == "true" => "InitiatorOnly".to_string(),
+        _ => "InitiatorAndResponder".to_string(),
+    });
+    (network_version, diffusion_mode)
+}
+
+fn keep_alive_stats(round_trips: &[Duration]) -> Option<KeepAliveStats> {
+    if round_trips.is_empty() {
+        return None;
+    }
+    let millis: Vec<f64> = round_trips.iter().map(|rtt| rtt.as_micros() as f64 / 1000.0).collect();
+    Some(KeepAliveStats {
+        count: millis.len(),
+        min_ms: millis.iter().cloned().fold(f64::MAX, f64::min),
+        avg_ms: ((millis.iter().sum::<f64>() / millis.len() as f64) * 1000.0).round() / 1000.0,
+        max_ms: millis.iter().cloned().fold(f64::MIN, f64::max),
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum KeepAliveState {
+    Client,
+    Server,
+    Done,
+}
+
+/// Times MsgKeepAlive/MsgKeepAliveResponse round trips
+struct KeepAliveProtocol {
+    remaining: u32,
+    state: KeepAliveState,
+    cookie: u16,
+    sent_at: Instant,
+    round_trips: Arc<Mutex<Vec<Duration>>>,
+    result: Option<Result<String, String>>,
+}
+
+impl KeepAliveProtocol {
+    fn new(count: u32, round_trips: Arc<Mutex<Vec<Duration>>>) -> Self {
+        KeepAliveProtocol {
+            remaining: count,
+            state: KeepAliveState::Client,
+            cookie: 0,
+            sent_at: Instant::now(),
+            round_trips,
+            result: None,
+        }
+    }
+}
+
+impl Protocol for KeepAliveProtocol {
+    fn protocol_id(&self) -> u16 {
+        KEEP_ALIVE_PROTOCOL_ID
+    }
+
+    fn result(&self) -> Result<String, String> {
+        self.result
+            .clone()
+            .unwrap_or_else(|| Err("keep-alive incomplete".to_string()))
+    }
+
+    fn role(&self) -> Agency {
+        Agency::Client
+    }
+
+    fn agency(&self) -> Agency {
+        match self.state {
+            KeepAliveState::Client => Agency::Client,
+            KeepAliveState::Server => Agency::Server,
+            KeepAliveState::Done => Agency::None,
+        }
+    }
+
+    fn state(&self) -> String {
+        format!("{:?}", self.state)
+    }
+
+    fn send_data(&mut self) -> Option<Vec<u8>> {
+        if self.remaining == 0 {
+            // MsgDone
+            self.state = KeepAliveState::Done;
+            self.result = Some(Ok("done".to_string()));
+            return Some(serde_cbor::to_vec(&(2u8,)).unwrap());
+        }
+        // MsgKeepAlive
+        self.cookie = self.cookie.wrapping_add(1);
+        self.state = KeepAliveState::Server;
+        self.sent_at = Instant::now();
+        Some(serde_cbor::to_vec(&(0u8, self.cookie)).unwrap())
+    }
+
+    fn receive_data(&mut self, data: Vec<u8>) {
+        let round_trip = self.sent_at.elapsed();
+        match serde_cbor::from_slice::<Value>(&data) {
+            // MsgKeepAliveResponse
+            Ok(Value::Array(message)) if message.first() == Some(&Value::Integer(1)) => {
+                if message.get(1) == Some(&Value::Integer(self.cookie as i128)) {
+                    self.round_trips.lock().unwrap().push(round_trip);
+                }
+                self.remaining -= 1;
+                self.state = KeepAliveState::Client;
+            }
+            _ => {
+                self.result = Some(Err(format!("unexpected keep-alive message: {}", hex::encode(&data))));
+                self.state = KeepAliveState::Done;
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TipState {
+    Idle,
+    Intersect,
+    Done,
+}
+
+/// Asks for the peer's tip with a chain-sync MsgFindIntersect without any points
+struct TipProtocol {
+    state: TipState,
+    tip: Arc<Mutex<Option<PeerTip>>>,
+    result: Option<Result<String, String>>,
+}
+
+impl TipProtocol {
+    fn new(tip: Arc<Mutex<Option<PeerTip>>>) -> Self {
+        TipProtocol {
+            state: TipState::Idle,
+            tip,
+            result: None,
+        }
+    }
+}
+
+impl Protocol for TipProtocol {
+    fn protocol_id(&self) -> u16 {
+        CHAIN_SYNC_PROTOCOL_ID
+    }
+
+    fn result(&self) -> Result<String, String> {
+        self.result
+            .clone()
+            .unwrap_or_else(|| Err("chain-sync incomplete".to_string()))
+    }
+
+    fn role(&self) -> Agency {
+        Agency::Client
+    }
+
+    fn agency(&self) -> Agency {
+        match self.state {
+            TipState::Idle => Agency::Client,
+            TipState::Intersect => Agency::Server,
+            TipState::Done => Agency::None,
+        }
+    }
+
+    fn state(&self) -> String {
+        format!("{:?}", self.state)
+    }
+
+    fn send_data(&mut self) -> Option<Vec<u8>> {
+        if self.result.is_some() {
+            // MsgDone
+            self.state = TipState::Done;
+            return Some(serde_cbor::to_vec(&(7u8,)).unwrap());
+        }
+        // MsgFindIntersect
+        self.state = TipState::Intersect;
+        Some(serde_cbor::to_vec(&(4u8, Vec::<u8>::new())).unwrap())
+    }
+
+    fn receive_data(&mut self, data: Vec<u8>) {
+        // MsgIntersectFound [5, point, tip] or MsgIntersectNotFound [6, tip]
+        let tip = match serde_cbor::from_slice::<Value>(&data) {
+            Ok(Value::Array(message)) => match message.first() {
+                Some(Value::Integer(5)) => message.get(2).and_then(parse_tip),
+                Some(Value::Integer(6)) => message.get(1).and_then(parse_tip),
+                _ => None,
+            },
+            _ => None,
+        };
+        self.result = match tip {
+            Some(tip) => {
+                *self.tip.lock().unwrap() = Some(tip);
+                Some(Ok("tip".to_string()))
+            }
+            None => Some(Err(format!("unexpected chain-sync message: {}", hex::encode(&data)))),
+        };
+        self.state = TipState::Idle;
+    }
+}
+
+/// The version table of a handshake that asks for peer sharing. Versions 11 and up carry
+/// [networkMagic, initiatorOnlyDiffusionMode, peerSharing, query], older ones only the first two.
+pub fn peer_sharing_versions(network_magic: u32) -> Value {
+    let mut versions: BTreeMap<Value, Value> = BTreeMap::new();
+    for version in FALLBACK_VERSIONS {
+        versions.insert(
+            Value::Integer(version),
+            Value::Array(vec![Value::Integer(network_magic as i128), Value::Bool(false)]),
+        );
+    }
+    for version in PEER_SHARING_VERSIONS {
+        versions.insert(
+            Value::Integer(version),
+            Value::Array(vec![
+                Value::Integer(network_magic as i128),
+                Value::Bool(false),
+                Value::Integer(1),
+                Value::Bool(false),
+            ]),
+        );
+    }
+    Value::Map(versions)
+}
+
+/// Whether a MsgAcceptVersion [1, version, versionData] agreed on peer sharing
+pub fn accepts_peer_sharing(message: &[Value]) -> bool {
+    match (message.first(), message.get(1), message.get(2)) {
+        (Some(Value::Integer(1)), Some(Value::Integer(version)), Some(Value::Array(version_data))) => {
+            PEER_SHARING_VERSIONS.contains(version)
+                && matches!(version_data.get(2), Some(Value::Integer(peer_sharing)) if *peer_sharing != 0)
+        }
+        _ => false,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum HandshakeState {
+    Propose,
+    Confirm,
+    Done,
+}
+
+/// A node-to-node handshake that proposes peer sharing on the versions that support it
+struct PeerSharingHandshake {
+    network_magic: u32,
+    state: HandshakeState,
+    peer_sharing: Arc<Mutex<bool>>,
+    result: Option<Result<String, String>>,
+}
+
+impl PeerSharingHandshake {
+    fn new(network_magic: u32, peer_sharing: Arc<Mutex<bool>>) -> Self {
+        PeerSharingHandshake {
+            network_magic,
+            state: HandshakeState::Propose,
+            peer_sharing,
+            result: None,
+        }
+    }
+}
+
+impl Protocol for PeerSharingHandshake {
+    fn protocol_id(&self) -> u16 {
+        HANDSHAKE_PROTOCOL_ID
+    }
+
+    fn result(&self) -> Result<String, String> {
+        self.result
+            .clone()
+            .unwrap_or_else(|| Err("handshake incomplete".to_string()))
+    }
+
+    fn role(&self) -> Agency {
+        Agency::Client
+    }
+
+    fn agency(&self) -> Agency {
+        match self.state {
+            HandshakeState::Propose => Agency::Client,
+            HandshakeState::Confirm => Agency::Server,
+            HandshakeState::Done => Agency::None,
+        }
+    }
+
+    fn state(&self) -> String {
+        format!("{:?}", self.state)
+    }
+
+    fn send_data(&mut self) -> Option<Vec<u8>> {
+        // MsgProposeVersions
+        self.state = HandshakeState::Confirm;
+        let message = Value::Array(vec![Value::Integer(0), peer_sharing_versions(self.network_magic)]);
+        Some(serde_cbor::to_vec(&message).unwrap())
+    }
+
+    fn receive_data(&mut self, data: Vec<u8>) {
+        // MsgAcceptVersion [1, version, versionData] or MsgRefuse [2, reason]
+        self.result = match serde_cbor::from_slice::<Value>(&data) {
+            Ok(Value::Array(message)) => match message.first() {
+                Some(Value::Integer(1)) => {
+                    *self.peer_sharing.lock().unwrap() = accepts_peer_sharing(&message);
+                    // Same format as the default handshake's result, for parse_handshake_data
+                    Some(Ok(format!("{:?}", message)))
+                }
+                Some(Value::Integer(2)) => Some(Err(format!("Refuse: {:?}", message.get(1)))),
+                _ => Some(Err(format!("unexpected handshake message: {}", hex::encode(&data)))),
+            },
+            _ => Some(Err(format!("unexpected handshake message: {}", hex::encode(&data)))),
+        };
+        self.state = HandshakeState::Done;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PeerSharingState {
+    Idle,
+    Busy,
+    Done,
+}
+
+/// Asks the peer for some of the relays it knows about
+struct PeerSharingProtocol {
+    amount: u8,
+    state: PeerSharingState,
+    shared_peers: Arc<Mutex<Vec<SocketAddr>>>,
+    result: Option<Result<String, String>>,
+}
+
+impl PeerSharingProtocol {
+    fn new(amount: u8, shared_peers: Arc<Mutex<Vec<SocketAddr>>>) -> Self {
+        PeerSharingProtocol {
+            amount,
+            state: PeerSharingState::Idle,
+            shared_peers,
+            result: None,
+        }
+    }
+}
+
+impl Protocol for PeerSharingProtocol {
+    fn protocol_id(&self) -> u16 {
+        PEER_SHARING_PROTOCOL_ID
+    }
+
+    fn result(&self) -> Result<String, String> {
+        self.result
+            .clone()
+            .unwrap_or_else(|| Err("peer-sharing incomplete".to_string()))
+    }
+
+    fn role(&self) -> Agency {
+        Agency::Client
+    }
+
+    fn agency(&self) -> Agency {
+        match self.state {
+            PeerSharingState::Idle => Agency::Client,
+            PeerSharingState::Busy => Agency::Server,
+            PeerSharingState::Done => Agency::None,
+        }
+    }
+
+    fn state(&self) -> String {
+        format!("{:?}", self.state)
+    }
+
+    fn send_data(&mut self) -> Option<Vec<u8>> {
+        if self.result.is_some() {
+            // MsgDone
+            self.state = PeerSharingState::Done;
+            return Some(serde_cbor::to_vec(&(2u8,)).unwrap());
+        }
+        // MsgShareRequest
+        self.state = PeerSharingState::Busy;
+        Some(serde_cbor::to_vec(&(0u8, self.amount)).unwrap())
+    }
+
+    fn receive_data(&mut self, data: Vec<u8>) {
+        // MsgSharePeers [1, [peerAddress]]
+        self.result = match serde_cbor::from_slice::<Value>(&data) {
+            Ok(Value::Array(message)) if message.first() == Some(&Value::Integer(1)) => {
+                let shared_peers = message.get(1).map(parse_peer_addresses).unwrap_or_default();
+                let count = shared_peers.len();
+                *self.shared_peers.lock().unwrap() = shared_peers;
+                Some(Ok(format!("{} peers", count)))
+            }
+            _ => Some(Err(format!("unexpected peer-sharing message: {}", hex::encode(&data)))),
+        };
+        self.state = PeerSharingState::Idle;
+    }
+}
+
+/// The addresses in a MsgSharePeers, each [0, ipv4, port] or [1, ipv6 as 4 words, port]
+pub fn parse_peer_addresses(value: &Value) -> Vec<SocketAddr> {
+    let word = |value: Option<&Value>| match value {
+        Some(Value::Integer(word)) => u32::try_from(*word).ok(),
+        _ => None,
+    };
+    match value {
+        Value::Array(addresses) => addresses
+            .iter()
+            .filter_map(|address| match address {
+                Value::Array(fields) => match fields.first() {
+                    Some(Value::Integer(0)) => {
+                        let ip = Ipv4Addr::from(word(fields.get(1))?);
+                        let port = u16::try_from(word(fields.get(2))?).ok()?;
+                        Some(SocketAddr::new(IpAddr::V4(ip), port))
+                    }
+                    Some(Value::Integer(1)) => {
+                        let mut octets = [0u8; 16];
+                        for i in 0..4 {
+                            octets[i * 4..i * 4 + 4].copy_from_slice(&word(fields.get(i + 1))?.to_be_bytes());
+                        }
+                        let port = u16::try_from(word(fields.get(5))?).ok()?;
+                        Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port))
+                    }
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect(),
+        _ => vec![],
+    }
+}
+
+// tip = [[slot, hash], block number], or [[], 0] at origin
+fn parse_tip(value: &Value) -> Option<PeerTip> {
+    match value {
+        Value::Array(tip) => {
+            let block_number = match tip.get(1) {
+                Some(Value::Integer(block_number)) => *block_number as i64,
+                _ => return None,
+            };
+            match tip.first() {
+                Some(Value::Array(point)) if point.is_empty() => Some(PeerTip {
+                    slot_number: 0,
+                    block_number,
+                    hash: String::new(),
+                    seconds_behind: None,
+                }),
+                Some(Value::Array(point)) => match (point.first(), point.get(1)) {
+                    (Some(Value::Integer(slot_number)), Some(Value::Bytes(hash))) => Some(PeerTip {
+                        slot_number: *slot_number as i64,
+                        block_number,
+                        hash: hex::encode(hash),
+                        seconds_behind: None,
+                    }),
+                    _ => None,
+                },
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+fn ping_error(message: String, host: &str, port: u16) -> PingError {
+    PingError {
+        status: "error".to_string(),
+        host: host.to_string(),
+        port,
+        error_message: message,
+        address: None,
+        addresses: vec![],
+    }
+}
+
+fn ping_json_success<W: Write>(out: &mut W, ping_success: &PingSuccess) {
+    serde_json::ser::to_writer_pretty(out, ping_success).unwrap();
+}
+
+fn ping_json_error<W: Write>(out: &mut W, ping_error: &PingError) {
+    serde_json::ser::to_writer_pretty(out, ping_error).unwrap();
 }
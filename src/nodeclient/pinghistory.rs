@@ -0,0 +1,242 @@
+use std::collections::BTreeMap;
+use std::io::stdout;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use log::{error, info, warn};
+use rusqlite::{named_params, Connection, Error};
+use serde::Serialize;
+
+use crate::nodeclient::leaderlog::handle_error;
+use crate::nodeclient::ping::{self, PingOptions};
+use crate::nodeclient::propagation::{DelayStats, DelayWindow};
+use crate::nodeclient::sqlite::SqLiteBlockStore;
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PingReport {
+    status: String,
+    window_secs: u64,
+    peers: Vec<PeerUptime>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PeerUptime {
+    pub host: String,
+    pub port: u16,
+    pub pings: usize,
+    pub ok: usize,
+    pub uptime_pct: f64,
+    /// How many times the peer went from reachable to unreachable or back
+    pub flaps: usize,
+    pub last_status: String,
+    /// Handshake durations of the successful pings
+    pub latency: DelayStats,
+}
+
+#[derive(Default)]
+struct PeerTally {
+    pings: usize,
+    flaps: usize,
+    last_status: String,
+    durations: Vec<i64>,
+}
+
+/// Ping results of watched peers, persisted in the cncli db
+pub struct PingHistory {
+    db: Connection,
+}
+
+impl PingHistory {
+    pub fn open(db_path: &Path) -> Result<PingHistory, Error> {
+        // Make sure the ping_history table exists before we use it
+        let block_store = SqLiteBlockStore::new(db_path)?;
+        Ok(PingHistory { db: block_store.db })
+    }
+
+    pub fn record(
+        &self,
+        host: &str,
+        port: u16,
+        duration_ms: Result<(i64, i64), &str>,
+        created_at: i64,
+    ) -> Result<(), Error> {
+        let (status, connect_duration_ms, duration_ms, error_message) = match duration_ms {
+            Ok((connect_duration_ms, duration_ms)) => ("ok", Some(connect_duration_ms), Some(duration_ms), None),
+            Err(error_message) => ("error", None, None, Some(error_message)),
+        };
+        self.db.execute_named(
+            "INSERT INTO ping_history (host, port, status, connect_duration_ms, duration_ms, error_message, created_at) \
+            VALUES (:host, :port, :status, :connect_duration_ms, :duration_ms, :error_message, :created_at)",
+            named_params! {
+                ":host": host,
+                ":port": port,
+                ":status": status,
+                ":connect_duration_ms": connect_duration_ms,
+                ":duration_ms": duration_ms,
+                ":error_message": error_message,
+                ":created_at": created_at,
+            },
+        )?;
+        Ok(())
+    }
+
+    /// Uptime and latency of each peer pinged since the given unix timestamp
+    pub fn uptime(&self, since: i64) -> Result<Vec<PeerUptime>, Error> {
+        let mut stmt = self.db.prepare(
+            "SELECT host, port, status, duration_ms FROM ping_history \
+            WHERE created_at >= :since ORDER BY host, port, created_at, id",
+        )?;
+        let rows = stmt
+            .query_map_named(named_params! { ":since": since }, |row| {
+                Ok((
+                    row.get::<_, String>("host")?,
+                    row.get::<_, u16>("port")?,
+                    row.get::<_, String>("status")?,
+                    row.get::<_, Option<i64>>("duration_ms")?,
+                ))
+            })?
+            .collect::<Result<Vec<(String, u16, String, Option<i64>)>, Error>>()?;
+
+        let mut peers: BTreeMap<(String, u16), PeerTally> = BTreeMap::new();
+        for (host, port, status, duration_ms) in rows.into_iter() {
+            let tally = peers.entry((host, port)).or_default();
+            if !tally.last_status.is_empty() && tally.last_status != status {
+                tally.flaps += 1;
+            }
+            tally.pings += 1;
+            if let Some(duration_ms) = duration_ms {
+                tally.durations.push(duration_ms);
+            }
+            tally.last_status = status;
+        }
+
+        Ok(peers
+            .into_iter()
+            .map(|((host, port), tally)| {
+                let ok = tally.durations.len();
+                let mut window = DelayWindow::new(ok);
+                for duration_ms in tally.durations.into_iter() {
+                    window.push(duration_ms);
+                }
+                PeerUptime {
+                    host,
+                    port,
+                    pings: tally.pings,
+                    ok,
+                    uptime_pct: (ok as f64 * 10000.0 / tally.pings as f64).round() / 100.0,
+                    flaps: tally.flaps,
+                    last_status: tally.last_status,
+                    latency: window.stats(),
+                }
+            })
+            .collect())
+    }
+}
+
+/// Parse a duration such as 30, 30s, 5m, 24h or 7d into seconds
+pub fn parse_duration_secs(duration: &str) -> Result<u64, String> {
+    let duration = duration.trim();
+    let (value, multiplier) = match duration.chars().last() {
+        Some('s') => (&duration[..duration.len() - 1], 1),
+        Some('m') => (&duration[..duration.len() - 1], 60),
+        Some('h') => (&duration[..duration.len() - 1], 60 * 60),
+        Some('d') => (&duration[..duration.len() - 1], 24 * 60 * 60),
+        _ => (duration, 1),
+    };
+    match value.parse::<u64>() {
+        Ok(value) if value > 0 => Ok(value * multiplier),
+        _ => Err(format!("invalid duration: {}", duration)),
+    }
+}
+
+/// Ping the peers every interval forever, saving each result to the ping history
+pub(crate) fn watch(
+    db_path: &Path,
+    peers: &[(String, u16)],
+    network_magic: u32,
+    concurrency: usize,
+    interval_secs: u64,
+    options: &PingOptions,
+) {
+    let ping_history = match PingHistory::open(db_path) {
+        Ok(ping_history) => ping_history,
+        Err(error) => {
+            handle_error(format!("db error: {}", error));
+            return;
+        }
+    };
+    loop {
+        let start = Instant::now();
+        let created_at = Utc::now().timestamp();
+        let results = match ping::ping_peers(peers, network_magic, concurrency, options) {
+            Ok(results) => results,
+            Err(error) => {
+                handle_error(error);
+                return;
+            }
+        };
+        for result in results.into_iter() {
+            let recorded = match result {
+                Ok(ping_success) => {
+                    info!(
+                        "{}:{} ok: {}ms",
+                        ping_success.host, ping_success.port, ping_success.duration_ms
+                    );
+                    ping_history.record(
+                        &ping_success.host,
+                        ping_success.port,
+                        Ok((ping_success.connect_duration_ms as i64, ping_success.duration_ms as i64)),
+                        created_at,
+                    )
+                }
+                Err(ping_error) => {
+                    warn!(
+                        "{}:{} error: {}",
+                        ping_error.host, ping_error.port, ping_error.error_message
+                    );
+                    ping_history.record(
+                        &ping_error.host,
+                        ping_error.port,
+                        Err(&ping_error.error_message),
+                        created_at,
+                    )
+                }
+            };
+            if let Err(error) = recorded {
+                error!("Could not save ping history: {}", error);
+            }
+        }
+
+        let interval = Duration::from_secs(interval_secs);
+        let elapsed = start.elapsed();
+        if elapsed < interval {
+            thread::sleep(interval - elapsed);
+        }
+    }
+}
+
+pub(crate) fn report(db_path: &Path, window_secs: u64) {
+    if !db_path.exists() {
+        handle_error("database not found!");
+        return;
+    }
+    let since = Utc::now().timestamp() - window_secs as i64;
+    match PingHistory::open(db_path).and_then(|ping_history| ping_history.uptime(since)) {
+        Ok(peers) => {
+            serde_json::ser::to_writer_pretty(
+                &mut stdout(),
+                &PingReport {
+                    status: "ok".to_string(),
+                    window_secs,
+                    peers,
+                },
+            )
+            .unwrap();
+        }
+        Err(error) => handle_error(error),
+    }
+}
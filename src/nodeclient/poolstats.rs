@@ -0,0 +1,249 @@
+use std::collections::BTreeMap;
+use std::io::stdout;
+use std::path::Path;
+
+use log::debug;
+use rusqlite::{Connection, Error};
+use serde::Serialize;
+
+use crate::nodeclient::leaderlog::{
+    get_first_slot_of_epoch, get_first_slot_of_epoch_number, get_tip_slot_number, handle_error, read_byron_genesis,
+    read_shelley_genesis,
+};
+use crate::nodeclient::OutputFormat;
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PoolStats {
+    pub status: String,
+    pub pool_id: String,
+    pub total_blocks: i64,
+    pub total_orphans: i64,
+    pub first_block: Option<PoolBlock>,
+    pub last_block: Option<PoolBlock>,
+    pub longest_gap_slots: i64,
+    pub epochs_without_blocks: i64,
+    pub epochs: Vec<EpochStats>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EpochStats {
+    pub epoch: i64,
+    pub blocks: i64,
+    pub orphans: i64,
+    pub avg_block_size: f64,
+    pub first_block_number: Option<i64>,
+    pub last_block_number: Option<i64>,
+}
+
+/// The blocks of a pool that are on the chain
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct PoolBlocks {
+    status: String,
+    pool_id: String,
+    blocks: Vec<PoolBlock>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PoolBlock {
+    pub block_number: i64,
+    pub slot_number: i64,
+    pub hash: String,
+    #[serde(skip)]
+    pub block_size: i64,
+    #[serde(skip)]
+    pub orphaned: bool,
+}
+
+pub(crate) fn pool_stats(
+    db_path: &Path,
+    byron_genesis: &Path,
+    shelley_genesis: &Path,
+    pool_id: &str,
+    epochs: &Option<i64>,
+    output_format: &OutputFormat,
+) {
+    if !db_path.exists() {
+        handle_error("database not found!");
+        return;
+    }
+
+    match calculate_pool_stats(db_path, byron_genesis, shelley_genesis, pool_id, epochs) {
+        Ok(pool_stats) => match output_format {
+            OutputFormat::Json => {
+                serde_json::ser::to_writer_pretty(&mut stdout(), &pool_stats).unwrap();
+            }
+            OutputFormat::Table => print_pool_stats_table(&pool_stats),
+        },
+        Err(error) => handle_error(error),
+    }
+}
+
+/// Block production of the pool in every epoch from its first block, or the last epochs, up to the tip
+pub fn calculate_pool_stats(
+    db_path: &Path,
+    byron_genesis: &Path,
+    shelley_genesis: &Path,
+    pool_id: &str,
+    epochs: &Option<i64>,
+) -> Result<PoolStats, String> {
+    if let Some(epochs) = epochs {
+        if *epochs < 1 {
+            return Err(format!("epochs must be at least 1, got {}", epochs));
+        }
+    }
+    let byron = read_byron_genesis(byron_genesis).map_err(|error| error.to_string())?;
+    debug!("{:?}", byron);
+    let shelley = read_shelley_genesis(shelley_genesis).map_err(|error| error.to_string())?;
+    debug!("{:?}", shelley);
+
+    let db = Connection::open(db_path).map_err(|error| error.to_string())?;
+    let tip_slot_number = get_tip_slot_number(&db).map_err(|error| error.to_string())?;
+    debug!("tip_slot_number: {}", tip_slot_number);
+    let pool_blocks = get_pool_blocks(&db, pool_id).map_err(|error| error.to_string())?;
+    if let Err(error) = db.close() {
+        return Err(format!("db close error: {}", error.1));
+    }
+    if pool_blocks.is_empty() {
+        return Err(format!("No blocks found for pool {}", pool_id));
+    }
+
+    let on_chain_blocks: Vec<&PoolBlock> = pool_blocks.iter().filter(|block| !block.orphaned).collect();
+
+    // Report every epoch from the pool's first block (or the requested number of epochs) up to the tip
+    let (tip_epoch, _) = get_first_slot_of_epoch(&byron, &shelley, tip_slot_number);
+    let (first_epoch, _) = get_first_slot_of_epoch(&byron, &shelley, pool_blocks[0].slot_number);
+    let start_epoch = match epochs {
+        Some(epochs) => tip_epoch - epochs + 1,
+        None => first_epoch,
+    };
+    // Only the gaps between blocks in the reported epochs
+    let start_slot = get_first_slot_of_epoch_number(&byron, &shelley, start_epoch);
+    let longest_gap_slots = on_chain_blocks
+        .iter()
+        .filter(|block| block.slot_number >= start_slot)
+        .collect::<Vec<_>>()
+        .windows(2)
+        .map(|pair| pair[1].slot_number - pair[0].slot_number)
+        .max()
+        .unwrap_or(0);
+    let mut epoch_blocks: BTreeMap<i64, Vec<&PoolBlock>> =
+        (start_epoch..=tip_epoch).map(|epoch| (epoch, vec![])).collect();
+    for block in pool_blocks.iter() {
+        let (epoch, _) = get_first_slot_of_epoch(&byron, &shelley, block.slot_number);
+        if let Some(blocks) = epoch_blocks.get_mut(&epoch) {
+            blocks.push(block);
+        }
+    }
+
+    let epoch_stats: Vec<EpochStats> = epoch_blocks
+        .into_iter()
+        .map(|(epoch, blocks)| {
+            let on_chain: Vec<&&PoolBlock> = blocks.iter().filter(|block| !block.orphaned).collect();
+            let total_size: i64 = on_chain.iter().map(|block| block.block_size).sum();
+            EpochStats {
+                epoch,
+                blocks: on_chain.len() as i64,
+                orphans: (blocks.len() - on_chain.len()) as i64,
+                avg_block_size: if on_chain.is_empty() {
+                    0.0
+                } else {
+                    (total_size as f64 / on_chain.len() as f64 * 100.0).round() / 100.0
+                },
+                first_block_number: on_chain.first().map(|block| block.block_number),
+                last_block_number: on_chain.last().map(|block| block.block_number),
+            }
+        })
+        .collect();
+
+    Ok(PoolStats {
+        status: "ok".to_string(),
+        pool_id: pool_id.to_string(),
+        total_blocks: on_chain_blocks.len() as i64,
+        total_orphans: (pool_blocks.len() - on_chain_blocks.len()) as i64,
+        first_block: on_chain_blocks.first().map(|block| (*block).clone()),
+        last_block: on_chain_blocks.last().map(|block| (*block).clone()),
+        longest_gap_slots,
+        epochs_without_blocks: epoch_stats.iter().filter(|stats| stats.blocks == 0).count() as i64,
+        epochs: epoch_stats,
+    })
+}
+
+/// The on-chain blocks of the pool, oldest first, None when the pool has no blocks
+pub(crate) fn pool_blocks(db: &Connection, pool_id: &str) -> Result<Option<PoolBlocks>, String> {
+    let pool_blocks = get_pool_blocks(db, pool_id).map_err(|error| error.to_string())?;
+    let blocks: Vec<PoolBlock> = pool_blocks.into_iter().filter(|block| !block.orphaned).collect();
+    if blocks.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(PoolBlocks {
+        status: "ok".to_string(),
+        pool_id: pool_id.to_string(),
+        blocks,
+    }))
+}
+
+fn get_pool_blocks(db: &Connection, pool_id: &str) -> Result<Vec<PoolBlock>, Error> {
+    let mut stmt = db.prepare(
+        "SELECT block_number, slot_number, hash, block_size, orphaned FROM chain \
+        WHERE pool_id = ?1 ORDER BY slot_number ASC",
+    )?;
+    let blocks = stmt
+        .query_map(&[&pool_id], |row| {
+            Ok(PoolBlock {
+                block_number: row.get(0)?,
+                slot_number: row.get(1)?,
+                hash: row.get(2)?,
+                block_size: row.get(3)?,
+                orphaned: row.get(4)?,
+            })
+        })?
+        .collect::<Result<Vec<PoolBlock>, Error>>();
+    blocks
+}
+
+fn print_pool_stats_table(pool_stats: &PoolStats) {
+    println!("Pool: {}", pool_stats.pool_id);
+    println!(
+        "Blocks: {}, Orphans: {}, Longest gap: {} slots, Epochs without blocks: {}",
+        pool_stats.total_blocks,
+        pool_stats.total_orphans,
+        pool_stats.longest_gap_slots,
+        pool_stats.epochs_without_blocks
+    );
+    if let Some(first_block) = &pool_stats.first_block {
+        println!(
+            "First block: {} (slot {})",
+            first_block.block_number, first_block.slot_number
+        );
+    }
+    if let Some(last_block) = &pool_stats.last_block {
+        println!(
+            "Last block: {} (slot {})",
+            last_block.block_number, last_block.slot_number
+        );
+    }
+    println!();
+    println!(
+        "{:>6} {:>7} {:>8} {:>14} {:>12} {:>12}",
+        "Epoch", "Blocks", "Orphans", "AvgBlockSize", "FirstBlock", "LastBlock"
+    );
+    for stats in pool_stats.epochs.iter() {
+        println!(
+            "{:>6} {:>7} {:>8} {:>14.2} {:>12} {:>12}",
+            stats.epoch,
+            stats.blocks,
+            stats.orphans,
+            stats.avg_block_size,
+            stats
+                .first_block_number
+                .map_or("-".to_string(), |block_number| block_number.to_string()),
+            stats
+                .last_block_number
+                .map_or("-".to_string(), |block_number| block_number.to_string()),
+        );
+    }
+}
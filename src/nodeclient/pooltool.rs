@@ -1,58 +1,213 @@
 use std::ops::Sub;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
 use std::time::{Duration, Instant};
 
-use crate::nodeclient::APP_USER_AGENT;
+use crate::nodeclient::outbox::Outbox;
+use crate::nodeclient::{PooltoolConfig, APP_USER_AGENT};
 use cardano_ouroboros_network::protocols::chainsync::Listener;
 use cardano_ouroboros_network::BlockHeader;
 use chrono::{SecondsFormat, Utc};
-use log::{error, info};
+use log::{error, info, warn};
 use regex::Regex;
 use serde::Serialize;
 
+pub const SENDSTATS: &str = "/v0/sendstats";
+pub const SENDSLOTS: &str = "/v0/sendslots";
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PooltoolStats {
+    pub api_key: String,
+    pub pool_id: String,
+    pub data: PooltoolData,
+}
+
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
-struct PooltoolStats {
-    api_key: String,
-    pool_id: String,
-    data: PooltoolData,
+pub struct PooltoolData {
+    pub node_id: String,
+    pub version: String,
+    pub at: String,
+    pub block_no: i64,
+    pub slot_no: i64,
+    pub block_hash: String,
+    pub parent_hash: String,
+    pub leader_vrf: String,
+    pub leader_vrf_proof: String,
+    pub node_v_key: String,
+    pub platform: String,
 }
 
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
-struct PooltoolData {
-    node_id: String,
-    version: String,
-    at: String,
-    block_no: i64,
-    slot_no: i64,
-    block_hash: String,
-    parent_hash: String,
-    leader_vrf: String,
-    leader_vrf_proof: String,
-    node_v_key: String,
-    platform: String,
+pub struct PooltoolSendSlots {
+    pub api_key: String,
+    pub pool_id: String,
+    pub epoch: i64,
+    pub slot_qty: i64,
+    pub hash: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub override_time: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prev_slots: Option<String>,
+}
+
+/// A reusable http client for the pooltool api
+#[derive(Debug, Clone)]
+pub struct PooltoolClient {
+    client: reqwest::blocking::Client,
+    base_url: String,
+}
+
+impl PooltoolClient {
+    pub fn new(base_url: &str, timeout: Duration, proxy: &Option<String>) -> Result<Self, String> {
+        let mut builder = reqwest::blocking::Client::builder()
+            .user_agent(APP_USER_AGENT)
+            .timeout(timeout);
+        if let Some(proxy) = proxy {
+            builder = builder.proxy(reqwest::Proxy::all(proxy).map_err(|error| error.to_string())?);
+        }
+        match builder.build() {
+            Ok(client) => Ok(PooltoolClient {
+                client,
+                base_url: base_url.trim_end_matches('/').to_string(),
+            }),
+            Err(error) => Err(format!("Could not set up the reqwest client!: {}", error)),
+        }
+    }
+
+    pub(crate) fn from_config(pooltool_config: &PooltoolConfig) -> Result<Self, String> {
+        PooltoolClient::new(
+            &pooltool_config.base_url,
+            Duration::from_secs(pooltool_config.timeout),
+            &pooltool_config.proxy,
+        )
+    }
+
+    pub fn send_stats(&self, stats: &PooltoolStats) -> Result<String, String> {
+        self.post_payload(SENDSTATS, serde_json::ser::to_string(stats).unwrap())
+    }
+
+    pub fn send_slots(&self, send_slots: &PooltoolSendSlots) -> Result<String, String> {
+        let request = serde_json::ser::to_string(send_slots).unwrap();
+        info!("Sending: {}", &request);
+        self.post_payload(SENDSLOTS, request)
+    }
+
+    pub(crate) fn post_payload(&self, path: &str, body: String) -> Result<String, String> {
+        self.client
+            .post(format!("{}{}", self.base_url, path))
+            .body(body)
+            .send()
+            .and_then(|response| response.error_for_status())
+            .and_then(|response| response.text())
+            .map_err(|error| error.to_string())
+    }
+}
+
+/// Where the cardano-node version we report to pooltool comes from
+#[derive(Debug, Clone)]
+pub enum NodeVersionSource {
+    /// A fixed version from the pooltool config
+    Config(String),
+    /// The node's prometheus metrics url
+    Prometheus(String),
+    /// The cardano-node executable
+    Binary(PathBuf),
+}
+
+impl NodeVersionSource {
+    pub fn fetch(&self) -> Result<String, String> {
+        match self {
+            NodeVersionSource::Config(version) => Ok(version.clone()),
+            NodeVersionSource::Prometheus(url) => {
+                let metrics = reqwest::blocking::Client::builder()
+                    .user_agent(APP_USER_AGENT)
+                    .timeout(Duration::from_secs(5))
+                    .build()
+                    .and_then(|client| client.get(url).send())
+                    .and_then(|response| response.text())
+                    .map_err(|error| error.to_string())?;
+                parse_prometheus_build_info(&metrics)
+                    .ok_or_else(|| format!("No build info in prometheus metrics from {}", url))
+            }
+            NodeVersionSource::Binary(cardano_node_path) => {
+                let output = Command::new(cardano_node_path)
+                    .arg("--version")
+                    .stdin(Stdio::null())
+                    .stdout(Stdio::piped())
+                    .output()
+                    .map_err(|error| error.to_string())?;
+                let version_string = String::from_utf8_lossy(&output.stdout);
+                parse_cardano_node_version(&version_string)
+                    .ok_or_else(|| format!("Unexpected cardano-node --version output: {}", version_string))
+            }
+        }
+    }
+}
+
+/// Parse `cardano-node --version` output into the version:rev format pooltool expects
+pub fn parse_cardano_node_version(version_string: &str) -> Option<String> {
+    let cap = Regex::new("cardano-node (\\d+\\.\\d+\\.\\d+) .*\ngit rev ([a-f0-9]{5}).*")
+        .unwrap()
+        .captures(version_string)?;
+    Some(format!("{}:{}", cap.get(1)?.as_str(), cap.get(2)?.as_str()))
+}
+
+/// Find the version and git revision labels of a *build_info metric in prometheus text output
+pub fn parse_prometheus_build_info(metrics: &str) -> Option<String> {
+    let line = metrics.lines().find(|line| {
+        let name = line.split(&['{', ' '][..]).next().unwrap_or("");
+        name.ends_with("build_info")
+    })?;
+    let label = |name: &str| {
+        Regex::new(&format!("[{{,]\\s*{}=\"([^\"]*)\"", name))
+            .unwrap()
+            .captures(line)
+            .and_then(|cap| cap.get(1))
+            .map(|value| value.as_str().to_string())
+    };
+    let version = label("version")?;
+    let revision = label("revision").or_else(|| label("gitrev")).unwrap_or_default();
+    Some(format!("{}:{}", version, &revision[..revision.len().min(5)]))
 }
 
 pub struct PoolToolNotifier {
     pub pool_name: String,
     pub pool_id: String,
     pub api_key: String,
-    pub cardano_node_path: PathBuf,
+    pub node_version_sources: Vec<NodeVersionSource>,
     pub last_node_version_time: Instant,
     pub node_version: String,
+    pub client: PooltoolClient,
+    pub db: PathBuf,
+    // Opened on first use, so a sendtip that never fails doesn't create the db
+    pub outbox: Option<Outbox>,
+    // A tip of this pool may be waiting in the outbox, e.g. from an earlier run
+    pub tip_waiting: bool,
 }
 
-impl Default for PoolToolNotifier {
-    fn default() -> Self {
+impl PoolToolNotifier {
+    pub fn new(
+        pool_name: String,
+        pool_id: String,
+        api_key: String,
+        node_version_sources: Vec<NodeVersionSource>,
+        client: PooltoolClient,
+        db: PathBuf,
+    ) -> Self {
         PoolToolNotifier {
-            pool_name: String::new(),
-            pool_id: String::new(),
-            api_key: String::new(),
-            cardano_node_path: PathBuf::new(),
+            pool_name,
+            pool_id,
+            api_key,
+            node_version_sources,
             last_node_version_time: Instant::now().sub(Duration::from_secs(7200)), // 2 hours ago
             node_version: String::new(),
+            client,
+            db,
+            outbox: None,
+            tip_waiting: true,
         }
     }
 }
@@ -60,86 +215,85 @@ impl Default for PoolToolNotifier {
 impl PoolToolNotifier {
     pub fn send_to_pooltool(&mut self, header: &BlockHeader) {
         if self.last_node_version_time.elapsed() > Duration::from_secs(3600) {
-            // Our node version is outdated. Make a call to update it.
-            match Command::new(&self.cardano_node_path)
-                .arg("--version")
-                .stdin(Stdio::null())
-                .stdout(Stdio::piped())
-                .output()
-            {
-                Ok(output) => {
-                    let version_string = String::from_utf8_lossy(&output.stdout);
-                    let cap = Regex::new("cardano-node (\\d+\\.\\d+\\.\\d+) .*\ngit rev ([a-f0-9]{5}).*")
-                        .unwrap()
-                        .captures(&*version_string)
-                        .unwrap();
-                    self.node_version = format!(
-                        "{}:{}",
-                        cap.get(1).map_or("", |m| m.as_str()),
-                        cap.get(2).map_or("", |m| m.as_str())
-                    );
-                    info!("Checking cardano-node version: {}", &self.node_version);
-                    self.last_node_version_time = Instant::now();
-                }
-                Err(err) => {
-                    panic!("Error getting cardano-node version: {}", err)
+            // Our node version is outdated. Try each source in turn, keeping the old version if all fail.
+            for source in self.node_version_sources.iter() {
+                match source.fetch() {
+                    Ok(node_version) => {
+                        self.node_version = node_version;
+                        info!("Checking cardano-node version: {}", &self.node_version);
+                        break;
+                    }
+                    Err(error) => {
+                        warn!("Error getting cardano-node version from {:?}: {}", source, error);
+                    }
                 }
             }
+            self.last_node_version_time = Instant::now();
         }
-        match reqwest::blocking::Client::builder().user_agent(APP_USER_AGENT).build() {
-            Ok(client) => {
-                let pooltool_result = client
-                    .post("https://api.pooltool.io/v0/sendstats")
-                    .body(
-                        serde_json::ser::to_string(&PooltoolStats {
-                            api_key: self.api_key.clone(),
-                            pool_id: self.pool_id.clone(),
-                            data: PooltoolData {
-                                node_id: "".to_string(),
-                                version: self.node_version.clone(),
-                                at: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
-                                block_no: header.block_number,
-                                slot_no: header.slot_number,
-                                block_hash: hex::encode(&header.hash),
-                                parent_hash: hex::encode(&header.prev_hash),
-                                leader_vrf: hex::encode(&header.leader_vrf_0),
-                                leader_vrf_proof: hex::encode(&header.leader_vrf_1),
-                                node_v_key: hex::encode(&header.node_vkey),
-                                platform: "cncli".to_string(),
-                            },
-                        })
-                        .unwrap(),
-                    )
-                    .send();
-
-                match pooltool_result {
-                    Ok(response) => match response.text() {
-                        Ok(text) => {
-                            info!(
-                                "Pooltool ({}, {}): ({}, {}), json: {}",
-                                &self.pool_name,
-                                &self.pool_id[..8],
-                                &header.block_number,
-                                hex::encode(&header.hash[..8]),
-                                text
-                            );
-                        }
-                        Err(error) => {
-                            error!("PoolTool error: {}", error);
+        let stats = PooltoolStats {
+            api_key: self.api_key.clone(),
+            pool_id: self.pool_id.clone(),
+            data: PooltoolData {
+                node_id: "".to_string(),
+                version: self.node_version.clone(),
+                at: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+                block_no: header.block_number,
+                slot_no: header.slot_number,
+                block_hash: hex::encode(&header.hash),
+                parent_hash: hex::encode(&header.prev_hash),
+                leader_vrf: hex::encode(&header.leader_vrf_0),
+                leader_vrf_proof: hex::encode(&header.leader_vrf_1),
+                node_v_key: hex::encode(&header.node_vkey),
+                platform: "cncli".to_string(),
+            },
+        };
+
+        match self.client.send_stats(&stats) {
+            Ok(text) => {
+                info!(
+                    "Pooltool ({}, {}): ({}, {}), json: {}",
+                    &self.pool_name,
+                    &self.pool_id[..8],
+                    &header.block_number,
+                    hex::encode(&header.hash[..8]),
+                    text
+                );
+                // Pooltool has a newer tip now, so the one waiting in the outbox is stale. Tips are superseded
+                // like this rather than retried, a new one comes in every block.
+                if self.tip_waiting && self.db.exists() {
+                    if let Some(outbox) = open_outbox(&mut self.outbox, &self.db) {
+                        match outbox.discard(SENDSTATS, &self.pool_id) {
+                            Ok(_) => self.tip_waiting = false,
+                            Err(error) => error!("Could not discard the outbox tip: {}", error),
                         }
-                    },
-                    Err(error) => {
-                        error!("PoolTool error: {}", error);
                     }
                 }
             }
-            Err(err) => {
-                error!("Could not set up the reqwest client!: {}", err)
+            Err(error) => {
+                error!("PoolTool error: {}", error);
+                let payload = serde_json::ser::to_string(&stats).unwrap();
+                if let Some(outbox) = open_outbox(&mut self.outbox, &self.db) {
+                    match outbox.enqueue(SENDSTATS, &self.pool_id, &payload, &error) {
+                        Ok(_) => info!("Saved {} payload for {} to the outbox", SENDSTATS, &self.pool_id),
+                        Err(error) => error!("Could not save to the outbox: {}", error),
+                    }
+                }
+                self.tip_waiting = true;
             }
         }
     }
 }
 
+fn open_outbox<'a>(outbox: &'a mut Option<Outbox>, db: &Path) -> Option<&'a Outbox> {
+    if outbox.is_none() {
+        match Outbox::open(db) {
+            Ok(opened) => *outbox = Some(opened),
+            Err(error) => error!("Could not open the outbox: {}", error),
+        }
+    }
+    outbox.as_ref()
+}
+
 impl Listener for PoolToolNotifier {
     fn handle_tip(&mut self, block_header: &BlockHeader) {
         self.send_to_pooltool(block_header);
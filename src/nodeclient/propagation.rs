@@ -0,0 +1,161 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use cardano_ouroboros_network::protocols::chainsync::Listener;
+use cardano_ouroboros_network::BlockHeader;
+use chrono::{SecondsFormat, Utc};
+use log::{error, info};
+use serde::Serialize;
+
+use crate::nodeclient::leaderlog::{slot_to_naivedatetime, ByronGenesis, ShelleyGenesis};
+
+// Percentiles are over this many of the most recent tips of a relay
+const WINDOW_SIZE: usize = 1000;
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PropagationReport {
+    status: String,
+    updated_at: String,
+    relays: Vec<RelayDelays>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RelayDelays {
+    relay: String,
+    #[serde(flatten)]
+    stats: DelayStats,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DelayStats {
+    pub count: usize,
+    pub last_ms: i64,
+    pub min_ms: i64,
+    pub p50_ms: i64,
+    pub p90_ms: i64,
+    pub p95_ms: i64,
+    pub p99_ms: i64,
+    pub max_ms: i64,
+}
+
+/// The most recent tip delays of a relay
+pub struct DelayWindow {
+    delays: VecDeque<i64>,
+    capacity: usize,
+}
+
+impl DelayWindow {
+    pub fn new(capacity: usize) -> Self {
+        DelayWindow {
+            delays: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn push(&mut self, delay_ms: i64) {
+        if self.delays.len() == self.capacity {
+            self.delays.pop_front();
+        }
+        self.delays.push_back(delay_ms);
+    }
+
+    pub fn stats(&self) -> DelayStats {
+        let mut sorted: Vec<i64> = self.delays.iter().copied().collect();
+        if sorted.is_empty() {
+            return DelayStats::default();
+        }
+        sorted.sort_unstable();
+        // nearest-rank percentile, the rank being ceil(p * n / 100)
+        let percentile = |p: usize| sorted[(p * sorted.len() - 1) / 100];
+        DelayStats {
+            count: sorted.len(),
+            last_ms: *self.delays.back().unwrap(),
+            min_ms: sorted[0],
+            p50_ms: percentile(50),
+            p90_ms: percentile(90),
+            p95_ms: percentile(95),
+            p99_ms: percentile(99),
+            max_ms: sorted[sorted.len() - 1],
+        }
+    }
+}
+
+/// Tip delays of all relays, shared by their trackers
+pub(crate) struct Propagation {
+    byron: ByronGenesis,
+    shelley: ShelleyGenesis,
+    stats_file: Option<PathBuf>,
+    windows: Mutex<BTreeMap<String, DelayWindow>>,
+}
+
+impl Propagation {
+    pub(crate) fn new(byron: ByronGenesis, shelley: ShelleyGenesis, stats_file: Option<PathBuf>) -> Self {
+        Propagation {
+            byron,
+            shelley,
+            stats_file,
+            windows: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn record(&self, relay: &str, header: &BlockHeader) {
+        let slot_time = slot_to_naivedatetime(&self.byron, &self.shelley, header.slot_number);
+        let delay_ms = (Utc::now().naive_utc() - slot_time).num_milliseconds();
+
+        let mut windows = self.windows.lock().unwrap();
+        let window = windows
+            .entry(relay.to_string())
+            .or_insert_with(|| DelayWindow::new(WINDOW_SIZE));
+        window.push(delay_ms);
+        let stats = window.stats();
+        info!(
+            "Tip propagation ({}): block {}, slot {}, delay {}ms, p50 {}ms, p90 {}ms, p99 {}ms over {} tips",
+            relay,
+            header.block_number,
+            header.slot_number,
+            delay_ms,
+            stats.p50_ms,
+            stats.p90_ms,
+            stats.p99_ms,
+            stats.count
+        );
+
+        if let Some(stats_file) = &self.stats_file {
+            let report = PropagationReport {
+                status: "ok".to_string(),
+                updated_at: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+                relays: windows
+                    .iter()
+                    .map(|(relay, window)| RelayDelays {
+                        relay: relay.clone(),
+                        stats: window.stats(),
+                    })
+                    .collect(),
+            };
+            // Write then rename so readers never see a partial file
+            let tmp_file = stats_file.with_extension("tmp");
+            if let Err(error) = fs::write(&tmp_file, serde_json::ser::to_string_pretty(&report).unwrap())
+                .and_then(|_| fs::rename(&tmp_file, stats_file))
+            {
+                error!("Could not write propagation stats to {:?}: {}", stats_file, error);
+            }
+        }
+    }
+}
+
+/// Measures how long after the start of its slot each tip arrives from a relay
+pub(crate) struct PropagationTracker {
+    pub(crate) relay: String,
+    pub(crate) propagation: Arc<Propagation>,
+}
+
+impl Listener for PropagationTracker {
+    fn handle_tip(&mut self, header: &BlockHeader) {
+        self.propagation.record(&self.relay, header);
+    }
+}
@@ -0,0 +1,254 @@
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+
+use log::debug;
+use rand::Rng;
+
+const DNS_PORT: u16 = 53;
+const DNS_TIMEOUT: Duration = Duration::from_secs(5);
+const DNS_TYPE_SRV: u16 = 33;
+const DNS_CLASS_IN: u16 = 1;
+// The truncation flag of the header, the full answer has to be fetched over TCP
+const DNS_FLAG_TC: u16 = 0x0200;
+const DNS_MAX_LABEL: usize = 63;
+const DNS_MAX_NAME: usize = 253;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SrvRecord {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    pub target: String,
+}
+
+/// Every address a relay can be reached on. SRV names such as _cardano-mainnet._tcp.example.com
+/// are looked up as DNS SRV records and the port of each record is used instead of the given one.
+/// IPv6 literals may be written with or without brackets.
+pub fn resolve(host: &str, port: u16) -> Result<Vec<SocketAddr>, String> {
+    if is_srv_name(host) {
+        let records = query_srv(host, &nameservers()?)?;
+        if records.is_empty() {
+            return Err(format!("no SRV records found for {}", host));
+        }
+        let mut addresses: Vec<SocketAddr> = vec![];
+        let mut last_error = String::new();
+        for record in records.iter() {
+            match resolve_host(&record.target, record.port) {
+                Ok(record_addresses) => {
+                    for address in record_addresses.into_iter() {
+                        if !addresses.contains(&address) {
+                            addresses.push(address);
+                        }
+                    }
+                }
+                Err(error) => last_error = error,
+            }
+        }
+        if addresses.is_empty() {
+            return Err(last_error);
+        }
+        return Ok(addresses);
+    }
+    resolve_host(host, port)
+}
+
+// All A and AAAA records of a host in the order the system resolver returns them
+fn resolve_host(host: &str, port: u16) -> Result<Vec<SocketAddr>, String> {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let mut addresses: Vec<SocketAddr> = vec![];
+    for address in (host, port).to_socket_addrs().map_err(|error| error.to_string())? {
+        if !addresses.contains(&address) {
+            addresses.push(address);
+        }
+    }
+    if addresses.is_empty() {
+        return Err(format!("no addresses found for {}", host));
+    }
+    Ok(addresses)
+}
+
+/// Whether a host is an SRV name, _service._proto followed by the domain
+pub fn is_srv_name(host: &str) -> bool {
+    let mut labels = host.split('.');
+    match (labels.next(), labels.next(), labels.next()) {
+        (Some(service), Some(proto), Some(_)) => {
+            service.len() > 1 && service.starts_with('_') && (proto == "_tcp" || proto == "_udp")
+        }
+        _ => false,
+    }
+}
+
+/// Look up the SRV records of a name, trying each nameserver in turn until one answers
+pub fn query_srv(name: &str, nameservers: &[SocketAddr]) -> Result<Vec<SrvRecord>, String> {
+    let id: u16 = rand::thread_rng().gen();
+    let query = srv_query(id, name).map_err(|error| format!("SRV lookup of {} failed: {}", name, error))?;
+    let mut last_error = "no nameservers".to_string();
+    for nameserver in nameservers.iter() {
+        match query_nameserver(*nameserver, id, &query) {
+            Ok(response) => {
+                debug!("SRV response from {}: {}", nameserver, hex::encode(&response));
+                return parse_srv_response(&response)
+                    .map_err(|error| format!("SRV lookup of {} failed: {}", name, error));
+            }
+            Err(error) => {
+                debug!("SRV lookup of {} at {} failed: {}", name, nameserver, error);
+                last_error = format!("{}: {}", nameserver, error);
+            }
+        }
+    }
+    Err(format!("SRV lookup of {} failed: {}", name, last_error))
+}
+
+// The response to a query over UDP, or over TCP if the UDP response was truncated
+fn query_nameserver(nameserver: SocketAddr, id: u16, query: &[u8]) -> Result<Vec<u8>, String> {
+    let socket = UdpSocket::bind(if nameserver.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" })
+        .map_err(|error| error.to_string())?;
+    socket
+        .set_read_timeout(Some(DNS_TIMEOUT))
+        .map_err(|error| error.to_string())?;
+    // Connected, so only the nameserver can answer and a nameserver that is down fails right away
+    socket.connect(nameserver).map_err(|error| error.to_string())?;
+    socket.send(query).map_err(|error| error.to_string())?;
+    let mut response = [0u8; 4096];
+    let size = socket.recv(&mut response).map_err(|error| error.to_string())?;
+    let mut response = response[..size].to_vec();
+    if size >= 4 && u16::from_be_bytes([response[2], response[3]]) & DNS_FLAG_TC != 0 {
+        debug!("Truncated SRV response from {}, retrying over TCP", nameserver);
+        response = query_tcp(nameserver, query)?;
+    }
+    if response.len() < 2 || u16::from_be_bytes([response[0], response[1]]) != id {
+        return Err("unexpected response".to_string());
+    }
+    Ok(response)
+}
+
+// Over TCP each message is prefixed with its length
+fn query_tcp(nameserver: SocketAddr, query: &[u8]) -> Result<Vec<u8>, String> {
+    let mut stream = TcpStream::connect_timeout(&nameserver, DNS_TIMEOUT).map_err(|error| error.to_string())?;
+    stream
+        .set_read_timeout(Some(DNS_TIMEOUT))
+        .map_err(|error| error.to_string())?;
+    let mut message = (query.len() as u16).to_be_bytes().to_vec();
+    message.extend_from_slice(query);
+    stream.write_all(&message).map_err(|error| error.to_string())?;
+    let mut length = [0u8; 2];
+    stream.read_exact(&mut length).map_err(|error| error.to_string())?;
+    let mut response = vec![0u8; u16::from_be_bytes(length) as usize];
+    stream.read_exact(&mut response).map_err(|error| error.to_string())?;
+    Ok(response)
+}
+
+// Every nameserver in /etc/resolv.conf, in order
+fn nameservers() -> Result<Vec<SocketAddr>, String> {
+    let resolv_conf = fs::read_to_string("/etc/resolv.conf").map_err(|error| format!("/etc/resolv.conf: {}", error))?;
+    let nameservers: Vec<SocketAddr> = resolv_conf
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            match (fields.next(), fields.next()) {
+                (Some("nameserver"), Some(address)) => address.parse().ok(),
+                _ => None,
+            }
+        })
+        .map(|ip| SocketAddr::new(ip, DNS_PORT))
+        .collect();
+    if nameservers.is_empty() {
+        return Err("no nameserver found in /etc/resolv.conf".to_string());
+    }
+    Ok(nameservers)
+}
+
+fn srv_query(id: u16, name: &str) -> Result<Vec<u8>, String> {
+    let name = name.trim_end_matches('.');
+    if name.len() > DNS_MAX_NAME {
+        return Err(format!("name longer than {} bytes", DNS_MAX_NAME));
+    }
+    let mut query: Vec<u8> = vec![];
+    query.extend_from_slice(&id.to_be_bytes());
+    // recursion desired, one question
+    query.extend_from_slice(&[0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > DNS_MAX_LABEL {
+            return Err(format!("label {:?} must be 1 to {} bytes", label, DNS_MAX_LABEL));
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&DNS_TYPE_SRV.to_be_bytes());
+    query.extend_from_slice(&DNS_CLASS_IN.to_be_bytes());
+    Ok(query)
+}
+
+/// The SRV records in a DNS response, lowest priority first and highest weight first within a priority
+pub fn parse_srv_response(response: &[u8]) -> Result<Vec<SrvRecord>, String> {
+    let truncated = || "truncated DNS response".to_string();
+    let read_u16 = |offset: usize| -> Result<u16, String> {
+        match response.get(offset..offset + 2) {
+            Some(bytes) => Ok(u16::from_be_bytes([bytes[0], bytes[1]])),
+            None => Err(truncated()),
+        }
+    };
+
+    match read_u16(2)? & 0x000f {
+        0 => {}
+        3 => return Err("no such domain".to_string()),
+        rcode => return Err(format!("DNS error response code: {}", rcode)),
+    }
+    let questions = read_u16(4)?;
+    let answers = read_u16(6)?;
+
+    let mut offset = 12;
+    for _ in 0..questions {
+        offset = read_name(response, offset)?.1 + 4;
+    }
+
+    let mut records: Vec<SrvRecord> = vec![];
+    for _ in 0..answers {
+        offset = read_name(response, offset)?.1;
+        let record_type = read_u16(offset)?;
+        let data_length = read_u16(offset + 8)? as usize;
+        let data = offset + 10;
+        if record_type == DNS_TYPE_SRV {
+            records.push(SrvRecord {
+                priority: read_u16(data)?,
+                weight: read_u16(data + 2)?,
+                port: read_u16(data + 4)?,
+                target: read_name(response, data + 6)?.0,
+            });
+        }
+        offset = data + data_length;
+    }
+    records.sort_by(|a, b| a.priority.cmp(&b.priority).then(b.weight.cmp(&a.weight)));
+    Ok(records)
+}
+
+// A possibly compressed domain name and the offset just past it
+fn read_name(response: &[u8], offset: usize) -> Result<(String, usize), String> {
+    let mut labels: Vec<String> = vec![];
+    let mut position = offset;
+    let mut end: Option<usize> = None;
+    // Guard against pointer loops
+    for _ in 0..128 {
+        let length = *response.get(position).ok_or("truncated DNS name")? as usize;
+        if length == 0 {
+            return Ok((labels.join("."), end.unwrap_or(position + 1)));
+        }
+        if length & 0xc0 == 0xc0 {
+            let low = *response.get(position + 1).ok_or("truncated DNS name")? as usize;
+            if end.is_none() {
+                end = Some(position + 2);
+            }
+            position = ((length & 0x3f) << 8) | low;
+            continue;
+        }
+        let label = response
+            .get(position + 1..position + 1 + length)
+            .ok_or("truncated DNS name")?;
+        labels.push(String::from_utf8_lossy(label).to_string());
+        position += 1 + length;
+    }
+    Err("DNS name compression loop".to_string())
+}
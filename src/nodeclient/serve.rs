@@ -0,0 +1,320 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use chrono_tz::Tz;
+use log::{debug, info, warn};
+use rusqlite::{Connection, OpenFlags};
+use serde::Serialize;
+
+use crate::nodeclient::leaderlog::{
+    calculate_epoch_nonce, check_tip_synced, get_first_slot_of_epoch, get_first_slot_of_epoch_number,
+    get_saved_leader_log, get_stability_window, handle_error, read_byron_genesis, read_shelley_genesis, ByronGenesis,
+    ShelleyGenesis,
+};
+use crate::nodeclient::poolstats::pool_blocks;
+use crate::nodeclient::snapshot::{read_tip, SnapshotTip};
+use crate::nodeclient::validate::{find_block, BlockQuery};
+
+// A request line and headers larger than this are cut off
+const MAX_REQUEST_BYTES: u64 = 8192;
+const REQUEST_TIMEOUT_SECS: u64 = 10;
+// Connections are answered by this many threads, with as many more waiting. Any beyond that get a 503.
+const CONNECTION_THREADS: usize = 16;
+
+/// An HTTP status code and the json body to send with it
+pub type Response = (u16, String);
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ApiError {
+    status: String,
+    error_message: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Status {
+    status: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Tip {
+    status: String,
+    epoch: i64,
+    #[serde(flatten)]
+    tip: SnapshotTip,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Nonce {
+    status: String,
+    epoch: i64,
+    epoch_nonce: String,
+}
+
+/// Answers the read-only json api from the db, each request with its own read-only connection
+pub struct Api {
+    db: PathBuf,
+    byron: ByronGenesis,
+    shelley: ShelleyGenesis,
+    tz: Tz,
+    epoch_extra_entropy: HashMap<i64, String>,
+}
+
+impl Api {
+    pub fn new(
+        db: &Path,
+        byron_genesis: &Path,
+        shelley_genesis: &Path,
+        timezone: &str,
+        epoch_extra_entropy: HashMap<i64, String>,
+    ) -> Result<Api, String> {
+        if !db.exists() {
+            return Err("database not found!".to_string());
+        }
+        let tz: Tz = timezone.parse().map_err(|_| "timezone parse error!".to_string())?;
+        Ok(Api {
+            db: db.to_path_buf(),
+            byron: read_byron_genesis(byron_genesis).map_err(|error| error.to_string())?,
+            shelley: read_shelley_genesis(shelley_genesis).map_err(|error| error.to_string())?,
+            tz,
+            epoch_extra_entropy,
+        })
+    }
+
+    /// Route a request, the query string of the target is ignored
+    pub fn handle(&self, method: &str, target: &str) -> Response {
+        let path = target.split('?').next().unwrap_or_default();
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        let response = match segments.as_slice() {
+            ["status"] | ["tip"] | ["nonce", _] | ["leaderlog", _, _] | ["block", _] | ["pool", _, "blocks"]
+                if method != "GET" =>
+            {
+                error(405, format!("method not allowed: {}", method))
+            }
+            ["status"] => self.status(),
+            ["tip"] => self.tip(),
+            ["nonce", epoch] => self.nonce(epoch),
+            ["leaderlog", epoch, pool_id] => self.leader_log(epoch, pool_id),
+            ["block", hash] => self.block(hash),
+            ["pool", pool_id, "blocks"] => self.pool_blocks(pool_id),
+            _ => error(404, format!("not found: {}", path)),
+        };
+        debug!("{} {} -> {}", method, target, response.0);
+        response
+    }
+
+    fn status(&self) -> Response {
+        match self.query(|db| check_tip_synced(db, &self.byron, &self.shelley)) {
+            Ok(()) => ok(&Status {
+                status: "ok".to_string(),
+            }),
+            Err(error_message) => error(503, error_message),
+        }
+    }
+
+    fn tip(&self) -> Response {
+        let tip = match self.query(read_tip) {
+            Ok(tip) => tip,
+            Err(error_message) => return error(500, error_message),
+        };
+        match tip {
+            Some(tip) => {
+                let (epoch, _) = get_first_slot_of_epoch(&self.byron, &self.shelley, tip.slot_number);
+                ok(&Tip {
+                    status: "ok".to_string(),
+                    epoch,
+                    tip,
+                })
+            }
+            None => error(404, "database has no blocks"),
+        }
+    }
+
+    fn nonce(&self, epoch: &str) -> Response {
+        let epoch: i64 = match epoch.parse() {
+            Ok(epoch) => epoch,
+            Err(_) => return error(400, format!("invalid epoch: {}", epoch)),
+        };
+        let first_slot_of_epoch = get_first_slot_of_epoch_number(&self.byron, &self.shelley, epoch);
+        let stability_window_start = first_slot_of_epoch - get_stability_window(&self.byron, &self.shelley);
+        let extra_entropy = self.epoch_extra_entropy.get(&epoch).cloned();
+        let epoch_nonce = self.query(|db| {
+            // The nonce is fixed once the chain is past the start of the stability window
+            match read_tip(db)? {
+                Some(tip) if tip.slot_number >= stability_window_start => {}
+                _ => return Ok(None),
+            }
+            calculate_epoch_nonce(db, &self.byron, &self.shelley, first_slot_of_epoch, &extra_entropy)
+                .map(Some)
+                .or_else(|error| match error {
+                    rusqlite::Error::QueryReturnedNoRows => Ok(None),
+                    error => Err(error.to_string()),
+                })
+        });
+        match epoch_nonce {
+            Ok(Some(epoch_nonce)) => ok(&Nonce {
+                status: "ok".to_string(),
+                epoch,
+                epoch_nonce: hex::encode(&epoch_nonce),
+            }),
+            Ok(None) => error(404, format!("no nonce for epoch {} in the database", epoch)),
+            Err(error_message) => error(500, error_message),
+        }
+    }
+
+    fn leader_log(&self, epoch: &str, pool_id: &str) -> Response {
+        let epoch: i64 = match epoch.parse() {
+            Ok(epoch) => epoch,
+            Err(_) => return error(400, format!("invalid epoch: {}", epoch)),
+        };
+        match self.query(|db| get_saved_leader_log(db, &self.byron, &self.shelley, epoch, pool_id, &self.tz)) {
+            Ok(Some(leader_log)) => ok(&leader_log),
+            Ok(None) => error(404, format!("no leaderlog for pool {} in epoch {}", pool_id, epoch)),
+            Err(error_message) => error(500, error_message),
+        }
+    }
+
+    fn block(&self, hash: &str) -> Response {
+        if hash.is_empty() || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            return error(400, format!("invalid block hash: {}", hash));
+        }
+        match self.query(|db| find_block(db, &BlockQuery::HashPrefix(hash))) {
+            Ok(Some(validation)) => ok(&validation),
+            Ok(None) => error(404, "block not found"),
+            Err(error_message) => error(500, error_message),
+        }
+    }
+
+    fn pool_blocks(&self, pool_id: &str) -> Response {
+        match self.query(|db| pool_blocks(db, pool_id)) {
+            Ok(Some(pool_blocks)) => ok(&pool_blocks),
+            Ok(None) => error(404, format!("No blocks found for pool {}", pool_id)),
+            Err(error_message) => error(500, error_message),
+        }
+    }
+
+    fn query<T, F>(&self, query: F) -> Result<T, String>
+    where
+        F: FnOnce(&Connection) -> Result<T, String>,
+    {
+        let db = Connection::open_with_flags(&self.db, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .map_err(|error| error.to_string())?;
+        let result = query(&db);
+        if let Err(error) = db.close() {
+            return Err(format!("db close error: {}", error.1));
+        }
+        result
+    }
+}
+
+fn ok<T: Serialize>(body: &T) -> Response {
+    match serde_json::to_string_pretty(body) {
+        Ok(json) => (200, json),
+        Err(error_message) => error(500, error_message),
+    }
+}
+
+fn error<T: Display>(status_code: u16, error_message: T) -> Response {
+    let body = ApiError {
+        status: "error".to_string(),
+        error_message: error_message.to_string(),
+    };
+    (status_code, serde_json::to_string_pretty(&body).unwrap())
+}
+
+fn reason_phrase(status_code: u16) -> &'static str {
+    match status_code {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    }
+}
+
+pub(crate) fn serve(bind: &str, api: Api) {
+    let listener = match TcpListener::bind(bind) {
+        Ok(listener) => listener,
+        Err(error) => {
+            handle_error(format!("cannot listen on {}: {}", bind, error));
+            return;
+        }
+    };
+    info!("Serving the api on http://{}", bind);
+    let api = Arc::new(api);
+    let (sender, receiver) = mpsc::sync_channel::<TcpStream>(CONNECTION_THREADS);
+    let receiver = Arc::new(Mutex::new(receiver));
+    for _ in 0..CONNECTION_THREADS {
+        let api = api.clone();
+        let receiver = receiver.clone();
+        thread::spawn(move || loop {
+            let stream = match receiver.lock().unwrap().recv() {
+                Ok(stream) => stream,
+                Err(_) => return,
+            };
+            if let Err(error) = handle_connection(&api, stream) {
+                debug!("connection error: {}", error);
+            }
+        });
+    }
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => match sender.try_send(stream) {
+                Ok(()) => {}
+                Err(TrySendError::Full(stream)) => {
+                    warn!("too many connections, refusing one");
+                    if let Err(error) = write_response(stream, error(503, "too many connections")) {
+                        debug!("connection error: {}", error);
+                    }
+                }
+                Err(TrySendError::Disconnected(_)) => return,
+            },
+            Err(error) => warn!("accept error: {}", error),
+        }
+    }
+}
+
+// One request per connection, the connection is closed after the response
+fn handle_connection(api: &Api, stream: TcpStream) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(REQUEST_TIMEOUT_SECS)))?;
+    let mut reader = BufReader::new(stream.try_clone()?.take(MAX_REQUEST_BYTES));
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Skip the headers, a GET has no body
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => api.handle(method, target),
+        _ => error(400, "bad request"),
+    };
+    write_response(stream, response)
+}
+
+fn write_response(mut stream: TcpStream, (status_code, body): Response) -> std::io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status_code,
+        reason_phrase(status_code),
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
@@ -0,0 +1,340 @@
+use std::fs::{self, File};
+use std::io::{self, stdout, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use blake2b_simd::{Params, State};
+use chrono::{SecondsFormat, Utc};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::info;
+use rusqlite::{Connection, OptionalExtension, NO_PARAMS};
+use serde::{Deserialize, Serialize};
+
+use crate::nodeclient::leaderlog::handle_error;
+use crate::nodeclient::sqlite::SqLiteBlockStore;
+
+// Bump when the layout of the archive changes
+const SNAPSHOT_VERSION: u32 = 1;
+const MANIFEST_ENTRY: &str = "manifest.json";
+const DB_ENTRY: &str = "cncli.db";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SnapshotTip {
+    pub block_number: i64,
+    pub slot_number: i64,
+    pub hash: String,
+}
+
+/// What a snapshot archive holds, stored in it next to the db
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SnapshotManifest {
+    pub snapshot_version: u32,
+    pub network_magic: u32,
+    pub db_version: i64,
+    pub tip: SnapshotTip,
+    pub created_at: String,
+    pub db_size: u64,
+    /// blake2b-256 of the db file in hex
+    pub checksum: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SnapshotResult {
+    status: String,
+    snapshot: String,
+    db: String,
+    #[serde(flatten)]
+    manifest: SnapshotManifest,
+}
+
+// Hashes everything written through it
+struct HashingWriter<W: Write> {
+    inner: W,
+    state: State,
+    size: u64,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.state.update(&buf[..written]);
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn hashing_writer<W: Write>(inner: W) -> HashingWriter<W> {
+    HashingWriter {
+        inner,
+        state: Params::new().hash_length(32).to_state(),
+        size: 0,
+    }
+}
+
+pub(crate) fn create(db_path: &Path, snapshot_path: &Path, network_magic: u32) {
+    if !db_path.exists() {
+        handle_error("database not found!");
+        return;
+    }
+    match create_snapshot(db_path, snapshot_path, network_magic) {
+        Ok(manifest) => print_result(snapshot_path, db_path, manifest),
+        Err(error) => handle_error(error),
+    }
+}
+
+pub(crate) fn restore(snapshot_path: &Path, db_path: &Path, network_magic: Option<u32>, force: bool) {
+    if !snapshot_path.exists() {
+        handle_error("snapshot not found!");
+        return;
+    }
+    match restore_snapshot(snapshot_path, db_path, network_magic, force) {
+        Ok(manifest) => print_result(snapshot_path, db_path, manifest),
+        Err(error) => handle_error(error),
+    }
+}
+
+fn print_result(snapshot_path: &Path, db_path: &Path, manifest: SnapshotManifest) {
+    serde_json::ser::to_writer_pretty(
+        &mut stdout(),
+        &SnapshotResult {
+            status: "ok".to_string(),
+            snapshot: snapshot_path.to_string_lossy().to_string(),
+            db: db_path.to_string_lossy().to_string(),
+            manifest,
+        },
+    )
+    .unwrap();
+}
+
+/// Write a gzipped tar of a consistent copy of the db and a manifest describing it. The db is copied
+/// with VACUUM INTO so a running sync and its WAL file don't need to be stopped.
+pub fn create_snapshot(db_path: &Path, snapshot_path: &Path, network_magic: u32) -> Result<SnapshotManifest, String> {
+    let copy_path = temp_path(snapshot_path, "db");
+    let _ = fs::remove_file(&copy_path);
+    let result = write_snapshot(db_path, &copy_path, snapshot_path, network_magic);
+    let _ = fs::remove_file(&copy_path);
+    if result.is_err() {
+        let _ = fs::remove_file(snapshot_path);
+    }
+    result
+}
+
+fn write_snapshot(
+    db_path: &Path,
+    copy_path: &Path,
+    snapshot_path: &Path,
+    network_magic: u32,
+) -> Result<SnapshotManifest, String> {
+    info!("Copying {} ...", db_path.display());
+    let db = Connection::open(db_path).map_err(|error| error.to_string())?;
+    db.execute("VACUUM INTO ?1", &[&copy_path.to_string_lossy().to_string()])
+        .map_err(|error| format!("db copy error: {}", error))?;
+    if let Err(error) = db.close() {
+        return Err(format!("db close error: {}", error.1));
+    }
+
+    // Describe the copy rather than the live db, which may have moved on since
+    let copy = Connection::open(copy_path).map_err(|error| error.to_string())?;
+    let (db_version, tip) = read_db_version_and_tip(&copy)?;
+    if let Err(error) = copy.close() {
+        return Err(format!("db close error: {}", error.1));
+    }
+    let tip = tip.ok_or_else(|| "database has no blocks to snapshot".to_string())?;
+
+    let mut hasher = hashing_writer(io::sink());
+    io::copy(
+        &mut File::open(copy_path).map_err(|error| error.to_string())?,
+        &mut hasher,
+    )
+    .map_err(|error| error.to_string())?;
+    let manifest = SnapshotManifest {
+        snapshot_version: SNAPSHOT_VERSION,
+        network_magic,
+        db_version,
+        tip,
+        created_at: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+        db_size: hasher.size,
+        checksum: hasher.state.finalize().to_hex().to_string(),
+    };
+
+    info!(
+        "Compressing {} bytes to {} ...",
+        manifest.db_size,
+        snapshot_path.display()
+    );
+    let file = File::create(snapshot_path).map_err(|error| format!("{}: {}", snapshot_path.display(), error))?;
+    let mut archive = tar::Builder::new(GzEncoder::new(BufWriter::new(file), Compression::default()));
+    let manifest_json = serde_json::to_vec_pretty(&manifest).map_err(|error| error.to_string())?;
+    let mut header = tar::Header::new_gnu();
+    header.set_size(manifest_json.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(Utc::now().timestamp() as u64);
+    header.set_cksum();
+    archive
+        .append_data(&mut header, MANIFEST_ENTRY, &manifest_json[..])
+        .map_err(|error| error.to_string())?;
+    archive
+        .append_path_with_name(copy_path, DB_ENTRY)
+        .map_err(|error| error.to_string())?;
+    archive
+        .into_inner()
+        .and_then(|encoder| encoder.finish())
+        .and_then(|mut writer| writer.flush())
+        .map_err(|error| error.to_string())?;
+    Ok(manifest)
+}
+
+/// Unpack a snapshot over the db after checking its checksum, its integrity and that its tip and network
+/// magic are what the manifest says. An existing db is only replaced when forced.
+pub fn restore_snapshot(
+    snapshot_path: &Path,
+    db_path: &Path,
+    network_magic: Option<u32>,
+    force: bool,
+) -> Result<SnapshotManifest, String> {
+    if db_path.exists() && !force {
+        return Err(format!(
+            "{} already exists, use --force to replace it",
+            db_path.display()
+        ));
+    }
+    let restore_path = temp_path(db_path, "restore");
+    let _ = fs::remove_file(&restore_path);
+    let result = unpack_snapshot(snapshot_path, &restore_path, network_magic);
+    match result {
+        Ok(manifest) => {
+            // Swap the restored db in with a single rename so the old one stays in place if it fails
+            fs::rename(&restore_path, db_path).map_err(|error| format!("{}: {}", db_path.display(), error))?;
+            // Stale WAL files of the old db must not be applied to the restored one
+            for path in [temp_path(db_path, "wal"), temp_path(db_path, "shm")].iter() {
+                if path.exists() {
+                    fs::remove_file(path).map_err(|error| format!("{}: {}", path.display(), error))?;
+                }
+            }
+            // Upgrade a snapshot made by an older cncli
+            SqLiteBlockStore::new(db_path).map_err(|error| format!("db error: {}", error))?;
+            Ok(manifest)
+        }
+        Err(error) => {
+            let _ = fs::remove_file(&restore_path);
+            Err(error)
+        }
+    }
+}
+
+fn unpack_snapshot(
+    snapshot_path: &Path,
+    restore_path: &Path,
+    network_magic: Option<u32>,
+) -> Result<SnapshotManifest, String> {
+    let file = File::open(snapshot_path).map_err(|error| format!("{}: {}", snapshot_path.display(), error))?;
+    let mut archive = tar::Archive::new(GzDecoder::new(BufReader::new(file)));
+    let mut manifest: Option<SnapshotManifest> = None;
+    let mut unpacked: Option<(u64, String)> = None;
+    for entry in archive
+        .entries()
+        .map_err(|error| format!("invalid snapshot: {}", error))?
+    {
+        let mut entry = entry.map_err(|error| format!("invalid snapshot: {}", error))?;
+        let path = entry
+            .path()
+            .map_err(|error| format!("invalid snapshot: {}", error))?
+            .to_string_lossy()
+            .to_string();
+        if path == MANIFEST_ENTRY {
+            let mut manifest_json = String::new();
+            entry
+                .read_to_string(&mut manifest_json)
+                .map_err(|error| format!("invalid snapshot: {}", error))?;
+            manifest = Some(
+                serde_json::from_str(&manifest_json)
+                    .map_err(|error| format!("invalid snapshot manifest: {}", error))?,
+            );
+        } else if path == DB_ENTRY {
+            info!("Unpacking {} ...", snapshot_path.display());
+            let file = File::create(restore_path).map_err(|error| format!("{}: {}", restore_path.display(), error))?;
+            let mut hasher = hashing_writer(BufWriter::new(file));
+            io::copy(&mut entry, &mut hasher).map_err(|error| format!("invalid snapshot: {}", error))?;
+            hasher.flush().map_err(|error| error.to_string())?;
+            unpacked = Some((hasher.size, hasher.state.finalize().to_hex().to_string()));
+        }
+    }
+
+    let manifest = manifest.ok_or_else(|| "invalid snapshot: no manifest".to_string())?;
+    if manifest.snapshot_version > SNAPSHOT_VERSION {
+        return Err(format!(
+            "snapshot version {} is newer than this cncli supports",
+            manifest.snapshot_version
+        ));
+    }
+    let (db_size, checksum) = unpacked.ok_or_else(|| "invalid snapshot: no database".to_string())?;
+    if db_size != manifest.db_size || checksum != manifest.checksum {
+        return Err(format!(
+            "snapshot checksum mismatch: expected {}, got {}",
+            manifest.checksum, checksum
+        ));
+    }
+    if let Some(network_magic) = network_magic {
+        if network_magic != manifest.network_magic {
+            return Err(format!(
+                "snapshot is for network magic {}, not {}",
+                manifest.network_magic, network_magic
+            ));
+        }
+    }
+
+    let db = Connection::open(restore_path).map_err(|error| error.to_string())?;
+    let integrity: String = db
+        .query_row("PRAGMA integrity_check", NO_PARAMS, |row| row.get(0))
+        .map_err(|error| error.to_string())?;
+    if integrity != "ok" {
+        return Err(format!("snapshot database is corrupt: {}", integrity));
+    }
+    let (db_version, tip) = read_db_version_and_tip(&db)?;
+    if let Err(error) = db.close() {
+        return Err(format!("db close error: {}", error.1));
+    }
+    if db_version != manifest.db_version || tip.as_ref() != Some(&manifest.tip) {
+        return Err("snapshot database does not match its manifest".to_string());
+    }
+    Ok(manifest)
+}
+
+fn read_db_version_and_tip(db: &Connection) -> Result<(i64, Option<SnapshotTip>), String> {
+    let db_version: i64 = db
+        .query_row("SELECT version FROM db_version", NO_PARAMS, |row| row.get(0))
+        .map_err(|error| format!("not a cncli database: {}", error))?;
+    Ok((db_version, read_tip(db)?))
+}
+
+/// The last block on the chain, None for an empty db
+pub(crate) fn read_tip(db: &Connection) -> Result<Option<SnapshotTip>, String> {
+    db.query_row(
+        "SELECT block_number, slot_number, hash FROM chain WHERE orphaned = 0 ORDER BY slot_number DESC LIMIT 1",
+        NO_PARAMS,
+        |row| {
+            Ok(SnapshotTip {
+                block_number: row.get(0)?,
+                slot_number: row.get(1)?,
+                hash: row.get(2)?,
+            })
+        },
+    )
+    .optional()
+    .map_err(|error| error.to_string())
+}
+
+// A file next to path, e.g. cncli.db-wal for "wal"
+fn temp_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(format!("-{}", suffix));
+    path.with_file_name(file_name)
+}
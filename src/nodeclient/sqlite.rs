@@ -2,20 +2,29 @@ use std::io;
 
 use blake2b_simd::Params;
 use cardano_ouroboros_network::{BlockHeader, BlockStore};
-use log::{debug, info};
+use log::{debug, error, info, warn};
 use rusqlite::{named_params, Connection, Error, NO_PARAMS};
 use std::path::Path;
+use std::time::Duration;
+
+use crate::nodeclient::headercheck::{HeaderCheckMode, HeaderVerifier};
+use crate::nodeclient::webhook::WebhookNotifier;
 
 pub struct SqLiteBlockStore {
     pub db: Connection,
+    header_verifier: Option<HeaderVerifier>,
+    webhook_notifier: Option<WebhookNotifier>,
 }
 
 impl SqLiteBlockStore {
-    const DB_VERSION: i64 = 3;
+    pub(crate) const DB_VERSION: i64 = 7;
+    const BUSY_TIMEOUT_SECS: u64 = 5;
 
     pub fn new(db_path: &Path) -> Result<SqLiteBlockStore, Error> {
         debug!("Opening database");
         let mut db = Connection::open(db_path)?;
+        // Wait for other cncli processes writing to the same db instead of failing right away
+        db.busy_timeout(Duration::from_secs(Self::BUSY_TIMEOUT_SECS))?;
         db.execute_batch("PRAGMA journal_mode=WAL")?;
 
         let tx = db.transaction()?;
@@ -153,6 +162,66 @@ impl SqLiteBlockStore {
                 }
             }
 
+            if version < 4 {
+                info!("Upgrade database to version 4...");
+                // The unknown_* columns are the operational certificate fields of the block header
+                tx.execute("ALTER TABLE chain RENAME COLUMN unknown_0 TO opcert_counter", NO_PARAMS)?;
+                tx.execute(
+                    "ALTER TABLE chain RENAME COLUMN unknown_1 TO opcert_kes_period",
+                    NO_PARAMS,
+                )?;
+                tx.execute(
+                    "ALTER TABLE chain RENAME COLUMN unknown_2 TO opcert_signature",
+                    NO_PARAMS,
+                )?;
+            }
+
+            if version < 5 {
+                info!("Upgrade database to version 5...");
+                // Pooltool payloads that failed to send and are waiting to be retried
+                tx.execute(
+                    "CREATE TABLE IF NOT EXISTS outbox (\
+                    id INTEGER PRIMARY KEY AUTOINCREMENT, \
+                    endpoint TEXT NOT NULL, \
+                    pool_id TEXT NOT NULL, \
+                    payload TEXT NOT NULL, \
+                    attempts INTEGER NOT NULL, \
+                    last_error TEXT NOT NULL, \
+                    created_at INTEGER NOT NULL, \
+                    next_attempt_at INTEGER NOT NULL \
+                )",
+                    NO_PARAMS,
+                )?;
+            }
+
+            if version < 6 {
+                info!("Upgrade database to version 6...");
+                // Results of ping --watch
+                tx.execute(
+                    "CREATE TABLE IF NOT EXISTS ping_history (\
+                    id INTEGER PRIMARY KEY AUTOINCREMENT, \
+                    host TEXT NOT NULL, \
+                    port INTEGER NOT NULL, \
+                    status TEXT NOT NULL, \
+                    connect_duration_ms INTEGER, \
+                    duration_ms INTEGER, \
+                    error_message TEXT, \
+                    created_at INTEGER NOT NULL \
+                )",
+                    NO_PARAMS,
+                )?;
+                tx.execute(
+                    "CREATE INDEX IF NOT EXISTS idx_ping_history_created_at ON ping_history(created_at)",
+                    NO_PARAMS,
+                )?;
+            }
+
+            if version < 7 {
+                info!("Upgrade database to version 7...");
+                // Why sync --verify-headers flag found a header invalid
+                tx.execute("ALTER TABLE chain ADD COLUMN header_error TEXT", NO_PARAMS)?;
+            }
+
             // Update the db version now that we've upgraded the user's database fully
             if version < 0 {
                 tx.execute(
@@ -165,12 +234,76 @@ impl SqLiteBlockStore {
         }
         tx.commit()?;
 
-        Ok(SqLiteBlockStore { db })
+        Ok(SqLiteBlockStore {
+            db,
+            header_verifier: None,
+            webhook_notifier: None,
+        })
+    }
+
+    /// Check each header before it is saved
+    pub fn with_header_verifier(mut self, header_verifier: HeaderVerifier) -> SqLiteBlockStore {
+        self.header_verifier = Some(header_verifier);
+        self
+    }
+
+    /// Announce the blocks of our pools as they are saved
+    pub fn with_webhook_notifier(mut self, webhook_notifier: WebhookNotifier) -> SqLiteBlockStore {
+        self.webhook_notifier = Some(webhook_notifier);
+        self
+    }
+
+    // Why each of the pending headers is invalid, if it is. Fails on the first invalid header when
+    // rejecting them, with the number of valid headers before it.
+    fn verify_headers(&mut self, pending_blocks: &[BlockHeader]) -> Result<Vec<Option<String>>, (usize, String)> {
+        let header_verifier = match &mut self.header_verifier {
+            Some(header_verifier) => header_verifier,
+            None => return Ok(vec![None; pending_blocks.len()]),
+        };
+        let mut header_errors: Vec<Option<String>> = vec![];
+        for block in pending_blocks.iter() {
+            match header_verifier.verify(&self.db, block) {
+                Ok(_) => header_errors.push(None),
+                Err(header_error) => {
+                    let message = format!(
+                        "Invalid header {} at block {}: {}",
+                        hex::encode(&block.hash),
+                        block.block_number,
+                        header_error
+                    );
+                    if header_verifier.mode == HeaderCheckMode::Reject {
+                        return Err((header_errors.len(), message));
+                    }
+                    warn!("{}", message);
+                    header_errors.push(Some(header_error));
+                }
+            }
+        }
+        Ok(header_errors)
+    }
+
+    fn save_blocks(
+        &mut self,
+        blocks: &mut Vec<BlockHeader>,
+        header_errors: Vec<Option<String>>,
+        network_magic: u32,
+    ) -> io::Result<()> {
+        let minted = match &self.webhook_notifier {
+            Some(webhook_notifier) => webhook_notifier.minted(blocks),
+            None => vec![],
+        };
+        self.sql_save_block(blocks, header_errors, network_magic)
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "Database error!"))?;
+        if let Some(webhook_notifier) = &self.webhook_notifier {
+            webhook_notifier.blocks_minted(minted);
+        }
+        Ok(())
     }
 
     fn sql_save_block(
         &mut self,
         pending_blocks: &mut Vec<BlockHeader>,
+        header_errors: Vec<Option<String>>,
         network_magic: u32,
     ) -> Result<(), rusqlite::Error> {
         let db = &mut self.db;
@@ -257,11 +390,12 @@ impl SqLiteBlockStore {
             block_size, \
             block_body_hash, \
             pool_opcert, \
-            unknown_0, \
-            unknown_1, \
-            unknown_2, \
+            opcert_counter, \
+            opcert_kes_period, \
+            opcert_signature, \
             protocol_major_version, \
-            protocol_minor_version) \
+            protocol_minor_version, \
+            header_error) \
             VALUES (\
             :block_number, \
             :slot_number, \
@@ -278,14 +412,15 @@ impl SqLiteBlockStore {
             :block_size, \
             :block_body_hash, \
             :pool_opcert, \
-            :unknown_0, \
-            :unknown_1, \
-            :unknown_2, \
+            :opcert_counter, \
+            :opcert_kes_period, \
+            :opcert_signature, \
             :protocol_major_version, \
-            :protocol_minor_version)",
+            :protocol_minor_version, \
+            :header_error)",
             )?;
 
-            for block in pending_blocks.drain(..) {
+            for (block, header_error) in pending_blocks.drain(..).zip(header_errors) {
                 // Set any necessary blocks as orphans
                 let orphan_num = orphan_stmt.execute(&[&block.block_number])?;
 
@@ -326,32 +461,8 @@ impl SqLiteBlockStore {
                         .unwrap()
                     };
                 }
-                // blake2b hash of eta_vrf_0
-                let mut block_eta_v = Params::new()
-                    .hash_length(32)
-                    .to_state()
-                    .update(&*block.eta_vrf_0)
-                    .finalize()
-                    .as_bytes()
-                    .to_vec();
-                prev_eta_v.append(&mut block_eta_v);
-                // blake2b hash of prev_eta_v + block_eta_v
-                prev_eta_v = Params::new()
-                    .hash_length(32)
-                    .to_state()
-                    .update(&*prev_eta_v)
-                    .finalize()
-                    .as_bytes()
-                    .to_vec();
-
-                // blake2b 224 of node_vkey is the pool_id
-                let pool_id = Params::new()
-                    .hash_length(28)
-                    .to_state()
-                    .update(&*block.node_vkey)
-                    .finalize()
-                    .as_bytes()
-                    .to_vec();
+                prev_eta_v = next_eta_v(&prev_eta_v, &block.eta_vrf_0);
+                let pool_id = pool_id(&block.node_vkey);
 
                 insert_stmt.execute_named(named_params! {
                     ":block_number" : block.block_number,
@@ -369,11 +480,12 @@ impl SqLiteBlockStore {
                     ":block_size" : block.block_size,
                     ":block_body_hash" : hex::encode(block.block_body_hash),
                     ":pool_opcert" : hex::encode(block.pool_opcert),
-                    ":unknown_0" : block.unknown_0,
-                    ":unknown_1" : block.unknown_1,
-                    ":unknown_2" : hex::encode(block.unknown_2),
+                    ":opcert_counter" : block.unknown_0,
+                    ":opcert_kes_period" : block.unknown_1,
+                    ":opcert_signature" : hex::encode(block.unknown_2),
                     ":protocol_major_version" : block.protocol_major_version,
                     ":protocol_minor_version" : block.protocol_minor_version,
+                    ":header_error" : header_error,
                 })?;
             }
         }
@@ -383,12 +495,62 @@ impl SqLiteBlockStore {
     }
 }
 
+/// The rolling nonce after a block: blake2b hash of the previous eta_v + the blake2b hash of the block's eta_vrf_0
+pub fn next_eta_v(prev_eta_v: &[u8], eta_vrf_0: &[u8]) -> Vec<u8> {
+    let mut eta_v = prev_eta_v.to_vec();
+    eta_v.extend_from_slice(
+        Params::new()
+            .hash_length(32)
+            .to_state()
+            .update(eta_vrf_0)
+            .finalize()
+            .as_bytes(),
+    );
+    Params::new()
+        .hash_length(32)
+        .to_state()
+        .update(&eta_v)
+        .finalize()
+        .as_bytes()
+        .to_vec()
+}
+
+/// blake2b 224 of node_vkey is the pool_id
+pub fn pool_id(node_vkey: &[u8]) -> Vec<u8> {
+    Params::new()
+        .hash_length(28)
+        .to_state()
+        .update(node_vkey)
+        .finalize()
+        .as_bytes()
+        .to_vec()
+}
+
 impl BlockStore for SqLiteBlockStore {
-    fn save_block(&mut self, mut pending_blocks: &mut Vec<BlockHeader>, network_magic: u32) -> io::Result<()> {
-        match self.sql_save_block(&mut pending_blocks, network_magic) {
-            Ok(_) => Ok(()),
-            Err(_) => Err(io::Error::new(io::ErrorKind::Other, "Database error!")),
+    fn save_block(&mut self, pending_blocks: &mut Vec<BlockHeader>, network_magic: u32) -> io::Result<()> {
+        while !pending_blocks.is_empty() {
+            // Save the blocks of an epoch before checking the headers of the next one against its nonce
+            let count = match &self.header_verifier {
+                Some(header_verifier) => header_verifier.epoch_len(pending_blocks),
+                None => pending_blocks.len(),
+            };
+            let mut blocks: Vec<BlockHeader> = pending_blocks.drain(..count).collect();
+            match self.verify_headers(&blocks) {
+                Ok(header_errors) => self.save_blocks(&mut blocks, header_errors, network_magic)?,
+                Err((valid, error)) => {
+                    // Keep the valid headers before the rejected one and have sync stop
+                    blocks.truncate(valid);
+                    self.save_blocks(&mut blocks, vec![None; valid], network_magic)?;
+                    pending_blocks.clear();
+                    error!("{}, stopping sync", error);
+                    if let Some(header_verifier) = &self.header_verifier {
+                        header_verifier.reject(error.clone());
+                    }
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, error));
+                }
+            }
         }
+        Ok(())
     }
 
     fn load_blocks(&mut self) -> Option<Vec<(i64, Vec<u8>)>> {
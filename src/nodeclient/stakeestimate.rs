@@ -0,0 +1,222 @@
+use std::io::stdout;
+use std::path::Path;
+
+use log::debug;
+use rusqlite::{Connection, Error};
+use serde::Serialize;
+
+use crate::nodeclient::leaderlog::{
+    get_first_slot_of_epoch, get_first_slot_of_epoch_number, get_tip_slot_number, handle_error, read_byron_genesis,
+    read_shelley_genesis,
+};
+use crate::nodeclient::OutputFormat;
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct StakeEstimate {
+    status: String,
+    start_epoch: i64,
+    end_epoch: i64,
+    slots: i64,
+    total_blocks: i64,
+    active_slots_coeff: f64,
+    confidence: f64,
+    pools: Vec<PoolEstimate>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PoolEstimate {
+    rank: i64,
+    pool_id: String,
+    blocks: i64,
+    block_share: f64,
+    sigma: f64,
+    sigma_low: f64,
+    sigma_high: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stake: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stake_low: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stake_high: Option<u64>,
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn stake_estimate(
+    db_path: &Path,
+    byron_genesis: &Path,
+    shelley_genesis: &Path,
+    epochs: i64,
+    confidence: f64,
+    active_stake: &Option<u64>,
+    limit: &Option<usize>,
+    output_format: &OutputFormat,
+) {
+    if !db_path.exists() {
+        handle_error("database not found!");
+        return;
+    }
+
+    match calculate_stake_estimate(
+        db_path,
+        byron_genesis,
+        shelley_genesis,
+        epochs,
+        confidence,
+        active_stake,
+        limit,
+    ) {
+        Ok(stake_estimate) => match output_format {
+            OutputFormat::Json => {
+                serde_json::ser::to_writer_pretty(&mut stdout(), &stake_estimate).unwrap();
+            }
+            OutputFormat::Table => print_stake_estimate_table(&stake_estimate),
+        },
+        Err(error) => handle_error(error),
+    }
+}
+
+fn calculate_stake_estimate(
+    db_path: &Path,
+    byron_genesis: &Path,
+    shelley_genesis: &Path,
+    epochs: i64,
+    confidence: f64,
+    active_stake: &Option<u64>,
+    limit: &Option<usize>,
+) -> Result<StakeEstimate, String> {
+    if epochs < 1 {
+        return Err("epochs must be at least 1!".to_string());
+    }
+    if confidence <= 0.0 || confidence >= 1.0 {
+        return Err("confidence must be between 0 and 1!".to_string());
+    }
+
+    let byron = read_byron_genesis(byron_genesis).map_err(|error| error.to_string())?;
+    debug!("{:?}", byron);
+    let shelley = read_shelley_genesis(shelley_genesis).map_err(|error| error.to_string())?;
+    debug!("{:?}", shelley);
+
+    let db = Connection::open(db_path).map_err(|error| error.to_string())?;
+    let tip_slot_number = get_tip_slot_number(&db).map_err(|error| error.to_string())?;
+    debug!("tip_slot_number: {}", tip_slot_number);
+
+    let (end_epoch, _) = get_first_slot_of_epoch(&byron, &shelley, tip_slot_number);
+    let start_epoch = end_epoch - epochs + 1;
+    let start_slot = get_first_slot_of_epoch_number(&byron, &shelley, start_epoch);
+    let end_slot = tip_slot_number + 1;
+    let slots = end_slot - start_slot;
+    debug!("start_slot: {}, end_slot: {}", start_slot, end_slot);
+
+    let pool_blocks = get_pool_block_counts(&db, start_slot, end_slot).map_err(|error| error.to_string())?;
+    if let Err(error) = db.close() {
+        return Err(format!("db close error: {}", error.1));
+    }
+    if pool_blocks.is_empty() {
+        return Err(format!("No blocks found from epoch {} to {}", start_epoch, end_epoch));
+    }
+
+    let active_slots_coeff = shelley.active_slots_coeff;
+    let z = z_score(confidence);
+    let total_blocks: i64 = pool_blocks.iter().map(|(_, blocks)| blocks).sum();
+    let pools = pool_blocks
+        .iter()
+        .take(limit.unwrap_or(usize::MAX))
+        .enumerate()
+        .map(|(index, (pool_id, blocks))| {
+            let (p_low, p_high) = wilson_interval(*blocks, slots, z);
+            let sigma = sigma_from_leader_probability(*blocks as f64 / slots as f64, active_slots_coeff);
+            let sigma_low = sigma_from_leader_probability(p_low, active_slots_coeff);
+            let sigma_high = sigma_from_leader_probability(p_high, active_slots_coeff);
+            PoolEstimate {
+                rank: index as i64 + 1,
+                pool_id: pool_id.clone(),
+                blocks: *blocks,
+                // A percentage like the shares in versions, sigma stays a fraction as in leaderlog
+                block_share: (*blocks as f64 / total_blocks as f64 * 1_000_000.0).round() / 10_000.0,
+                sigma,
+                sigma_low,
+                sigma_high,
+                stake: active_stake.map(|active_stake| (sigma * active_stake as f64) as u64),
+                stake_low: active_stake.map(|active_stake| (sigma_low * active_stake as f64) as u64),
+                stake_high: active_stake.map(|active_stake| (sigma_high * active_stake as f64) as u64),
+            }
+        })
+        .collect();
+
+    Ok(StakeEstimate {
+        status: "ok".to_string(),
+        start_epoch,
+        end_epoch,
+        slots,
+        total_blocks,
+        active_slots_coeff,
+        confidence,
+        pools,
+    })
+}
+
+// Leader slots per pool, including orphaned blocks since those slots were still won
+fn get_pool_block_counts(db: &Connection, start_slot: i64, end_slot: i64) -> Result<Vec<(String, i64)>, Error> {
+    let mut stmt = db.prepare(
+        "SELECT pool_id, COUNT(DISTINCT slot_number) AS blocks FROM chain \
+        WHERE slot_number >= ?1 AND slot_number < ?2 AND pool_id != '' \
+        GROUP BY pool_id ORDER BY blocks DESC, pool_id ASC",
+    )?;
+    let pool_blocks = stmt
+        .query_map(&[&start_slot, &end_slot], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<Vec<(String, i64)>, Error>>();
+    pool_blocks
+}
+
+/// Invert phi(sigma) = 1 - (1 - f)^sigma, the probability of leading any given slot, to get sigma
+pub fn sigma_from_leader_probability(p: f64, active_slots_coeff: f64) -> f64 {
+    if p <= 0.0 {
+        return 0.0;
+    }
+    if p >= active_slots_coeff {
+        return 1.0;
+    }
+    (1.0 - p).ln() / (1.0 - active_slots_coeff).ln()
+}
+
+/// Wilson score interval for a binomial proportion of successes out of trials
+pub fn wilson_interval(successes: i64, trials: i64, z: f64) -> (f64, f64) {
+    let n = trials as f64;
+    let p = successes as f64 / n;
+    let z2 = z * z;
+    let center = (p + z2 / (2.0 * n)) / (1.0 + z2 / n);
+    let margin = (z / (1.0 + z2 / n)) * (p * (1.0 - p) / n + z2 / (4.0 * n * n)).sqrt();
+    ((center - margin).max(0.0), (center + margin).min(1.0))
+}
+
+// Two-sided z score for a confidence level (Abramowitz and Stegun 26.2.23)
+fn z_score(confidence: f64) -> f64 {
+    let q = (1.0 - confidence) / 2.0;
+    let t = (-2.0 * q.ln()).sqrt();
+    t - (2.515517 + 0.802853 * t + 0.010328 * t * t) / (1.0 + 1.432788 * t + 0.189269 * t * t + 0.001308 * t * t * t)
+}
+
+fn print_stake_estimate_table(stake_estimate: &StakeEstimate) {
+    println!(
+        "Epochs {} - {}, slots: {}, blocks: {}, activeSlotsCoeff: {}, confidence: {}",
+        stake_estimate.start_epoch,
+        stake_estimate.end_epoch,
+        stake_estimate.slots,
+        stake_estimate.total_blocks,
+        stake_estimate.active_slots_coeff,
+        stake_estimate.confidence
+    );
+    println!();
+    println!(
+        "{:>5}  {:<56} {:>7} {:>9} {:>10} {:>10} {:>10}",
+        "Rank", "PoolId", "Blocks", "Share", "Sigma", "SigmaLow", "SigmaHigh"
+    );
+    for pool in stake_estimate.pools.iter() {
+        println!(
+            "{:>5}  {:<56} {:>7} {:>8.4}% {:>10.6} {:>10.6} {:>10.6}",
+            pool.rank, pool.pool_id, pool.blocks, pool.block_share, pool.sigma, pool.sigma_low, pool.sigma_high
+        );
+    }
+}
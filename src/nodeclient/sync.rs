@@ -1,65 +1,189 @@
-use std::path::Path;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
 use std::time::Duration;
 
 use async_std::task;
 use cardano_ouroboros_network::{
     mux,
     protocols::chainsync::{ChainSyncProtocol, Mode},
-    BlockHeader,
+    Agency, BlockHeader, Protocol,
 };
 use futures::{executor::block_on, try_join};
 use log::{error, info};
 
+use crate::nodeclient::headercheck::HeaderCheck;
+use crate::nodeclient::leaderlog::handle_error;
 use crate::nodeclient::pooltool;
+use crate::nodeclient::propagation::{Propagation, PropagationTracker};
+use crate::nodeclient::resolve;
 use crate::nodeclient::sqlite;
+use crate::nodeclient::webhook;
 use cardano_ouroboros_network::protocols::chainsync::Listener;
 
-struct SyncExit {}
+struct SyncExit {
+    webhook_notifier: Option<webhook::WebhookNotifier>,
+}
 
 impl Listener for SyncExit {
     fn handle_tip(&mut self, _msg_roll_forward: &BlockHeader) {
+        // Post the events still queued for the webhooks first, the worker thread dies with us
+        if let Some(webhook_notifier) = &self.webhook_notifier {
+            webhook_notifier.flush();
+        }
         info!("Exiting...");
         std::process::exit(0);
     }
 }
 
-pub(crate) fn sync(db: &Path, host: &str, port: u16, network_magic: u32, no_service: bool) {
+// Hands each tip to several listeners in order
+struct FanOut {
+    listeners: Vec<Box<dyn Listener>>,
+}
+
+impl Listener for FanOut {
+    fn handle_tip(&mut self, msg_roll_forward: &BlockHeader) {
+        for listener in self.listeners.iter_mut() {
+            listener.handle_tip(msg_roll_forward);
+        }
+    }
+}
+
+// Ends chain sync once the block store rejected a header, which the chain sync client itself ignores
+struct CheckedChainSync {
+    chain_sync: ChainSyncProtocol,
+    header_check: HeaderCheck,
+}
+
+impl Protocol for CheckedChainSync {
+    fn protocol_id(&self) -> u16 {
+        self.chain_sync.protocol_id()
+    }
+
+    fn result(&self) -> Result<String, String> {
+        match self.header_check.rejected() {
+            Some(error) => Err(error),
+            None => self.chain_sync.result(),
+        }
+    }
+
+    fn role(&self) -> Agency {
+        self.chain_sync.role()
+    }
+
+    fn agency(&self) -> Agency {
+        match self.header_check.rejected() {
+            Some(_) => Agency::None,
+            None => self.chain_sync.agency(),
+        }
+    }
+
+    fn state(&self) -> String {
+        self.chain_sync.state()
+    }
+
+    fn send_data(&mut self) -> Option<Vec<u8>> {
+        self.chain_sync.send_data()
+    }
+
+    fn receive_data(&mut self, data: Vec<u8>) {
+        self.chain_sync.receive_data(data)
+    }
+}
+
+pub(crate) fn sync(
+    db: &Path,
+    host: &str,
+    port: u16,
+    network_magic: u32,
+    no_service: bool,
+    webhook_config: &Option<PathBuf>,
+    header_check: Option<HeaderCheck>,
+) {
+    let webhook_notifier = match webhook_config {
+        Some(webhook_config) => {
+            match webhook::read_webhook_config(webhook_config)
+                .and_then(|webhook_config| webhook::WebhookNotifier::start(webhook_config, db))
+            {
+                Ok(webhook_notifier) => Some(webhook_notifier),
+                Err(error) => {
+                    handle_error(format!("webhook config error: {}", error));
+                    return;
+                }
+            }
+        }
+        None => None,
+    };
     block_on(async {
         loop {
-            // Retry to establish connection forever
-            let block_store = sqlite::SqLiteBlockStore::new(db).unwrap();
-            match mux::connection::connect(host, port).await {
-                Ok(channel) => match channel.handshake(network_magic).await {
-                    Ok(_) => {
-                        let chain_sync_protocol = if no_service {
-                            ChainSyncProtocol {
-                                mode: Mode::Sync,
-                                network_magic,
-                                store: Some(Box::new(block_store)),
-                                notify: Some(Box::new(SyncExit {})),
-                                ..Default::default()
+            // Retry to establish connection forever, trying each address of the host in turn
+            let addresses = match resolve::resolve(host, port) {
+                Ok(addresses) => addresses,
+                Err(error) => {
+                    error!("{}: {}", host, error);
+                    vec![]
+                }
+            };
+            for address in addresses.iter() {
+                let mut block_store = sqlite::SqLiteBlockStore::new(db).unwrap();
+                if let Some(header_check) = &header_check {
+                    block_store = block_store.with_header_verifier(header_check.verifier());
+                }
+                if let Some(webhook_notifier) = &webhook_notifier {
+                    block_store = block_store.with_webhook_notifier(webhook_notifier.clone());
+                }
+                match mux::connection::connect(&address.ip().to_string(), address.port()).await {
+                    Ok(channel) => match channel.handshake(network_magic).await {
+                        Ok(_) => {
+                            info!("Connected to {} ({})", host, address);
+                            let mut listeners: Vec<Box<dyn Listener>> = vec![];
+                            if let Some(webhook_notifier) = &webhook_notifier {
+                                listeners.push(Box::new(webhook_notifier.clone()));
                             }
-                        } else {
-                            ChainSyncProtocol {
+                            if no_service {
+                                listeners.push(Box::new(SyncExit {
+                                    webhook_notifier: webhook_notifier.clone(),
+                                }));
+                            }
+                            let chain_sync_protocol = ChainSyncProtocol {
                                 mode: Mode::Sync,
                                 network_magic,
                                 store: Some(Box::new(block_store)),
+                                notify: Some(Box::new(FanOut { listeners })),
                                 ..Default::default()
+                            };
+                            let result = match &header_check {
+                                Some(header_check) => {
+                                    channel
+                                        .execute(CheckedChainSync {
+                                            chain_sync: chain_sync_protocol,
+                                            header_check: header_check.clone(),
+                                        })
+                                        .await
+                                }
+                                None => channel.execute(chain_sync_protocol).await,
+                            };
+                            if let Some(error) = header_check.as_ref().and_then(HeaderCheck::rejected) {
+                                if let Some(webhook_notifier) = &webhook_notifier {
+                                    webhook_notifier.flush();
+                                }
+                                handle_error(format!("sync stopped, {}", error));
+                                return;
                             }
-                        };
-                        match try_join!(channel.execute(chain_sync_protocol),) {
-                            Ok(_) => {}
-                            Err(error) => {
+                            if let Err(error) = result {
                                 error!("{}", error);
                             }
+                            // Start over from the first address once a connection has been used
+                            break;
                         }
-                    }
+                        Err(error) => {
+                            error!("{}: {}", address, error);
+                        }
+                    },
                     Err(error) => {
-                        error!("{}", error);
+                        error!("{}: {:?}", address, error);
                     }
-                },
-                Err(error) => {
-                    error!("{:?}", error);
                 }
             }
 
@@ -68,53 +192,129 @@ pub(crate) fn sync(db: &Path, host: &str, port: u16, network_magic: u32, no_serv
     });
 }
 
+/// A pool we send tips to pooltool for and the relay we follow the chain through
+pub struct SendtipPool {
+    pub pool_name: String,
+    pub pool_id: String,
+    pub host: String,
+    pub port: u16,
+    pub network_magic: u32,
+    pub api_key: String,
+    pub node_version_sources: Vec<pooltool::NodeVersionSource>,
+}
+
+/// Group the pools by relay, the pools of a relay share a single connection to it
+pub fn group_by_relay(pools: Vec<SendtipPool>) -> BTreeMap<(String, u16, u32), Vec<SendtipPool>> {
+    let mut relays: BTreeMap<(String, u16, u32), Vec<SendtipPool>> = BTreeMap::new();
+    for pool in pools.into_iter() {
+        relays
+            .entry((pool.host.clone(), pool.port, pool.network_magic))
+            .or_default()
+            .push(pool);
+    }
+    relays
+}
+
 pub(crate) fn sendtip(
-    pool_name: String,
-    pool_id: String,
+    pools: Vec<SendtipPool>,
+    pooltool_client: pooltool::PooltoolClient,
+    db_path: &Path,
+    propagation: Option<Propagation>,
+) {
+    let propagation = propagation.map(Arc::new);
+    // A thread per relay rather than tasks on one async runtime: the listeners post to pooltool and
+    // write the outbox with blocking calls, which would stall every other relay on a shared executor
+    let mut handles: Vec<JoinHandle<_>> = vec![];
+    for ((host, port, network_magic), pools) in group_by_relay(pools).into_iter() {
+        let pooltool_client = pooltool_client.clone();
+        let db_path = db_path.to_path_buf();
+        let propagation = propagation.clone();
+        handles.push(thread::spawn(move || {
+            block_on(sendtip_relay(
+                host,
+                port,
+                network_magic,
+                pools,
+                pooltool_client,
+                &*db_path,
+                propagation,
+            ));
+        }));
+    }
+
+    for handle in handles {
+        handle.join().unwrap()
+    }
+}
+
+async fn sendtip_relay(
     host: String,
     port: u16,
-    api_key: String,
-    cardano_node_path: &Path,
+    network_magic: u32,
+    pools: Vec<SendtipPool>,
+    pooltool_client: pooltool::PooltoolClient,
+    db_path: &Path,
+    propagation: Option<Arc<Propagation>>,
 ) {
-    block_on(async {
-        loop {
-            let pooltool_notifier = pooltool::PoolToolNotifier {
-                pool_name: pool_name.clone(),
-                pool_id: pool_id.clone(),
-                api_key: api_key.clone(),
-                cardano_node_path: cardano_node_path.to_path_buf(),
-                ..Default::default()
-            };
-            match mux::connection::connect(&*host, port).await {
-                Ok(channel) => {
-                    match channel.handshake(764824073).await {
-                        Ok(_) => {
-                            match try_join!(channel.execute({
-                                ChainSyncProtocol {
-                                    mode: Mode::SendTip,
-                                    network_magic: 764824073, // hardcoded to mainnet for pooltool
-                                    notify: Some(Box::new(pooltool_notifier)),
-                                    ..Default::default()
-                                }
-                            }),)
-                            {
-                                Ok(_) => {}
-                                Err(error) => {
-                                    error!("{}", error);
-                                }
-                            }
+    loop {
+        // Retry to establish connection forever, trying each address of the relay in turn
+        let addresses = match resolve::resolve(&host, port) {
+            Ok(addresses) => addresses,
+            Err(error) => {
+                error!("{}: {}", host, error);
+                vec![]
+            }
+        };
+        for address in addresses.iter() {
+            match mux::connection::connect(&address.ip().to_string(), address.port()).await {
+                Ok(channel) => match channel.handshake(network_magic).await {
+                    Ok(_) => {
+                        info!("Connected to {} ({})", host, address);
+                        let mut listeners: Vec<Box<dyn Listener>> = vec![];
+                        // Measure the delay first so it doesn't include the time spent sending to pooltool
+                        if let Some(propagation) = &propagation {
+                            listeners.push(Box::new(PropagationTracker {
+                                relay: format!("{}:{}", host, port),
+                                propagation: propagation.clone(),
+                            }));
                         }
-                        Err(error) => {
-                            error!("{}", error);
+                        for pool in pools.iter() {
+                            listeners.push(Box::new(pooltool::PoolToolNotifier::new(
+                                pool.pool_name.clone(),
+                                pool.pool_id.clone(),
+                                pool.api_key.clone(),
+                                pool.node_version_sources.clone(),
+                                pooltool_client.clone(),
+                                db_path.to_path_buf(),
+                            )));
+                        }
+                        match try_join!(channel.execute({
+                            ChainSyncProtocol {
+                                mode: Mode::SendTip,
+                                network_magic,
+                                notify: Some(Box::new(FanOut { listeners })),
+                                ..Default::default()
+                            }
+                        }),)
+                        {
+                            Ok(_) => {}
+                            Err(error) => {
+                                error!("{}", error);
+                            }
                         }
+                        // Start over from the first address once a connection has been used
+                        break;
                     }
-                }
+                    Err(error) => {
+                        error!("{}: {}", address, error);
+                    }
+                },
                 Err(error) => {
-                    error!("{:?}", error);
+                    error!("{}: {:?}", address, error);
                 }
             }
-
-            task::sleep(Duration::from_secs(5)).await;
         }
-    });
+
+        task::sleep(Duration::from_secs(5)).await;
+    }
 }
@@ -1,77 +1,329 @@
+use std::io::stdout;
 use std::path::Path;
+use std::str::FromStr;
 
-use rusqlite::{Connection, Error};
+use bigdecimal::{BigDecimal, FromPrimitive, One, ToPrimitive};
+use num_bigint::{BigInt, Sign};
+use rusqlite::{Connection, Error, OpenFlags, ToSql};
+use serde::Serialize;
 
-struct Block {
+use crate::nodeclient::leaderlog::libsodium::sodium_crypto_vrf_verify;
+use crate::nodeclient::leaderlog::{
+    calculate_epoch_nonce, get_first_slot_of_epoch, handle_error, is_leader_value_below_threshold, mk_seed,
+    read_byron_genesis, read_shelley_genesis, CERT_NAT_MAX,
+};
+use crate::nodeclient::math::{ln, normalize};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Block {
+    #[serde(skip)]
+    matched: bool,
+    pub status: String,
+    pub block_number: i64,
+    pub slot_number: i64,
+    pub hash: String,
+    pub prev_hash: String,
+    pub pool_id: String,
+    pub leader_vrf: String,
+    pub eta_v: String,
+    pub block_size: i64,
+    pub protocol_major_version: i64,
+    pub protocol_minor_version: i64,
+    pub orphaned: bool,
+}
+
+/// The block that was asked for, plus every other match and fork sibling when there is more than one
+#[derive(Debug, Serialize)]
+pub struct Validation {
+    #[serde(flatten)]
+    pub block: Block,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub matches: Vec<Block>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ValidationError {
+    status: String,
+    error_message: String,
+}
+
+/// How to find the block to validate
+pub enum BlockQuery<'a> {
+    HashPrefix(&'a str),
+    BlockNumber(i64),
+    Slot(i64),
+}
+
+/// The stake the leader threshold is checked against
+pub enum LeaderStake {
+    Sigma(f64),
+    Snapshot { pool_stake: u64, active_stake: u64 },
+}
+
+struct LeaderBlock {
     block_number: i64,
     slot_number: i64,
     hash: String,
-    prev_hash: String,
     pool_id: String,
-    leader_vrf: String,
-    orphaned: bool,
+    node_vrf_vkey: String,
+    leader_vrf_0: String,
+    leader_vrf_1: String,
 }
 
-pub fn validate_block(db_path: &Path, hash: &str) {
-    let like = format!("{}%", hash);
-    match query_block(db_path, like) {
-        Ok(block) => {
-            println!(
-                "{{\n\
-            \x20\"status\": \"{}\",\n\
-            \x20\"block_number\": \"{}\",\n\
-            \x20\"slot_number\": \"{}\",\n\
-            \x20\"pool_id\": \"{}\",\n\
-            \x20\"hash\": \"{}\",\n\
-            \x20\"prev_hash\": \"{}\",\n\
-            \x20\"leader_vrf\": \"{}\"\n\
-            }}",
-                if block.orphaned { "orphaned" } else { "ok" },
-                block.block_number,
-                block.slot_number,
-                block.pool_id,
-                block.hash,
-                block.prev_hash,
-                block.leader_vrf
-            );
-        }
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct VerifyLeader {
+    status: String,
+    block_number: i64,
+    slot_number: i64,
+    hash: String,
+    pool_id: String,
+    epoch: i64,
+    epoch_nonce: String,
+    sigma: f64,
+    vrf_proof_valid: bool,
+    vrf_output_matches: bool,
+    leader_threshold_met: bool,
+}
+
+pub(crate) fn validate_block(db_path: &Path, block_query: &BlockQuery) {
+    if !db_path.exists() {
+        print_validation_error("database not found!".to_string());
+        return;
+    }
+    let db = match Connection::open_with_flags(db_path, OpenFlags::SQLITE_OPEN_READ_ONLY) {
+        Ok(db) => db,
         Err(error) => {
-            println!(
-                "{{\n\
-            \x20\"status\": \"error\",\n\
-            \x20\"errorMessage\": \"{}\"\n\
-            }}",
-                error
-            );
+            print_validation_error(error.to_string());
+            return;
         }
+    };
+    let validation = find_block(&db, block_query);
+    if let Err(error) = db.close() {
+        print_validation_error(format!("db close error: {}", error.1));
+        return;
+    }
+    match validation {
+        Ok(Some(validation)) => serde_json::ser::to_writer_pretty(&mut stdout(), &validation).unwrap(),
+        Ok(None) => print_validation_error("block not found".to_string()),
+        Err(error) => print_validation_error(error),
     }
 }
 
-fn query_block(db_path: &Path, like: String) -> Result<Block, Error> {
-    if !db_path.exists() {
-        return Err(Error::InvalidPath(db_path.to_path_buf()));
+/// The matching block, None when no block matches
+pub fn find_block(db: &Connection, block_query: &BlockQuery) -> Result<Option<Validation>, String> {
+    let blocks = query_blocks(db, block_query)?;
+    if blocks.is_empty() {
+        return Ok(None);
     }
+    // Report on the matching block on the chain if there is one, otherwise the first matching orphan
+    let primary = blocks
+        .iter()
+        .position(|block| block.matched && !block.orphaned)
+        .or_else(|| blocks.iter().position(|block| block.matched))
+        .unwrap_or(0);
+    let block = blocks[primary].clone();
+    let matches = if blocks.len() > 1 { blocks } else { vec![] };
+    Ok(Some(Validation { block, matches }))
+}
 
-    let db = Connection::open(db_path)?;
-    let query_result = db.query_row(
-        "SELECT block_number,slot_number,hash,prev_hash,pool_id,leader_vrf_0,orphaned FROM chain WHERE hash LIKE ?",
-        &[&like],
-        |row| {
+fn print_validation_error(error_message: String) {
+    serde_json::ser::to_writer_pretty(
+        &mut stdout(),
+        &ValidationError {
+            status: "error".to_string(),
+            error_message,
+        },
+    )
+    .unwrap();
+}
+
+fn query_blocks(db: &Connection, block_query: &BlockQuery) -> Result<Vec<Block>, String> {
+    let (condition, value): (&str, Box<dyn ToSql>) = match block_query {
+        BlockQuery::HashPrefix(hash) => {
+            if hash.is_empty() || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(format!("invalid block hash: {}", hash));
+            }
+            ("hash LIKE ?1", Box::new(format!("{}%", hash.to_lowercase())))
+        }
+        BlockQuery::BlockNumber(block_number) => ("block_number = ?1", Box::new(*block_number)),
+        BlockQuery::Slot(slot_number) => ("slot_number = ?1", Box::new(*slot_number)),
+    };
+
+    // Include the other blocks at the same height so forks show up next to each other
+    db.prepare(&format!(
+        "SELECT block_number,slot_number,hash,prev_hash,pool_id,leader_vrf_0,eta_v,block_size,\
+            protocol_major_version,protocol_minor_version,orphaned,({}) AS matched FROM chain \
+            WHERE block_number IN (SELECT block_number FROM chain WHERE {}) \
+            ORDER BY block_number, orphaned, slot_number, hash",
+        condition, condition
+    ))
+    .and_then(|mut stmt| {
+        stmt.query_map(&[value.as_ref()], |row| {
+            let orphaned: bool = row.get(10)?;
             Ok(Block {
+                matched: row.get(11)?,
+                status: if orphaned { "orphaned" } else { "ok" }.to_string(),
                 block_number: row.get(0)?,
                 slot_number: row.get(1)?,
                 hash: row.get(2)?,
                 prev_hash: row.get(3)?,
                 pool_id: row.get(4)?,
                 leader_vrf: row.get(5)?,
-                orphaned: row.get(6)?,
+                eta_v: row.get(6)?,
+                block_size: row.get(7)?,
+                protocol_major_version: row.get(8)?,
+                protocol_minor_version: row.get(9)?,
+                orphaned,
             })
-        },
-    );
+        })?
+        .collect::<Result<Vec<Block>, Error>>()
+    })
+    .map_err(|error| error.to_string())
+}
+
+pub fn verify_leader(
+    db_path: &Path,
+    byron_genesis: &Path,
+    shelley_genesis: &Path,
+    hash: &str,
+    leader_stake: &LeaderStake,
+    extra_entropy: &Option<String>,
+) {
+    if !db_path.exists() {
+        handle_error("database not found!");
+        return;
+    }
+    match leader_stake {
+        LeaderStake::Sigma(sigma) if !(*sigma > 0.0 && *sigma <= 1.0) => {
+            handle_error("sigma must be greater than zero and at most one!");
+            return;
+        }
+        LeaderStake::Snapshot { active_stake: 0, .. } => {
+            handle_error("active-stake must be greater than zero!");
+            return;
+        }
+        _ => {}
+    }
+
+    match verify_leader_block(
+        db_path,
+        byron_genesis,
+        shelley_genesis,
+        hash,
+        leader_stake,
+        extra_entropy,
+    ) {
+        Ok(verify_leader) => {
+            serde_json::ser::to_writer_pretty(&mut std::io::stdout(), &verify_leader).unwrap();
+        }
+        Err(error) => handle_error(error),
+    }
+}
+
+fn verify_leader_block(
+    db_path: &Path,
+    byron_genesis: &Path,
+    shelley_genesis: &Path,
+    hash: &str,
+    leader_stake: &LeaderStake,
+    extra_entropy: &Option<String>,
+) -> Result<VerifyLeader, String> {
+    let byron = read_byron_genesis(byron_genesis).map_err(|error| error.to_string())?;
+    let shelley = read_shelley_genesis(shelley_genesis).map_err(|error| error.to_string())?;
+
+    let db = Connection::open(db_path).map_err(|error| error.to_string())?;
+    // The prefix must pick out a single block, on the chain or orphaned
+    let blocks = query_blocks(&db, &BlockQuery::HashPrefix(hash))?;
+    let matched: Vec<&Block> = blocks.iter().filter(|block| block.matched).collect();
+    let hash = match matched.as_slice() {
+        [] => return Err("block not found".to_string()),
+        [block] => block.hash.clone(),
+        _ => {
+            return Err(format!(
+                "{} blocks match hash {}, use a longer prefix",
+                matched.len(),
+                hash
+            ))
+        }
+    };
 
+    let block = query_leader_block(&db, &hash).map_err(|error| error.to_string())?;
+    let (epoch, first_slot_of_epoch) = get_first_slot_of_epoch(&byron, &shelley, block.slot_number);
+    let epoch_nonce = calculate_epoch_nonce(&db, &byron, &shelley, first_slot_of_epoch, extra_entropy)
+        .map_err(|error| error.to_string())?;
     if let Err(error) = db.close() {
-        return Err(error.1);
+        return Err(format!("db close error: {}", error.1));
     }
 
-    query_result
+    let node_vrf_vkey = hex::decode(&block.node_vrf_vkey).map_err(|error| error.to_string())?;
+    let leader_vrf_0 = hex::decode(&block.leader_vrf_0).map_err(|error| error.to_string())?;
+    let leader_vrf_1 = hex::decode(&block.leader_vrf_1).map_err(|error| error.to_string())?;
+    let sizes: [(&str, &[u8], usize); 3] = [
+        ("node_vrf_vkey", &node_vrf_vkey, 32),
+        ("leader_vrf_0", &leader_vrf_0, 64),
+        ("leader_vrf_1", &leader_vrf_1, 80),
+    ];
+    for (name, value, size) in sizes.iter() {
+        if value.len() != *size {
+            return Err(format!("{} is {} bytes, expected {}", name, value.len(), size));
+        }
+    }
+
+    // Verify the leader vrf proof against the pool's vrf vkey using the seed for this slot
+    let seed = mk_seed(block.slot_number, &epoch_nonce);
+    let (vrf_proof_valid, vrf_output_matches) = match sodium_crypto_vrf_verify(&node_vrf_vkey, &leader_vrf_1, &seed) {
+        Ok(vrf_output) => (true, vrf_output == leader_vrf_0),
+        Err(_) => (false, false),
+    };
+
+    // Check that the leader vrf value is below the threshold for the supplied stake
+    let sigma = match leader_stake {
+        LeaderStake::Sigma(sigma) => BigDecimal::from_f64(*sigma).ok_or("invalid sigma")?,
+        LeaderStake::Snapshot {
+            pool_stake,
+            active_stake,
+        } => normalize(BigDecimal::from(*pool_stake) / BigDecimal::from(*active_stake)),
+    };
+    let cert_nat_max = BigDecimal::from_str(CERT_NAT_MAX).unwrap();
+    let c = ln(&(BigDecimal::one() - BigDecimal::from_f64(shelley.active_slots_coeff).unwrap()));
+    let cert_nat = BigInt::from_bytes_be(Sign::Plus, &leader_vrf_0);
+    let leader_threshold_met = is_leader_value_below_threshold(cert_nat, &sigma, &cert_nat_max, &c);
+
+    Ok(VerifyLeader {
+        status: if vrf_proof_valid && vrf_output_matches && leader_threshold_met {
+            "ok".to_string()
+        } else {
+            "invalid".to_string()
+        },
+        block_number: block.block_number,
+        slot_number: block.slot_number,
+        hash: block.hash,
+        pool_id: block.pool_id,
+        epoch,
+        epoch_nonce: hex::encode(&epoch_nonce),
+        sigma: sigma.to_f64().unwrap(),
+        vrf_proof_valid,
+        vrf_output_matches,
+        leader_threshold_met,
+    })
+}
+
+fn query_leader_block(db: &Connection, hash: &str) -> Result<LeaderBlock, Error> {
+    db.query_row(
+        "SELECT block_number,slot_number,hash,pool_id,node_vrf_vkey,leader_vrf_0,leader_vrf_1 FROM chain WHERE hash = ?",
+        &[&hash],
+        |row| {
+            Ok(LeaderBlock {
+                block_number: row.get(0)?,
+                slot_number: row.get(1)?,
+                hash: row.get(2)?,
+                pool_id: row.get(3)?,
+                node_vrf_vkey: row.get(4)?,
+                leader_vrf_0: row.get(5)?,
+                leader_vrf_1: row.get(6)?,
+            })
+        },
+    )
 }
@@ -0,0 +1,181 @@
+use std::io::stdout;
+use std::path::Path;
+
+use log::debug;
+use rusqlite::{Connection, Error, OptionalExtension};
+use serde::Serialize;
+
+use crate::nodeclient::leaderlog::{
+    get_first_slot_of_epoch, get_first_slot_of_epoch_number, get_tip_slot_number, handle_error, read_byron_genesis,
+    read_shelley_genesis,
+};
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Versions {
+    pub status: String,
+    pub epochs: Vec<EpochVersions>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pool: Option<PoolVersion>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EpochVersions {
+    pub epoch: i64,
+    pub total_blocks: i64,
+    pub total_pools: i64,
+    pub versions: Vec<VersionShare>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VersionShare {
+    pub protocol_major_version: i64,
+    pub protocol_minor_version: i64,
+    pub blocks: i64,
+    pub block_share: f64,
+    pub pools: i64,
+    pub pool_share: f64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PoolVersion {
+    pub pool_id: String,
+    pub block_number: i64,
+    pub slot_number: i64,
+    pub protocol_major_version: i64,
+    pub protocol_minor_version: i64,
+}
+
+pub(crate) fn versions(
+    db_path: &Path,
+    byron_genesis: &Path,
+    shelley_genesis: &Path,
+    epochs: i64,
+    pool_id: &Option<String>,
+) {
+    if !db_path.exists() {
+        handle_error("database not found!");
+        return;
+    }
+
+    match calculate_versions(db_path, byron_genesis, shelley_genesis, epochs, pool_id) {
+        Ok(versions) => {
+            serde_json::ser::to_writer_pretty(&mut stdout(), &versions).unwrap();
+        }
+        Err(error) => handle_error(error),
+    }
+}
+
+/// Protocol version signalling of the last epochs up to the tip, and of a pool's latest block
+pub fn calculate_versions(
+    db_path: &Path,
+    byron_genesis: &Path,
+    shelley_genesis: &Path,
+    epochs: i64,
+    pool_id: &Option<String>,
+) -> Result<Versions, String> {
+    if epochs < 1 {
+        return Err(format!("epochs must be at least 1, got {}", epochs));
+    }
+    let byron = read_byron_genesis(byron_genesis).map_err(|error| error.to_string())?;
+    debug!("{:?}", byron);
+    let shelley = read_shelley_genesis(shelley_genesis).map_err(|error| error.to_string())?;
+    debug!("{:?}", shelley);
+
+    let db = Connection::open(db_path).map_err(|error| error.to_string())?;
+    let tip_slot_number = get_tip_slot_number(&db).map_err(|error| error.to_string())?;
+    debug!("tip_slot_number: {}", tip_slot_number);
+    let (tip_epoch, _) = get_first_slot_of_epoch(&byron, &shelley, tip_slot_number);
+
+    let mut epoch_versions: Vec<EpochVersions> = vec![];
+    for epoch in (tip_epoch - epochs + 1)..=tip_epoch {
+        let first_slot = get_first_slot_of_epoch_number(&byron, &shelley, epoch);
+        let last_slot = first_slot + shelley.epoch_length;
+        epoch_versions.push(get_epoch_versions(&db, epoch, first_slot, last_slot).map_err(|error| error.to_string())?);
+    }
+
+    let pool = match pool_id {
+        Some(pool_id) => Some(
+            get_pool_version(&db, pool_id)
+                .map_err(|error| error.to_string())?
+                .ok_or(format!("No blocks found for pool {}", pool_id))?,
+        ),
+        None => None,
+    };
+
+    if let Err(error) = db.close() {
+        return Err(format!("db close error: {}", error.1));
+    }
+
+    Ok(Versions {
+        status: "ok".to_string(),
+        epochs: epoch_versions,
+        pool,
+    })
+}
+
+fn get_epoch_versions(db: &Connection, epoch: i64, first_slot: i64, last_slot: i64) -> Result<EpochVersions, Error> {
+    let (total_blocks, total_pools): (i64, i64) = db.query_row(
+        "SELECT COUNT(*), COUNT(DISTINCT pool_id) FROM chain \
+        WHERE orphaned = 0 AND slot_number >= ?1 AND slot_number < ?2",
+        &[&first_slot, &last_slot],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+
+    let mut stmt = db.prepare(
+        "SELECT protocol_major_version, protocol_minor_version, COUNT(*), COUNT(DISTINCT pool_id) FROM chain \
+        WHERE orphaned = 0 AND slot_number >= ?1 AND slot_number < ?2 \
+        GROUP BY protocol_major_version, protocol_minor_version \
+        ORDER BY protocol_major_version DESC, protocol_minor_version DESC",
+    )?;
+    let versions = stmt
+        .query_map(&[&first_slot, &last_slot], |row| {
+            let blocks: i64 = row.get(2)?;
+            let pools: i64 = row.get(3)?;
+            Ok(VersionShare {
+                protocol_major_version: row.get(0)?,
+                protocol_minor_version: row.get(1)?,
+                blocks,
+                block_share: share(blocks, total_blocks),
+                pools,
+                pool_share: share(pools, total_pools),
+            })
+        })?
+        .collect::<Result<Vec<VersionShare>, Error>>()?;
+
+    Ok(EpochVersions {
+        epoch,
+        total_blocks,
+        total_pools,
+        versions,
+    })
+}
+
+fn get_pool_version(db: &Connection, pool_id: &str) -> Result<Option<PoolVersion>, Error> {
+    db.query_row(
+        "SELECT block_number, slot_number, protocol_major_version, protocol_minor_version FROM chain \
+        WHERE pool_id = ?1 AND orphaned = 0 ORDER BY slot_number DESC LIMIT 1",
+        &[&pool_id],
+        |row| {
+            Ok(PoolVersion {
+                pool_id: pool_id.to_string(),
+                block_number: row.get(0)?,
+                slot_number: row.get(1)?,
+                protocol_major_version: row.get(2)?,
+                protocol_minor_version: row.get(3)?,
+            })
+        },
+    )
+    .optional()
+}
+
+// Share of the total as a percentage rounded to 2 decimal places
+fn share(count: i64, total: i64) -> f64 {
+    if total == 0 {
+        return 0.0;
+    }
+    (count as f64 / total as f64 * 10000.0).round() / 100.0
+}
@@ -0,0 +1,471 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::Duration;
+
+use cardano_ouroboros_network::protocols::chainsync::Listener;
+use cardano_ouroboros_network::BlockHeader;
+use chrono::{SecondsFormat, Utc};
+use log::{debug, error, info, warn};
+use rusqlite::{params, Connection, Error, OpenFlags};
+use serde::{Deserialize, Serialize};
+
+use crate::nodeclient::leaderlog::{
+    read_byron_genesis, read_shelley_genesis, slot_to_naivedatetime, ByronGenesis, ShelleyGenesis,
+};
+use crate::nodeclient::snapshot::read_tip;
+use crate::nodeclient::{sqlite, APP_USER_AGENT};
+
+pub const TIP: &str = "tip";
+pub const BLOCK_MINTED: &str = "block_minted";
+pub const BLOCK_ORPHANED: &str = "block_orphaned";
+pub const SYNC_BEHIND: &str = "sync_behind";
+
+// How often the worker checks whether sync has fallen behind while no tips come in
+const BEHIND_CHECK_SECS: u64 = 30;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct WebhookConfig {
+    /// Our pools. Their blocks trigger the block_minted and block_orphaned events.
+    #[serde(default)]
+    pool_ids: Vec<String>,
+    /// Needed to tell how far the tip is behind the wall clock for sync_behind events
+    byron_genesis: Option<PathBuf>,
+    shelley_genesis: Option<PathBuf>,
+    #[serde(default = "default_behind_secs")]
+    behind_secs: i64,
+    #[serde(default = "default_timeout")]
+    timeout: u64,
+    hooks: Vec<Webhook>,
+}
+
+fn default_behind_secs() -> i64 {
+    300
+}
+
+// seconds
+fn default_timeout() -> u64 {
+    10
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Webhook {
+    url: String,
+    #[serde(default = "default_webhook_format")]
+    format: WebhookFormat,
+    /// Events this hook receives. All events but tip if empty, tips have to be asked for.
+    #[serde(default)]
+    events: Vec<String>,
+    /// Only send block events for these pools. All of our pools if empty.
+    #[serde(default)]
+    pool_ids: Vec<String>,
+    /// Message text with {event}, {pool_id}, {block_number}, {slot_number}, {hash} and {behind_secs} placeholders
+    template: Option<String>,
+    /// Required by telegram's sendMessage
+    chat_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WebhookFormat {
+    Json,
+    Slack,
+    Discord,
+    Telegram,
+}
+
+fn default_webhook_format() -> WebhookFormat {
+    WebhookFormat::Json
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WebhookEvent {
+    pub event: String,
+    pub block_number: i64,
+    pub slot_number: i64,
+    pub hash: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pool_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub behind_secs: Option<i64>,
+    pub at: String,
+}
+
+#[derive(Debug, Serialize)]
+struct SlackMessage {
+    text: String,
+}
+
+#[derive(Debug, Serialize)]
+struct DiscordMessage {
+    content: String,
+}
+
+#[derive(Debug, Serialize)]
+struct TelegramMessage {
+    chat_id: String,
+    text: String,
+}
+
+impl Webhook {
+    pub fn accepts(&self, event: &WebhookEvent) -> bool {
+        let event_match = if self.events.is_empty() {
+            event.event != TIP
+        } else {
+            self.events.iter().any(|name| name == &event.event)
+        };
+        let pool_match = match &event.pool_id {
+            Some(pool_id) => self.pool_ids.is_empty() || self.pool_ids.contains(pool_id),
+            None => true,
+        };
+        event_match && pool_match
+    }
+
+    pub fn payload(&self, event: &WebhookEvent) -> String {
+        match self.format {
+            WebhookFormat::Json => serde_json::ser::to_string(event).unwrap(),
+            WebhookFormat::Slack => serde_json::ser::to_string(&SlackMessage {
+                text: self.message(event),
+            })
+            .unwrap(),
+            WebhookFormat::Discord => serde_json::ser::to_string(&DiscordMessage {
+                content: self.message(event),
+            })
+            .unwrap(),
+            WebhookFormat::Telegram => serde_json::ser::to_string(&TelegramMessage {
+                chat_id: self.chat_id.clone().unwrap_or_default(),
+                text: self.message(event),
+            })
+            .unwrap(),
+        }
+    }
+
+    fn message(&self, event: &WebhookEvent) -> String {
+        let template = match &self.template {
+            Some(template) => template.as_str(),
+            None => match event.event.as_str() {
+                TIP => "New tip: block {block_number}, slot {slot_number}, hash {hash}",
+                BLOCK_MINTED => "Pool {pool_id} minted block {block_number}, slot {slot_number}, hash {hash}",
+                BLOCK_ORPHANED => {
+                    "Block {block_number} of pool {pool_id} was orphaned, slot {slot_number}, hash {hash}"
+                }
+                _ => "Sync is {behind_secs} seconds behind at block {block_number}, slot {slot_number}",
+            },
+        };
+        template
+            .replace("{event}", &event.event)
+            .replace("{pool_id}", event.pool_id.as_deref().unwrap_or(""))
+            .replace("{block_number}", &event.block_number.to_string())
+            .replace("{slot_number}", &event.slot_number.to_string())
+            .replace("{hash}", &event.hash)
+            .replace("{behind_secs}", &event.behind_secs.unwrap_or(0).to_string())
+    }
+}
+
+pub(crate) fn read_webhook_config(config: &Path) -> Result<WebhookConfig, String> {
+    let buf = BufReader::new(File::open(config).map_err(|error| error.to_string())?);
+    serde_json::from_reader(buf).map_err(|error| error.to_string())
+}
+
+/// A block from the chain sync, handed to the webhook worker
+pub(crate) struct WebhookTip {
+    block_number: i64,
+    slot_number: i64,
+    hash: String,
+    pool_id: String,
+}
+
+impl From<&BlockHeader> for WebhookTip {
+    fn from(header: &BlockHeader) -> Self {
+        WebhookTip {
+            block_number: header.block_number,
+            slot_number: header.slot_number,
+            hash: hex::encode(&header.hash),
+            pool_id: hex::encode(sqlite::pool_id(&header.node_vkey)),
+        }
+    }
+}
+
+enum WebhookMessage {
+    /// A new tip from the node
+    Tip(WebhookTip),
+    /// A block of one of our pools was saved to the db
+    Minted(WebhookTip),
+    /// Answered once every message sent before it has been handled
+    Flush(Sender<()>),
+}
+
+/// Hands new tips and our saved blocks to the webhook worker thread, so slow webhooks never hold up
+/// the chain sync
+#[derive(Clone)]
+pub struct WebhookNotifier {
+    sender: Sender<WebhookMessage>,
+    pool_ids: Vec<String>,
+}
+
+impl WebhookNotifier {
+    /// Start the worker that posts the events. It outlives the node connections, so sync_behind
+    /// is still announced when no tips come in at all.
+    pub fn start(config: WebhookConfig, db_path: &Path) -> Result<Self, String> {
+        let pool_ids = config.pool_ids.clone();
+        let worker = WebhookWorker::new(config, db_path)?;
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || worker.run(receiver));
+        Ok(WebhookNotifier { sender, pool_ids })
+    }
+
+    /// The blocks of our pools, to announce once they are saved
+    pub(crate) fn minted(&self, blocks: &[BlockHeader]) -> Vec<WebhookTip> {
+        if self.pool_ids.is_empty() {
+            return vec![];
+        }
+        blocks
+            .iter()
+            .map(WebhookTip::from)
+            .filter(|block| self.pool_ids.contains(&block.pool_id))
+            .collect()
+    }
+
+    pub(crate) fn blocks_minted(&self, minted: Vec<WebhookTip>) {
+        for block in minted.into_iter() {
+            self.send(WebhookMessage::Minted(block));
+        }
+    }
+
+    /// Wait until the worker has posted the events for everything sent to it so far
+    pub fn flush(&self) {
+        let (done, wait) = mpsc::channel();
+        self.send(WebhookMessage::Flush(done));
+        let _ = wait.recv();
+    }
+
+    fn send(&self, message: WebhookMessage) {
+        if self.sender.send(message).is_err() {
+            error!("Webhook worker has stopped");
+        }
+    }
+}
+
+impl Listener for WebhookNotifier {
+    fn handle_tip(&mut self, header: &BlockHeader) {
+        self.send(WebhookMessage::Tip(WebhookTip::from(header)));
+    }
+}
+
+/// Posts events about new tips and our own blocks to the configured webhooks
+struct WebhookWorker {
+    config: WebhookConfig,
+    client: reqwest::blocking::Client,
+    db_path: PathBuf,
+    genesis: Option<(ByronGenesis, ShelleyGenesis)>,
+    notified_orphans: HashSet<String>,
+    is_behind: bool,
+    tip: Option<WebhookTip>,
+    // Only blocks past the db tip we started at are new, none until the first tip on an empty db
+    minted_after: Option<i64>,
+}
+
+impl WebhookWorker {
+    fn new(config: WebhookConfig, db_path: &Path) -> Result<Self, String> {
+        let client = reqwest::blocking::Client::builder()
+            .user_agent(APP_USER_AGENT)
+            .timeout(Duration::from_secs(config.timeout))
+            .build()
+            .map_err(|error| format!("Could not set up the reqwest client!: {}", error))?;
+        let genesis = match (&config.byron_genesis, &config.shelley_genesis) {
+            (Some(byron_genesis), Some(shelley_genesis)) => Some((
+                read_byron_genesis(byron_genesis).map_err(|error| error.to_string())?,
+                read_shelley_genesis(shelley_genesis).map_err(|error| error.to_string())?,
+            )),
+            _ => {
+                warn!(
+                    "No genesis files in the webhook config, sync_behind events are disabled and \
+                    the whole chain is checked for orphans"
+                );
+                None
+            }
+        };
+        let mut worker = WebhookWorker {
+            config,
+            client,
+            db_path: db_path.to_path_buf(),
+            genesis,
+            notified_orphans: HashSet::new(),
+            is_behind: false,
+            tip: None,
+            minted_after: None,
+        };
+        // Don't announce blocks that were orphaned before we started
+        let orphans = worker.get_orphans(0).map_err(|error| error.to_string())?;
+        worker.notified_orphans = orphans.into_iter().map(|orphan| orphan.hash).collect();
+        // Tell how far behind we are from the db until the node sends a tip
+        worker.tip = worker.get_db_tip()?;
+        worker.minted_after = worker.tip.as_ref().map(|tip| tip.block_number);
+        Ok(worker)
+    }
+
+    fn run(mut self, receiver: Receiver<WebhookMessage>) {
+        loop {
+            match receiver.recv_timeout(Duration::from_secs(BEHIND_CHECK_SECS)) {
+                Ok(message) => {
+                    // Catch up on a backlog at once, only the newest of the tips in it is the tip
+                    let mut messages = vec![message];
+                    messages.extend(receiver.try_iter());
+                    let mut tip: Option<WebhookTip> = None;
+                    let mut flushed: Vec<Sender<()>> = vec![];
+                    for message in messages.into_iter() {
+                        match message {
+                            WebhookMessage::Tip(new_tip) => tip = Some(new_tip),
+                            WebhookMessage::Minted(block) => self.block_minted(&block),
+                            WebhookMessage::Flush(done) => flushed.push(done),
+                        }
+                    }
+                    if let Some(tip) = tip {
+                        self.notify(&self.event(TIP, &tip, None, None));
+                        self.check_orphans(tip.block_number);
+                        self.minted_after.get_or_insert(tip.block_number);
+                        self.tip = Some(tip);
+                    }
+                    for done in flushed.into_iter() {
+                        let _ = done.send(());
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+            self.check_behind();
+        }
+    }
+
+    fn block_minted(&self, block: &WebhookTip) {
+        match self.minted_after {
+            Some(block_number) if block.block_number > block_number => {
+                info!("Pool {} minted block {}", &block.pool_id, block.block_number);
+                self.notify(&self.event(BLOCK_MINTED, block, Some(block.pool_id.clone()), None));
+            }
+            _ => {}
+        }
+    }
+
+    fn event(&self, event: &str, tip: &WebhookTip, pool_id: Option<String>, behind_secs: Option<i64>) -> WebhookEvent {
+        WebhookEvent {
+            event: event.to_string(),
+            block_number: tip.block_number,
+            slot_number: tip.slot_number,
+            hash: tip.hash.clone(),
+            pool_id,
+            behind_secs,
+            at: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        }
+    }
+
+    fn notify(&self, event: &WebhookEvent) {
+        for hook in self.config.hooks.iter().filter(|hook| hook.accepts(event)) {
+            match self.client.post(&hook.url).body(hook.payload(event)).send() {
+                Ok(response) => debug!("Webhook {} ({}): {}", &event.event, &hook.url, response.status()),
+                Err(error) => error!("Webhook {} ({}) error: {}", &event.event, &hook.url, error),
+            }
+        }
+    }
+
+    fn check_orphans(&mut self, block_number: i64) {
+        // Rollbacks happen within k blocks of the tip
+        let min_block_number = match &self.genesis {
+            Some((_, shelley)) => block_number - shelley.security_param,
+            None => 0,
+        };
+        match self.get_orphans(min_block_number) {
+            Ok(orphans) => {
+                for orphan in orphans.iter() {
+                    if self.notified_orphans.insert(orphan.hash.clone()) {
+                        info!(
+                            "Block {} of pool {:?} was orphaned",
+                            orphan.block_number, &orphan.pool_id
+                        );
+                        self.notify(orphan);
+                    }
+                }
+            }
+            Err(error) => error!("Webhook orphan check error: {}", error),
+        }
+    }
+
+    // Runs on a timer as well as on new tips, a stalled node sends no tips at all
+    fn check_behind(&mut self) {
+        let (byron, shelley) = match &self.genesis {
+            Some(genesis) => genesis,
+            None => return,
+        };
+        let tip = match &self.tip {
+            Some(tip) => tip,
+            None => return,
+        };
+        let tip_time = slot_to_naivedatetime(byron, shelley, tip.slot_number).timestamp();
+        let behind_secs = Utc::now().timestamp() - tip_time;
+        // Only announce once each time we fall behind
+        if behind_secs > self.config.behind_secs {
+            if !self.is_behind {
+                self.is_behind = true;
+                self.notify(&self.event(SYNC_BEHIND, tip, None, Some(behind_secs)));
+            }
+        } else {
+            self.is_behind = false;
+        }
+    }
+
+    fn get_db_tip(&self) -> Result<Option<WebhookTip>, String> {
+        if !self.db_path.exists() {
+            return Ok(None);
+        }
+        let db = Connection::open_with_flags(&self.db_path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .map_err(|error| error.to_string())?;
+        let tip = read_tip(&db)?.map(|tip| WebhookTip {
+            block_number: tip.block_number,
+            slot_number: tip.slot_number,
+            hash: tip.hash,
+            pool_id: String::new(),
+        });
+        if let Err(error) = db.close() {
+            return Err(format!("db close error: {}", error.1));
+        }
+        Ok(tip)
+    }
+
+    fn get_orphans(&self, min_block_number: i64) -> Result<Vec<WebhookEvent>, Error> {
+        if self.config.pool_ids.is_empty() || !self.db_path.exists() {
+            return Ok(vec![]);
+        }
+        let db = Connection::open_with_flags(&self.db_path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        let mut orphans: Vec<WebhookEvent> = vec![];
+        {
+            let mut stmt = db.prepare(
+                "SELECT block_number, slot_number, hash FROM chain \
+                WHERE orphaned = 1 AND pool_id = ?1 AND block_number >= ?2",
+            )?;
+            for pool_id in self.config.pool_ids.iter() {
+                let pool_orphans = stmt
+                    .query_map(params![pool_id, min_block_number], |row| {
+                        Ok(WebhookEvent {
+                            event: BLOCK_ORPHANED.to_string(),
+                            block_number: row.get(0)?,
+                            slot_number: row.get(1)?,
+                            hash: row.get(2)?,
+                            pool_id: Some(pool_id.clone()),
+                            behind_secs: None,
+                            at: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+                        })
+                    })?
+                    .collect::<Result<Vec<WebhookEvent>, Error>>()?;
+                orphans.extend(pool_orphans);
+            }
+        }
+        if let Err(error) = db.close() {
+            return Err(error.1);
+        }
+        Ok(orphans)
+    }
+}
@@ -1,17 +1,181 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, UdpSocket};
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+use std::time::Duration;
 
 use bigdecimal::{BigDecimal, One, Zero};
+use cardano_ouroboros_network::{BlockHeader, BlockStore};
+use num_bigint::BigInt;
 use rug::float::Round;
 use rug::ops::MulAssignRound;
 use rug::{Float, Rational};
+use serde_cbor::Value;
 
+use cncli::nodeclient::discover::parse_seed;
+use cncli::nodeclient::export::{export_table, ExportFormat, ExportOptions, ExportTable};
+use cncli::nodeclient::fsck::{fsck_db, DUPLICATE_BLOCK, ETA_V_MISMATCH, POOL_ID_MISMATCH};
+use cncli::nodeclient::headercheck::{check_kes_period, parse_epoch_extra_entropy, HeaderCheck, HeaderCheckMode};
+use cncli::nodeclient::health::calculate_chain_health;
+use cncli::nodeclient::leaderlog::{read_byron_genesis, read_shelley_genesis};
 use cncli::nodeclient::math::{ceiling, exp, find_e, ln, round, split_ln};
+use cncli::nodeclient::opcert::{opcert_signable, opcert_status, parse_opcert};
+use cncli::nodeclient::outbox::{backoff_secs, Outbox};
 use cncli::nodeclient::ping;
-use nodeclient::leaderlog::is_overlay_slot;
+use cncli::nodeclient::pinghistory::{parse_duration_secs, PingHistory};
+use cncli::nodeclient::poolstats::calculate_pool_stats;
+use cncli::nodeclient::pooltool::{
+    parse_cardano_node_version, parse_prometheus_build_info, PooltoolClient, PooltoolSendSlots, SENDSLOTS, SENDSTATS,
+};
+use cncli::nodeclient::propagation::{DelayStats, DelayWindow};
+use cncli::nodeclient::resolve;
+use cncli::nodeclient::serve::Api;
+use cncli::nodeclient::snapshot::{create_snapshot, restore_snapshot};
+use cncli::nodeclient::sqlite::{next_eta_v, pool_id, SqLiteBlockStore};
+use cncli::nodeclient::stakeestimate::{sigma_from_leader_probability, wilson_interval};
+use cncli::nodeclient::sync::{group_by_relay, SendtipPool};
+use cncli::nodeclient::validate::{find_block, BlockQuery};
+use cncli::nodeclient::versions::calculate_versions;
+use cncli::nodeclient::webhook::{Webhook, WebhookConfig, WebhookEvent, WebhookNotifier, BLOCK_MINTED, TIP};
+use cncli::nodeclient::PooltoolConfig;
+use nodeclient::leaderlog::{is_leader_value_below_threshold, is_overlay_slot, CERT_NAT_MAX};
 use nodeclient::math::ipow;
 
 use super::*;
 
+/// A chain row for the test dbs, keys and VRF values are left empty unless a test sets them
+struct TestBlock {
+    block_number: i64,
+    slot_number: i64,
+    hash: String,
+    prev_hash: String,
+    pool_id: String,
+    eta_v: String,
+    node_vkey: String,
+    eta_vrf_0: String,
+    block_size: i64,
+    opcert_counter: i64,
+    opcert_kes_period: i64,
+    protocol_major_version: i64,
+    protocol_minor_version: i64,
+}
+
+impl TestBlock {
+    fn new(block_number: i64, slot_number: i64) -> TestBlock {
+        TestBlock {
+            block_number,
+            slot_number,
+            hash: format!("{:064x}", block_number),
+            prev_hash: format!("{:064x}", block_number - 1),
+            pool_id: "".to_string(),
+            eta_v: "".to_string(),
+            node_vkey: "".to_string(),
+            eta_vrf_0: "".to_string(),
+            block_size: 1000,
+            opcert_counter: 0,
+            opcert_kes_period: 0,
+            protocol_major_version: 6,
+            protocol_minor_version: 0,
+        }
+    }
+
+    fn pool_id(mut self, pool_id: &str) -> TestBlock {
+        self.pool_id = pool_id.to_string();
+        self
+    }
+}
+
+/// A migrated db and mainnet genesis files in the temp dir. Every file starting with the db's name is
+/// removed on drop, so a failing test cleans up too.
+struct TestDb {
+    name: String,
+    path: PathBuf,
+    byron_genesis: PathBuf,
+    shelley_genesis: PathBuf,
+    block_store: SqLiteBlockStore,
+}
+
+impl TestDb {
+    fn new(test: &str) -> TestDb {
+        let name = format!("cncli-{}-{}", test, std::process::id());
+        let dir = std::env::temp_dir();
+        let byron_genesis = dir.join(format!("{}-byron.json", name));
+        let shelley_genesis = dir.join(format!("{}-shelley.json", name));
+        std::fs::write(
+            &byron_genesis,
+            r#"{"startTime": 1506203091, "protocolConsts": {"k": 2160}, "blockVersionData": {"slotDuration": "20000"}}"#,
+        )
+        .unwrap();
+        // Without the KES parameters, so the opcert and header checks run on the mainnet defaults
+        std::fs::write(
+            &shelley_genesis,
+            r#"{"activeSlotsCoeff": 0.05, "networkMagic": 764824073, "slotLength": 1, "epochLength": 432000}"#,
+        )
+        .unwrap();
+        let path = dir.join(format!("{}.db", name));
+        let block_store = SqLiteBlockStore::new(&path).unwrap();
+        TestDb {
+            name,
+            path,
+            byron_genesis,
+            shelley_genesis,
+            block_store,
+        }
+    }
+
+    /// Another file next to the db that is removed with it
+    fn file(&self, suffix: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("{}-{}", self.name, suffix))
+    }
+
+    fn insert(&self, block: &TestBlock) {
+        self.block_store
+            .db
+            .execute_named(
+                "INSERT INTO chain (block_number, slot_number, hash, prev_hash, pool_id, eta_v, node_vkey, \
+                node_vrf_vkey, eta_vrf_0, eta_vrf_1, leader_vrf_0, leader_vrf_1, block_size, block_body_hash, \
+                pool_opcert, opcert_counter, opcert_kes_period, opcert_signature, protocol_major_version, \
+                protocol_minor_version) VALUES (:block_number, :slot_number, :hash, :prev_hash, :pool_id, :eta_v, \
+                :node_vkey, '', :eta_vrf_0, '', '', '', :block_size, '', '', :opcert_counter, :opcert_kes_period, \
+                '', :protocol_major_version, :protocol_minor_version)",
+                rusqlite::named_params! {
+                    ":block_number": block.block_number,
+                    ":slot_number": block.slot_number,
+                    ":hash": block.hash,
+                    ":prev_hash": block.prev_hash,
+                    ":pool_id": block.pool_id,
+                    ":eta_v": block.eta_v,
+                    ":node_vkey": block.node_vkey,
+                    ":eta_vrf_0": block.eta_vrf_0,
+                    ":block_size": block.block_size,
+                    ":opcert_counter": block.opcert_counter,
+                    ":opcert_kes_period": block.opcert_kes_period,
+                    ":protocol_major_version": block.protocol_major_version,
+                    ":protocol_minor_version": block.protocol_minor_version,
+                },
+            )
+            .unwrap();
+    }
+
+    fn execute(&self, sql: &str) {
+        self.block_store.db.execute(sql, rusqlite::NO_PARAMS).unwrap();
+    }
+}
+
+impl Drop for TestDb {
+    fn drop(&mut self) {
+        if let Ok(entries) = std::fs::read_dir(std::env::temp_dir()) {
+            for entry in entries.flatten() {
+                if entry.file_name().to_string_lossy().starts_with(&self.name) {
+                    let _ = std::fs::remove_file(entry.path());
+                }
+            }
+        }
+    }
+}
+
 #[test]
 fn test_is_overlay_slot() {
     pretty_env_logger::init_timed();
@@ -30,6 +194,27 @@ fn test_is_overlay_slot() {
     assert_eq!(is_overlay_slot(&first_slot_of_epoch, &current_slot, &r), true);
 }
 
+#[test]
+fn test_is_leader_value_below_threshold() {
+    let sigma = BigDecimal::from_str("0.0077949348290607914969808129687391").unwrap();
+    let c = BigDecimal::from_str("-0.0512932943875505334261962382072846").unwrap();
+    let cert_nat_max = BigDecimal::from_str(CERT_NAT_MAX).unwrap();
+
+    // The smallest possible leader value always wins the slot
+    let cert_nat = BigInt::from(0);
+    assert_eq!(
+        is_leader_value_below_threshold(cert_nat, &sigma, &cert_nat_max, &c),
+        true
+    );
+
+    // A leader value at half of 2^512 is far above the threshold for this stake
+    let cert_nat = BigInt::from(2).pow(511);
+    assert_eq!(
+        is_leader_value_below_threshold(cert_nat, &sigma, &cert_nat_max, &c),
+        false
+    );
+}
+
 #[test]
 fn test_ping() {
     let host = "north-america.relays-new.cardano-testnet.iohkdev.io".to_string();
@@ -82,6 +267,1206 @@ fn test_ping_failure_bad_magic() {
     assert_eq!(&std::str::from_utf8(&stdout).unwrap()[..], "{\n  \"status\": \"error\",\n  \"host\": \"north-america.relays-new.cardano-testnet.iohkdev.io\",\n  \"port\": 3001,\n  \"errorMessage\": \"version data mismatch: NodeToNodeVersionData {networkMagic = NetworkMagic {unNetworkMagic = 1097911063}, diffusionMode = InitiatorAndResponderDiffusionMode} /= NodeToNodeVersionData {networkMagic = NetworkMagic {unNetworkMagic = 111111}, diffusionMode = InitiatorAndResponderDiffusionMode}\"\n}");
 }
 
+#[test]
+fn test_parse_handshake_data() {
+    assert_eq!(
+        ping::parse_handshake_data("[Integer(1), Integer(7), Array([Integer(764824073), Bool(false)])]"),
+        (Some(7), Some("InitiatorAndResponder".to_string()))
+    );
+    assert_eq!(
+        ping::parse_handshake_data("[Integer(1), Integer(8), Array([Integer(1), Bool(true)])]"),
+        (Some(8), Some("InitiatorOnly".to_string()))
+    );
+    assert_eq!(ping::parse_handshake_data(""), (None, None));
+}
+
+#[test]
+fn test_resolve() {
+    assert_eq!(
+        resolve::resolve("127.0.0.1", 3001).unwrap(),
+        vec!["127.0.0.1:3001".parse().unwrap()]
+    );
+    assert_eq!(
+        resolve::resolve("[::1]", 3001).unwrap(),
+        vec!["[::1]:3001".parse().unwrap()]
+    );
+    assert_eq!(
+        resolve::resolve("::1", 6000).unwrap(),
+        vec!["[::1]:6000".parse().unwrap()]
+    );
+
+    // _cardano._tcp.example.com with two SRV answers, the second target compressed against the first
+    let mut response: Vec<u8> = vec![0x12, 0x34, 0x81, 0x80, 0, 1, 0, 2, 0, 0, 0, 0];
+    response.extend_from_slice(b"\x08_cardano\x04_tcp\x07example\x03com\x00\x00\x21\x00\x01");
+    response.extend_from_slice(&[0xc0, 0x0c, 0, 33, 0, 1, 0, 0, 0, 60, 0, 26, 0, 20, 0, 5, 0x0b, 0xb9]);
+    response.extend_from_slice(b"\x06relay1\x07example\x03com\x00");
+    response.extend_from_slice(&[0xc0, 0x0c, 0, 33, 0, 1, 0, 0, 0, 60, 0, 15, 0, 10, 0, 5, 0x17, 0x70]);
+    response.extend_from_slice(b"\x06relay2\xc0\x1a");
+    assert_eq!(
+        resolve::parse_srv_response(&response).unwrap(),
+        vec![
+            resolve::SrvRecord {
+                priority: 10,
+                weight: 5,
+                port: 6000,
+                target: "relay2.example.com".to_string(),
+            },
+            resolve::SrvRecord {
+                priority: 20,
+                weight: 5,
+                port: 3001,
+                target: "relay1.example.com".to_string(),
+            },
+        ]
+    );
+
+    // NXDOMAIN
+    assert!(resolve::parse_srv_response(&[0x12, 0x34, 0x81, 0x83, 0, 0, 0, 0, 0, 0, 0, 0]).is_err());
+
+    assert!(resolve::is_srv_name("_cardano-mainnet._tcp.example.com"));
+    assert!(!resolve::is_srv_name("_relay.example.com"));
+    assert!(!resolve::is_srv_name("relay1.example.com"));
+    let long_label = format!("_cardano._tcp.{}.com", "a".repeat(64));
+    assert!(resolve::query_srv(&long_label, &[]).unwrap_err().contains("label"));
+
+    // A nameserver that is down, then one that truncates over UDP and answers over TCP
+    let down = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let nameserver = udp.local_addr().unwrap();
+    let tcp = TcpListener::bind(nameserver).unwrap();
+    thread::spawn(move || {
+        let mut query = [0u8; 512];
+        let (_, from) = udp.recv_from(&mut query).unwrap();
+        udp.send_to(&[query[0], query[1], 0x83, 0x80, 0, 0, 0, 0, 0, 0, 0, 0], from)
+            .unwrap();
+        let (mut stream, _) = tcp.accept().unwrap();
+        let mut length = [0u8; 2];
+        stream.read_exact(&mut length).unwrap();
+        let mut query = vec![0u8; u16::from_be_bytes(length) as usize];
+        stream.read_exact(&mut query).unwrap();
+        response[0] = query[0];
+        response[1] = query[1];
+        stream.write_all(&(response.len() as u16).to_be_bytes()).unwrap();
+        stream.write_all(&response).unwrap();
+    });
+    let records = resolve::query_srv("_cardano._tcp.example.com", &[down, nameserver]).unwrap();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0].target, "relay2.example.com");
+}
+
+#[test]
+fn test_discover_peer_sharing() {
+    assert_eq!(
+        parse_seed("relay1.example.com:6000"),
+        Ok(("relay1.example.com".to_string(), 6000))
+    );
+    assert_eq!(
+        parse_seed("relay1.example.com"),
+        Ok(("relay1.example.com".to_string(), 3001))
+    );
+    assert_eq!(parse_seed("[2001:db8::1]:3002"), Ok(("2001:db8::1".to_string(), 3002)));
+    assert_eq!(parse_seed("2001:db8::1"), Ok(("2001:db8::1".to_string(), 3001)));
+    assert!(parse_seed("relay1.example.com:port").is_err());
+
+    // [[0, 203.0.113.10, 3001], [1, 2001:db8::1, 6000], [9]]
+    let shared_peers = Value::Array(vec![
+        Value::Array(vec![
+            Value::Integer(0),
+            Value::Integer(0xcb00_710a),
+            Value::Integer(3001),
+        ]),
+        Value::Array(vec![
+            Value::Integer(1),
+            Value::Integer(0x2001_0db8),
+            Value::Integer(0),
+            Value::Integer(0),
+            Value::Integer(1),
+            Value::Integer(6000),
+        ]),
+        Value::Array(vec![Value::Integer(9)]),
+    ]);
+    assert_eq!(
+        ping::parse_peer_addresses(&shared_peers),
+        vec![
+            "203.0.113.10:3001".parse().unwrap(),
+            "[2001:db8::1]:6000".parse().unwrap()
+        ]
+    );
+
+    // Peer sharing is proposed from v11 on and only used if the peer agreed to it
+    match ping::peer_sharing_versions(764824073) {
+        Value::Map(versions) => {
+            assert_eq!(versions.len(), 8);
+            assert_eq!(
+                versions.get(&Value::Integer(10)),
+                Some(&Value::Array(vec![Value::Integer(764824073), Value::Bool(false)]))
+            );
+            assert_eq!(
+                versions.get(&Value::Integer(13)),
+                Some(&Value::Array(vec![
+                    Value::Integer(764824073),
+                    Value::Bool(false),
+                    Value::Integer(1),
+                    Value::Bool(false)
+                ]))
+            );
+        }
+        versions => panic!("unexpected version table: {:?}", versions),
+    }
+    let accept = |version: i128, peer_sharing: i128| {
+        vec![
+            Value::Integer(1),
+            Value::Integer(version),
+            Value::Array(vec![
+                Value::Integer(764824073),
+                Value::Bool(false),
+                Value::Integer(peer_sharing),
+                Value::Bool(false),
+            ]),
+        ]
+    };
+    assert!(ping::accepts_peer_sharing(&accept(13, 1)));
+    assert!(!ping::accepts_peer_sharing(&accept(13, 0)));
+    assert!(!ping::accepts_peer_sharing(&[
+        Value::Integer(1),
+        Value::Integer(10),
+        Value::Array(vec![Value::Integer(764824073), Value::Bool(false)]),
+    ]));
+    assert_eq!(
+        ping::parse_handshake_data(&format!("{:?}", accept(13, 1))),
+        (Some(13), Some("InitiatorAndResponder".to_string()))
+    );
+}
+
+#[test]
+fn test_read_topology() {
+    let topology_path = std::env::temp_dir().join(format!("cncli-topology-{}.json", std::process::id()));
+    std::fs::write(
+        &topology_path,
+        r#"{"Producers": [{"addr": "relay1.example.com", "port": 3001, "valency": 1}, {"addr": "10.0.0.2", "port": 6000, "valency": 2}]}"#,
+    )
+    .unwrap();
+
+    let peers = ping::read_topology(&topology_path).unwrap();
+    std::fs::remove_file(&topology_path).unwrap();
+
+    assert_eq!(
+        peers,
+        vec![("relay1.example.com".to_string(), 3001), ("10.0.0.2".to_string(), 6000)]
+    );
+    assert!(ping::read_topology(&topology_path).is_err());
+}
+
+#[test]
+fn test_parse_opcert() {
+    let cbor = hex::decode(
+        "828458201111111111111111111111111111111111111111111111111111111111111111\
+        0518c95840222222222222222222222222222222222222222222222222222222222222222222222222\
+        22222222222222222222222222222222222222222222222222222222\
+        58203333333333333333333333333333333333333333333333333333333333333333",
+    )
+    .unwrap();
+    let opcert = parse_opcert(&cbor).unwrap();
+
+    assert_eq!(opcert.hot_vkey, vec![0x11u8; 32]);
+    assert_eq!(opcert.counter, 5);
+    assert_eq!(opcert.kes_period, 201);
+    assert_eq!(opcert.signature, vec![0x22u8; 64]);
+    assert_eq!(opcert.cold_vkey, vec![0x33u8; 32]);
+
+    assert!(parse_opcert(&hex::decode("5820").unwrap()).is_err());
+}
+
+#[test]
+fn test_opcert_status() {
+    let test_db = TestDb::new("opcert");
+    for (block_number, slot_number, pool_id, counter, kes_period) in [
+        (1, 4492800, "aa", 1, 34),
+        (2, 4924800, "aa", 2, 38),
+        (3, 4924900, "bb", 5, 38),
+        (4, 4925000, "aa", 3, 38),
+    ]
+    .iter()
+    {
+        let mut block = TestBlock::new(*block_number, *slot_number).pool_id(pool_id);
+        block.opcert_counter = *counter;
+        block.opcert_kes_period = *kes_period;
+        test_db.insert(&block);
+    }
+    test_db.execute("UPDATE chain SET orphaned = 1 WHERE block_number = 4");
+
+    // The orphaned block does not count, the tip does
+    let status = opcert_status(&test_db.path, &test_db.byron_genesis, &test_db.shelley_genesis, "aa").unwrap();
+    assert_eq!((status.block_number, status.slot_number), (2, 4924800));
+    assert_eq!((status.opcert_counter, status.opcert_kes_period), (2, 38));
+    assert_eq!(status.current_kes_period, 38);
+    assert_eq!(status.kes_expiry_period, 100);
+    assert_eq!(status.kes_expiry_date, "2020-11-04T21:44:51Z");
+    assert!(status.kes_remaining_days < 0.0);
+
+    assert_eq!(
+        opcert_status(&test_db.path, &test_db.byron_genesis, &test_db.shelley_genesis, "cc").unwrap_err(),
+        "No blocks found for pool cc"
+    );
+}
+
+#[test]
+fn test_versions() {
+    let test_db = TestDb::new("versions");
+    for (block_number, slot_number, pool_id, major) in [
+        (1, 4492800, "aa", 6),
+        (2, 4492900, "bb", 6),
+        (3, 4924800, "aa", 7),
+        (4, 4924900, "bb", 6),
+        (5, 4925000, "cc", 7),
+        (6, 4925100, "dd", 8),
+    ]
+    .iter()
+    {
+        let mut block = TestBlock::new(*block_number, *slot_number).pool_id(pool_id);
+        block.protocol_major_version = *major;
+        test_db.insert(&block);
+    }
+    test_db.execute("UPDATE chain SET orphaned = 1 WHERE block_number = 6");
+
+    let versions = calculate_versions(
+        &test_db.path,
+        &test_db.byron_genesis,
+        &test_db.shelley_genesis,
+        2,
+        &Some("aa".to_string()),
+    )
+    .unwrap();
+    assert_eq!(versions.epochs.len(), 2);
+    assert_eq!(versions.epochs[0].epoch, 208);
+    assert_eq!(versions.epochs[0].versions.len(), 1);
+    assert_eq!(versions.epochs[0].versions[0].block_share, 100.0);
+    let epoch = &versions.epochs[1];
+    assert_eq!((epoch.epoch, epoch.total_blocks, epoch.total_pools), (209, 3, 3));
+    assert_eq!(epoch.versions.len(), 2);
+    assert_eq!(
+        (epoch.versions[0].protocol_major_version, epoch.versions[0].blocks),
+        (7, 2)
+    );
+    assert_eq!(epoch.versions[0].block_share, 66.67);
+    assert_eq!(epoch.versions[1].pool_share, 33.33);
+    let pool = versions.pool.unwrap();
+    assert_eq!((pool.block_number, pool.protocol_major_version), (3, 7));
+
+    let calculate = |epochs: i64, pool_id: Option<&str>| {
+        calculate_versions(
+            &test_db.path,
+            &test_db.byron_genesis,
+            &test_db.shelley_genesis,
+            epochs,
+            &pool_id.map(|pool_id| pool_id.to_string()),
+        )
+    };
+    assert!(calculate(1, Some("dd")).is_err());
+    assert!(calculate(0, None).is_err());
+    assert!(calculate(-3, None).is_err());
+}
+
+#[test]
+fn test_pool_stats() {
+    let test_db = TestDb::new("poolstats");
+    // Two blocks in epoch 208, none in 209, one and an orphan in 210, then another pool's block at the tip
+    for (block_number, slot_number, pool_id, block_size) in [
+        (1, 4492800, "aa", 1000),
+        (2, 4492900, "aa", 2000),
+        (3, 5356800, "aa", 1500),
+        (4, 5356900, "aa", 1500),
+        (5, 5357000, "bb", 1000),
+    ]
+    .iter()
+    {
+        let mut block = TestBlock::new(*block_number, *slot_number).pool_id(pool_id);
+        block.block_size = *block_size;
+        test_db.insert(&block);
+    }
+    test_db.execute("UPDATE chain SET orphaned = 1 WHERE block_number = 4");
+    let pool_stats = |epochs: Option<i64>, pool_id: &str| {
+        calculate_pool_stats(
+            &test_db.path,
+            &test_db.byron_genesis,
+            &test_db.shelley_genesis,
+            pool_id,
+            &epochs,
+        )
+    };
+
+    let stats = pool_stats(None, "aa").unwrap();
+    assert_eq!((stats.total_blocks, stats.total_orphans), (3, 1));
+    assert_eq!(stats.first_block.unwrap().block_number, 1);
+    assert_eq!(stats.last_block.unwrap().block_number, 3);
+    assert_eq!(stats.longest_gap_slots, 5356800 - 4492900);
+    assert_eq!(stats.epochs_without_blocks, 1);
+    let epochs: Vec<(i64, i64, i64)> = stats
+        .epochs
+        .iter()
+        .map(|epoch| (epoch.epoch, epoch.blocks, epoch.orphans))
+        .collect();
+    assert_eq!(epochs, vec![(208, 2, 0), (209, 0, 0), (210, 1, 1)]);
+    assert_eq!(stats.epochs[0].avg_block_size, 1500.0);
+    assert_eq!(
+        (stats.epochs[0].first_block_number, stats.epochs[0].last_block_number),
+        (Some(1), Some(2))
+    );
+
+    let stats = pool_stats(Some(2), "aa").unwrap();
+    assert_eq!(stats.epochs.first().unwrap().epoch, 209);
+    // The gap back to epoch 208 is outside of the last two epochs
+    assert_eq!(stats.longest_gap_slots, 0);
+    assert!(pool_stats(Some(0), "aa").is_err());
+    assert!(pool_stats(None, "cc").is_err());
+}
+
+#[test]
+fn test_chain_health() {
+    let test_db = TestDb::new("health");
+    for (block_number, slot_number) in [(1, 1000), (2, 1010), (3, 1020), (4, 1060), (5, 1099)].iter() {
+        test_db.insert(&TestBlock::new(*block_number, *slot_number));
+    }
+    test_db.execute("UPDATE chain SET orphaned = 1 WHERE block_number = 3");
+    let chain_health = |start_slot: i64, end_slot: i64, window: i64| {
+        calculate_chain_health(
+            &test_db.path,
+            &test_db.byron_genesis,
+            &test_db.shelley_genesis,
+            1,
+            &Some(start_slot),
+            &Some(end_slot),
+            &Some(window),
+            0.9,
+        )
+    };
+
+    let health = chain_health(1000, 1099, 50).unwrap();
+    assert_eq!(health.windows.len(), 2);
+    let first = &health.windows[0];
+    assert_eq!((first.start_slot, first.end_slot), (1000, 1049));
+    assert_eq!((first.blocks, first.orphans, first.longest_empty_slots), (2, 1, 39));
+    assert_eq!(
+        (first.density, first.density_ratio, first.fork_rate),
+        (0.04, 0.8, 0.333333)
+    );
+    assert!(first.low_density);
+    let second = &health.windows[1];
+    assert_eq!((second.blocks, second.orphans, second.longest_empty_slots), (2, 0, 38));
+    assert_eq!(health.low_density_windows, 2);
+    assert_eq!((health.summary.slots, health.summary.blocks), (100, 4));
+
+    // Windows without blocks in between still show up
+    let health = chain_health(1000, 1099, 7).unwrap();
+    assert_eq!(health.windows.len(), 15);
+    assert_eq!(health.windows.iter().map(|window| window.blocks).sum::<i64>(), 4);
+    assert_eq!(health.windows[14].slots, 2);
+
+    assert!(chain_health(1000, 1099, 0).is_err());
+    assert!(chain_health(1099, 1000, 50).is_err());
+}
+
+#[test]
+fn test_header_check() {
+    let signable = opcert_signable(&[0x11u8; 32], 5, 201);
+    assert_eq!(signable.len(), 48);
+    assert_eq!(
+        &signable[32..],
+        &hex::decode("000000000000000500000000000000c9").unwrap()[..]
+    );
+
+    // mainnet: 129600 slots per KES period, 62 evolutions
+    assert!(check_kes_period(27_000_000, 201, 129600, 62).is_ok());
+    assert!(check_kes_period(201 * 129600, 201, 129600, 62).is_ok());
+    assert!(check_kes_period(201 * 129600 - 1, 201, 129600, 62).is_err());
+    assert!(check_kes_period(263 * 129600 - 1, 201, 129600, 62).is_ok());
+    assert!(check_kes_period(263 * 129600, 201, 129600, 62).is_err());
+
+    assert_eq!(
+        parse_epoch_extra_entropy("259:d982e06f").unwrap(),
+        (259, "d982e06f".to_string())
+    );
+    assert!(parse_epoch_extra_entropy("d982e06f").is_err());
+    assert!(parse_epoch_extra_entropy("259:xyz").is_err());
+    assert!(parse_epoch_extra_entropy("epoch:d982e06f").is_err());
+}
+
+// A header for slot 5356900 in mainnet epoch 210. The opcert is signed with the RFC 8032 test 2 key and the VRF
+// proofs are made with the draft-irtf-cfrg-vrf-03 test key, for the nonce of the two blocks before it.
+fn signed_header(block_number: i64) -> BlockHeader {
+    BlockHeader {
+        block_number,
+        slot_number: 5356900,
+        hash: vec![block_number as u8; 32],
+        prev_hash: vec![2; 32],
+        node_vkey: hex::decode("3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c").unwrap(),
+        node_vrf_vkey: hex::decode("d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a").unwrap(),
+        eta_vrf_0: hex::decode(
+            "8c32426bae5d9474f682ed38dab30fabb258a1dbcf3ae904d5d6b0d77b9d45c9\
+            9abf618ea402aef0c644b048439cb3e77ab7784f716d86df628cb1793407c572",
+        )
+        .unwrap(),
+        eta_vrf_1: hex::decode(
+            "edf7a60aa0be7dc1100597ed845d7ea94612eac329a1da98c317d05e79612009\
+            2a06fcf6174a303a3fe571f9f0564f893bfb6507b52fa5fee78dc86f437cd8c6\
+            932b0ead1a85be71071d80a475f25e05",
+        )
+        .unwrap(),
+        leader_vrf_0: hex::decode(
+            "194fa60a08eeb1bc0e517c25ae1f17d03fec38f2977276421cfaf1899fc1bd4d\
+            1a83dce753c6196d31e8f539b2543d5b238968a562c6d95e21ebc51f34b07723",
+        )
+        .unwrap(),
+        leader_vrf_1: hex::decode(
+            "8af2ef63f994abf4fac098c49813369fd90b160e23a11c27ba522773719b5636\
+            4e5c927e8e295b9b0831f9fe163a8fb54c3a6d6d292111f9dda6420996130327\
+            af282c45756535361d19af2b5956ea05",
+        )
+        .unwrap(),
+        block_size: 1000,
+        block_body_hash: vec![0x22; 32],
+        pool_opcert: vec![0x11; 32],
+        unknown_0: 3,
+        unknown_1: 40,
+        unknown_2: hex::decode(
+            "edbf91ab15a8c5779c681f07e0830556dd6864869c2d4e645818a49c7f46fa1c\
+            db32b632b88bddd97a40963616c570c39f63fa1e5a0f6adee7e7277392b7d401",
+        )
+        .unwrap(),
+        protocol_major_version: 6,
+        protocol_minor_version: 0,
+    }
+}
+
+#[test]
+fn test_header_verify() {
+    let test_db = TestDb::new("header-verify");
+    let header_check = |mode| {
+        HeaderCheck::new(
+            mode,
+            read_byron_genesis(&test_db.byron_genesis).unwrap(),
+            read_shelley_genesis(&test_db.shelley_genesis).unwrap(),
+            Default::default(),
+        )
+    };
+    let header_errors = || -> Vec<(i64, Option<String>)> {
+        let mut stmt = test_db
+            .block_store
+            .db
+            .prepare("SELECT block_number, header_error FROM chain WHERE orphaned = 0 ORDER BY block_number")
+            .unwrap();
+        let rows = stmt
+            .query_map(rusqlite::NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap();
+        rows.map(|row| row.unwrap()).collect()
+    };
+
+    // The epoch 210 nonce comes from the prev_hash of the last block before epoch 209 and the eta_v of the last
+    // block before the stability window. Both are in the same batch as the headers, so they have to be saved first.
+    let mut nonce_block = signed_header(1);
+    nonce_block.slot_number = 4924799;
+    nonce_block.prev_hash = vec![0x0a; 32];
+    nonce_block.eta_vrf_0 = vec![0xaa; 64];
+    let mut eta_v_block = signed_header(2);
+    eta_v_block.slot_number = 5227199;
+    eta_v_block.prev_hash = vec![1; 32];
+    eta_v_block.eta_vrf_0 = vec![0xbb; 64];
+    // Signed for another slot, the VRF outputs still match their proofs
+    let mut wrong_slot = signed_header(4);
+    wrong_slot.slot_number += 1;
+    let mut wrong_counter = signed_header(5);
+    wrong_counter.unknown_0 += 1;
+
+    let mut block_store = SqLiteBlockStore::new(&test_db.path)
+        .unwrap()
+        .with_header_verifier(header_check(HeaderCheckMode::Flag).verifier());
+    let mut pending_blocks = vec![nonce_block, eta_v_block, signed_header(3), wrong_slot, wrong_counter];
+    block_store.save_block(&mut pending_blocks, 764824073).unwrap();
+    assert!(pending_blocks.is_empty());
+    let saved = header_errors();
+    assert_eq!(saved.len(), 5);
+    assert_eq!(saved[2], (3, None));
+    assert_eq!(saved[3], (4, Some("invalid eta VRF proof".to_string())));
+    assert_eq!(saved[4], (5, Some("invalid opcert signature".to_string())));
+
+    // Rejecting keeps the valid headers before the invalid one and tells sync to stop
+    let header_check = header_check(HeaderCheckMode::Reject);
+    let mut block_store = SqLiteBlockStore::new(&test_db.path)
+        .unwrap()
+        .with_header_verifier(header_check.verifier());
+    let mut wrong_slot = signed_header(5);
+    wrong_slot.slot_number += 1;
+    let mut pending_blocks = vec![signed_header(4), wrong_slot, signed_header(6)];
+    assert!(block_store.save_block(&mut pending_blocks, 764824073).is_err());
+    let saved = header_errors();
+    assert_eq!(saved.len(), 4);
+    assert_eq!(saved[3], (4, None));
+    assert!(header_check
+        .rejected()
+        .unwrap()
+        .ends_with("at block 5: invalid eta VRF proof"));
+}
+
+// Answers a single http request on a local port and hands back its request line and body
+fn mock_pooltool_server(response: &'static str) -> (String, Receiver<(String, String)>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let base_url = format!("http://{}", listener.local_addr().unwrap());
+    let (sender, receiver) = channel();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).unwrap();
+        let mut content_length = 0;
+        loop {
+            let mut header = String::new();
+            reader.read_line(&mut header).unwrap();
+            if header.trim().is_empty() {
+                break;
+            }
+            let mut parts = header.splitn(2, ':');
+            if let (Some(name), Some(value)) = (parts.next(), parts.next()) {
+                if name.eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().unwrap();
+                }
+            }
+        }
+        let mut body = vec![0u8; content_length];
+        reader.read_exact(&mut body).unwrap();
+        if !response.is_empty() {
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                response.len(),
+                response
+            )
+            .unwrap();
+        }
+        sender
+            .send((request_line.trim().to_string(), String::from_utf8(body).unwrap()))
+            .unwrap();
+        // Hold the connection open so a missing response runs into the client timeout
+        thread::sleep(Duration::from_secs(5));
+    });
+    (base_url, receiver)
+}
+
+#[test]
+fn test_pooltool_send_slots() {
+    let (base_url, requests) = mock_pooltool_server("{\"success\":true}");
+    let client = PooltoolClient::new(&format!("{}/", base_url), Duration::from_secs(5), &None).unwrap();
+    let response = client
+        .send_slots(&PooltoolSendSlots {
+            api_key: "api-key".to_string(),
+            pool_id: "00beef0a9be2f6d897ed24a613cf547bb20cd282a04edfc53d477114".to_string(),
+            epoch: 300,
+            slot_qty: 3,
+            hash: "abcd".to_string(),
+            override_time: None,
+            prev_slots: Some("[1,2,3]".to_string()),
+        })
+        .unwrap();
+    assert_eq!(response, "{\"success\":true}");
+
+    let (request_line, body) = requests.recv().unwrap();
+    assert_eq!(request_line, "POST /v0/sendslots HTTP/1.1");
+    assert_eq!(
+        body,
+        "{\"apiKey\":\"api-key\",\"poolId\":\"00beef0a9be2f6d897ed24a613cf547bb20cd282a04edfc53d477114\",\
+        \"epoch\":300,\"slotQty\":3,\"hash\":\"abcd\",\"prevSlots\":\"[1,2,3]\"}"
+    );
+}
+
+#[test]
+fn test_pooltool_timeout() {
+    let (base_url, requests) = mock_pooltool_server("");
+    let client = PooltoolClient::new(&base_url, Duration::from_secs(1), &None).unwrap();
+    let send_slots = PooltoolSendSlots {
+        api_key: "api-key".to_string(),
+        pool_id: "pool".to_string(),
+        epoch: 300,
+        slot_qty: 0,
+        hash: "".to_string(),
+        override_time: None,
+        prev_slots: None,
+    };
+    assert!(client.send_slots(&send_slots).is_err());
+    assert!(requests.recv().is_ok());
+}
+
+#[test]
+fn test_node_version() {
+    assert_eq!(
+        parse_cardano_node_version(
+            "cardano-node 1.30.1 - linux-x86_64 - ghc-8.10\ngit rev 0fb43f4e3da8b225f4f86557aed90a183981a64f\n"
+        ),
+        Some("1.30.1:0fb43".to_string())
+    );
+    assert_eq!(parse_cardano_node_version("cardano-node version unknown\n"), None);
+
+    let metrics = "# TYPE cardano_node_metrics_blockNum_int gauge\n\
+        cardano_node_metrics_blockNum_int 6421337\n\
+        cardano_build_info{version=\"8.1.2\",revision=\"d2d90b48c5577b4412d5c9c9968b55f8ab4b9767\"} 1\n";
+    assert_eq!(parse_prometheus_build_info(metrics), Some("8.1.2:d2d90".to_string()));
+    assert_eq!(
+        parse_prometheus_build_info("cardano_node_metrics_blockNum_int 6421337\n"),
+        None
+    );
+}
+
+#[test]
+fn test_outbox_retry() {
+    assert_eq!(backoff_secs(1), 60);
+    assert_eq!(backoff_secs(3), 240);
+    assert_eq!(backoff_secs(100), 21600);
+
+    let db_path = std::env::temp_dir().join(format!("cncli-outbox-{}.db", std::process::id()));
+    let outbox = Outbox::open(&db_path).unwrap();
+    outbox
+        .enqueue(SENDSLOTS, "pool", "{\"epoch\":300}", "connection refused")
+        .unwrap();
+
+    // Nothing is due until the backoff has expired
+    let (base_url, requests) = mock_pooltool_server("{\"success\":true}");
+    let client = PooltoolClient::new(&base_url, Duration::from_secs(5), &None).unwrap();
+    let outbox_flush = outbox.flush(&client, Some(SENDSLOTS), Some("pool"), false).unwrap();
+    assert_eq!((outbox_flush.sent, outbox_flush.failed), (0, 0));
+    assert_eq!(outbox.list().unwrap().len(), 1);
+
+    let outbox_flush = outbox.flush(&client, None, None, true).unwrap();
+    assert_eq!((outbox_flush.sent, outbox_flush.failed), (1, 0));
+    assert_eq!(
+        requests.recv().unwrap(),
+        ("POST /v0/sendslots HTTP/1.1".to_string(), "{\"epoch\":300}".to_string())
+    );
+    assert!(outbox.list().unwrap().is_empty());
+
+    // Only the newest tip of a pool waits, until a newer one gets through
+    outbox.enqueue(SENDSTATS, "pool", "{\"blockNo\":1}", "timeout").unwrap();
+    outbox.enqueue(SENDSTATS, "pool", "{\"blockNo\":2}", "timeout").unwrap();
+    outbox
+        .enqueue(SENDSTATS, "other", "{\"blockNo\":2}", "timeout")
+        .unwrap();
+    let entries = outbox.list().unwrap();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(
+        (entries[0].pool_id.as_str(), entries[0].payload.as_str()),
+        ("pool", "{\"blockNo\":2}")
+    );
+    assert_eq!(outbox.discard(SENDSTATS, "pool").unwrap(), 1);
+    assert_eq!(outbox.list().unwrap()[0].pool_id, "other");
+
+    drop(outbox);
+    std::fs::remove_file(&db_path).unwrap();
+}
+
+#[test]
+fn test_fsck() {
+    let test_db = TestDb::new("fsck");
+    let mut eta_v = vec![0u8; 32];
+    for block_number in 1..=5i64 {
+        let eta_vrf_0 = vec![block_number as u8; 64];
+        let node_vkey = vec![7u8; 32];
+        eta_v = next_eta_v(&eta_v, &eta_vrf_0);
+        let mut block = TestBlock::new(block_number, block_number * 20).pool_id(&hex::encode(pool_id(&node_vkey)));
+        block.eta_v = hex::encode(&eta_v);
+        block.node_vkey = hex::encode(&node_vkey);
+        block.eta_vrf_0 = hex::encode(&eta_vrf_0);
+        test_db.insert(&block);
+    }
+    assert_eq!(fsck_db(&test_db.path, false, 100).unwrap().status, "ok");
+
+    // A fork block left on the chain at height 4, a bad nonce at 2 and a bad pool id at 5
+    test_db.execute(
+        "INSERT INTO chain (block_number, slot_number, hash, prev_hash, pool_id, eta_v, node_vkey, node_vrf_vkey, \
+        eta_vrf_0, eta_vrf_1, leader_vrf_0, leader_vrf_1, block_size, block_body_hash, pool_opcert, \
+        opcert_counter, opcert_kes_period, opcert_signature, protocol_major_version, protocol_minor_version) \
+        SELECT block_number, slot_number + 1, 'ff' || substr(hash, 3), prev_hash, pool_id, eta_v, node_vkey, \
+        node_vrf_vkey, eta_vrf_0, eta_vrf_1, leader_vrf_0, leader_vrf_1, block_size, block_body_hash, \
+        pool_opcert, opcert_counter, opcert_kes_period, opcert_signature, protocol_major_version, \
+        protocol_minor_version FROM chain WHERE block_number = 4",
+    );
+    test_db.execute("UPDATE chain SET eta_v = 'aa' WHERE block_number = 2");
+    test_db.execute("UPDATE chain SET pool_id = 'bb' WHERE block_number = 5");
+
+    let fsck_report = fsck_db(&test_db.path, false, 100).unwrap();
+    assert_eq!(fsck_report.status, "inconsistent");
+    assert_eq!(fsck_report.checked, 6);
+    assert_eq!(fsck_report.issue_kinds.get(ETA_V_MISMATCH), Some(&1));
+    assert_eq!(fsck_report.issue_kinds.get(POOL_ID_MISMATCH), Some(&1));
+    assert_eq!(fsck_report.issue_kinds.get(DUPLICATE_BLOCK), Some(&1));
+    assert!(fsck_report.repaired.is_none());
+
+    let fsck_report = fsck_db(&test_db.path, true, 100).unwrap();
+    assert_eq!(fsck_report.status, "repaired");
+    let fsck_repair = fsck_report.repaired.unwrap();
+    assert_eq!(
+        (
+            fsck_repair.orphans_remarked,
+            fsck_repair.eta_v_fixed,
+            fsck_repair.pool_id_fixed
+        ),
+        (1, 1, 1)
+    );
+    assert_eq!(fsck_db(&test_db.path, false, 100).unwrap().status, "ok");
+}
+
+#[test]
+fn test_validate_lookup() {
+    let test_db = TestDb::new("validate");
+    for block_number in 1..=3i64 {
+        test_db.insert(&TestBlock::new(block_number, block_number * 10));
+    }
+    // An orphaned sibling of block 2 one slot later
+    test_db.execute(
+        "INSERT INTO chain (block_number, slot_number, hash, prev_hash, pool_id, eta_v, node_vkey, node_vrf_vkey, \
+        eta_vrf_0, eta_vrf_1, leader_vrf_0, leader_vrf_1, block_size, block_body_hash, pool_opcert, \
+        opcert_counter, opcert_kes_period, opcert_signature, protocol_major_version, protocol_minor_version, \
+        orphaned) \
+        SELECT block_number, slot_number + 1, 'ff' || substr(hash, 3), prev_hash, pool_id, eta_v, node_vkey, \
+        node_vrf_vkey, eta_vrf_0, eta_vrf_1, leader_vrf_0, leader_vrf_1, block_size, block_body_hash, \
+        pool_opcert, opcert_counter, opcert_kes_period, opcert_signature, protocol_major_version, \
+        protocol_minor_version, 1 FROM chain WHERE block_number = 2",
+    );
+
+    // By number the block on the chain comes first, with its orphaned sibling among the matches
+    let validation = find_block(&test_db.block_store.db, &BlockQuery::BlockNumber(2))
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        (validation.block.slot_number, validation.block.status.as_str()),
+        (20, "ok")
+    );
+    let statuses: Vec<&str> = validation.matches.iter().map(|block| block.status.as_str()).collect();
+    assert_eq!(statuses, vec!["ok", "orphaned"]);
+
+    // By slot the orphan is the block that was asked for
+    let validation = find_block(&test_db.block_store.db, &BlockQuery::Slot(21))
+        .unwrap()
+        .unwrap();
+    assert_eq!((validation.block.block_number, validation.block.orphaned), (2, true));
+    assert_eq!(validation.matches.len(), 2);
+
+    let validation = find_block(&test_db.block_store.db, &BlockQuery::Slot(30))
+        .unwrap()
+        .unwrap();
+    assert_eq!(validation.block.hash, format!("{:064x}", 3));
+    assert!(validation.matches.is_empty());
+
+    // Hash prefixes are case insensitive
+    let validation = find_block(&test_db.block_store.db, &BlockQuery::HashPrefix("FF"))
+        .unwrap()
+        .unwrap();
+    assert_eq!((validation.block.slot_number, validation.block.orphaned), (21, true));
+
+    assert!(find_block(&test_db.block_store.db, &BlockQuery::BlockNumber(9))
+        .unwrap()
+        .is_none());
+    assert!(find_block(&test_db.block_store.db, &BlockQuery::HashPrefix("xyz")).is_err());
+}
+
+#[test]
+fn test_snapshot() {
+    let test_db = TestDb::new("snapshot");
+    let snapshot_path = test_db.file("snapshot.tar.gz");
+    let restored_path = test_db.file("restored.db");
+    for block_number in 1..=3i64 {
+        test_db.insert(&TestBlock::new(block_number, block_number * 20));
+    }
+
+    // The db is still open, as it would be with sync running
+    let manifest = create_snapshot(&test_db.path, &snapshot_path, 764824073).unwrap();
+    assert_eq!(manifest.network_magic, 764824073);
+    assert_eq!(manifest.tip.block_number, 3);
+    assert_eq!(manifest.tip.slot_number, 60);
+    assert_eq!(manifest.tip.hash, format!("{:064x}", 3));
+
+    assert!(restore_snapshot(&snapshot_path, &restored_path, Some(1097911063), false).is_err());
+    assert!(!restored_path.exists());
+    let restored = restore_snapshot(&snapshot_path, &restored_path, Some(764824073), false).unwrap();
+    assert_eq!(restored.checksum, manifest.checksum);
+    let restored_store = SqLiteBlockStore::new(&restored_path).unwrap();
+    let blocks: i64 = restored_store
+        .db
+        .query_row("SELECT COUNT(*) FROM chain", rusqlite::NO_PARAMS, |row| row.get(0))
+        .unwrap();
+    assert_eq!(blocks, 3);
+    drop(restored_store);
+
+    // An existing db is only replaced when forced
+    assert!(restore_snapshot(&snapshot_path, &restored_path, None, false).is_err());
+    assert!(restore_snapshot(&snapshot_path, &restored_path, None, true).is_ok());
+
+    // A truncated snapshot is refused
+    let snapshot = std::fs::read(&snapshot_path).unwrap();
+    std::fs::write(&snapshot_path, &snapshot[..snapshot.len() / 2]).unwrap();
+    assert!(restore_snapshot(&snapshot_path, &restored_path, None, true).is_err());
+}
+
+#[test]
+fn test_export() {
+    let test_db = TestDb::new("export");
+    let output = test_db.file("export.out");
+
+    // The first block of mainnet epoch 208 and two blocks in epoch 209
+    for (block_number, slot_number, pool_id) in [(1, 4492800, "aa"), (2, 4924800, "bb"), (3, 4924900, "aa")].iter() {
+        test_db.insert(&TestBlock::new(*block_number, *slot_number).pool_id(pool_id));
+    }
+    test_db.execute(
+        "INSERT INTO slots (epoch, pool_id, slot_qty, slots, hash) VALUES \
+        (208, 'aa', 2, '[4492801,4492802]', 'h1'), (209, 'aa', 1, '[4924801]', 'h2')",
+    );
+
+    let mut options = ExportOptions {
+        table: ExportTable::Chain,
+        format: ExportFormat::Csv,
+        from_epoch: Some(209),
+        to_epoch: None,
+        pool_id: None,
+        byron_genesis: Some(test_db.byron_genesis.clone()),
+        shelley_genesis: Some(test_db.shelley_genesis.clone()),
+    };
+    let export = |options: &ExportOptions| -> (usize, String) {
+        let rows = export_table(
+            &test_db.path,
+            options,
+            Box::new(std::fs::File::create(&output).unwrap()),
+        )
+        .unwrap();
+        (rows, std::fs::read_to_string(&output).unwrap())
+    };
+    let (rows, csv) = export(&options);
+    assert_eq!(rows, 2);
+    let lines: Vec<&str> = csv.lines().collect();
+    assert!(lines[0].starts_with("block_number,slot_number,epoch,hash,"));
+    assert!(lines[0].ends_with(",orphaned,header_error"));
+    assert!(lines[1].starts_with("2,4924800,209,"));
+    assert!(lines[2].starts_with("3,4924900,209,"));
+    assert!(lines[2].ends_with(",6,0,false,"));
+
+    options.format = ExportFormat::Jsonl;
+    options.from_epoch = None;
+    options.pool_id = Some("aa".to_string());
+    let (rows, jsonl) = export(&options);
+    assert_eq!(rows, 2);
+    let first: serde_json::Value = serde_json::from_str(jsonl.lines().next().unwrap()).unwrap();
+    assert_eq!(first["epoch"], 208);
+    assert_eq!(first["header_error"], serde_json::Value::Null);
+    assert!(jsonl.starts_with(r#"{"block_number":1,"slot_number":4492800,"epoch":208,"#));
+
+    // The slots table has its own epoch and does not need the genesis files
+    options.table = ExportTable::Slots;
+    options.to_epoch = Some(208);
+    options.byron_genesis = None;
+    options.shelley_genesis = None;
+    let (rows, jsonl) = export(&options);
+    assert_eq!(rows, 1);
+    assert_eq!(
+        jsonl,
+        "{\"epoch\":208,\"pool_id\":\"aa\",\"slot_qty\":2,\"slots\":\"[4492801,4492802]\",\"hash\":\"h1\"}\n"
+    );
+
+    options.table = ExportTable::Chain;
+    assert!(export_table(&test_db.path, &options, Box::new(std::io::sink())).is_err());
+
+    // An older db is refused rather than migrated
+    options.byron_genesis = Some(test_db.byron_genesis.clone());
+    options.shelley_genesis = Some(test_db.shelley_genesis.clone());
+    test_db.execute("UPDATE db_version SET version = 6");
+    let error = export_table(&test_db.path, &options, Box::new(std::io::sink())).unwrap_err();
+    assert!(error.starts_with("database is at version 6"));
+}
+
+#[test]
+fn test_serve_api() {
+    let test_db = TestDb::new("serve");
+
+    // Blocks before the start of epoch 208 and before the stability window of epoch 209 give the epoch 209 nonce
+    for (block_number, slot_number, pool_id) in [(1, 4492790, "aa"), (2, 4795100, "bb"), (3, 4924800, "aa")].iter() {
+        let mut block = TestBlock::new(*block_number, *slot_number).pool_id(pool_id);
+        block.eta_v = format!("{:064x}", block_number * 100);
+        test_db.insert(&block);
+    }
+    test_db.execute(
+        "INSERT INTO slots (epoch, pool_id, slot_qty, slots, hash) VALUES (208, 'aa', 2, '[4492801,4492802]', 'h')",
+    );
+
+    let api = Api::new(
+        &test_db.path,
+        &test_db.byron_genesis,
+        &test_db.shelley_genesis,
+        "UTC",
+        Default::default(),
+    )
+    .unwrap();
+    let get = |path: &str| -> (u16, serde_json::Value) {
+        let (status_code, body) = api.handle("GET", path);
+        (status_code, serde_json::from_str(&body).unwrap())
+    };
+
+    let (status_code, status) = get("/status");
+    assert_eq!(status_code, 503);
+    assert_eq!(status["errorMessage"], "db not fully synced!");
+
+    let (status_code, tip) = get("/tip");
+    assert_eq!(status_code, 200);
+    assert_eq!(tip["epoch"], 209);
+    assert_eq!(tip["blockNumber"], 3);
+    assert_eq!(tip["slotNumber"], 4924800);
+
+    let expected_nonce = blake2b_simd::Params::new()
+        .hash_length(32)
+        .to_state()
+        .update(&hex::decode(format!("{:064x}{:064x}", 200, 0)).unwrap())
+        .finalize();
+    let (status_code, nonce) = get("/nonce/209");
+    assert_eq!(status_code, 200);
+    assert_eq!(nonce["epochNonce"], hex::encode(expected_nonce.as_bytes()));
+    // The chain has not reached the stability window of epoch 210 yet
+    assert_eq!(get("/nonce/210").0, 404);
+    assert_eq!(get("/nonce/next").0, 400);
+
+    let (status_code, leader_log) = get("/leaderlog/208/aa");
+    assert_eq!(status_code, 200);
+    assert_eq!(leader_log["epochSlots"], 2);
+    assert_eq!(leader_log["assignedSlots"][1]["slotInEpoch"], 2);
+    assert_eq!(leader_log["assignedSlots"][1]["at"], "2020-07-29T21:44:53+00:00");
+    assert_eq!(get("/leaderlog/209/aa").0, 404);
+
+    let (status_code, block) = get(&format!("/block/{:064x}", 2));
+    assert_eq!(status_code, 200);
+    assert_eq!(block["pool_id"], "bb");
+    assert_eq!(get(&format!("/block/{:064x}", 9)).0, 404);
+    assert_eq!(get("/block/xyz").0, 400);
+
+    let (status_code, pool_blocks) = get("/pool/aa/blocks?limit=1");
+    assert_eq!(status_code, 200);
+    assert_eq!(pool_blocks["blocks"].as_array().unwrap().len(), 2);
+    assert_eq!(get("/pool/cc/blocks").0, 404);
+
+    assert_eq!(api.handle("POST", "/tip").0, 405);
+    assert_eq!(get("/tips").0, 404);
+}
+
+#[test]
+fn test_watch_ping_history() {
+    assert_eq!(parse_duration_secs("30"), Ok(30));
+    assert_eq!(parse_duration_secs("30s"), Ok(30));
+    assert_eq!(parse_duration_secs("5m"), Ok(300));
+    assert_eq!(parse_duration_secs("24h"), Ok(86400));
+    assert_eq!(parse_duration_secs("7d"), Ok(604800));
+    assert!(parse_duration_secs("0s").is_err());
+    assert!(parse_duration_secs("soon").is_err());
+
+    let db_path = std::env::temp_dir().join(format!("cncli-ping-history-{}.db", std::process::id()));
+    let ping_history = PingHistory::open(&db_path).unwrap();
+    ping_history.record("relay1", 3001, Ok((10, 40)), 1000).unwrap();
+    ping_history
+        .record("relay1", 3001, Err("connection timed out"), 1030)
+        .unwrap();
+    ping_history.record("relay1", 3001, Ok((10, 20)), 1060).unwrap();
+    ping_history.record("relay1", 3001, Ok((10, 30)), 1090).unwrap();
+    ping_history.record("relay2", 3001, Ok((5, 15)), 1090).unwrap();
+    // Too old for the window
+    ping_history
+        .record("relay2", 3001, Err("connection timed out"), 900)
+        .unwrap();
+
+    let peers = ping_history.uptime(1000).unwrap();
+    drop(ping_history);
+    std::fs::remove_file(&db_path).unwrap();
+
+    assert_eq!(peers.len(), 2);
+    assert_eq!((peers[0].host.as_str(), peers[0].pings, peers[0].ok), ("relay1", 4, 3));
+    assert_eq!(peers[0].uptime_pct, 75.0);
+    assert_eq!(peers[0].flaps, 2);
+    assert_eq!(peers[0].last_status, "ok");
+    assert_eq!(
+        (
+            peers[0].latency.min_ms,
+            peers[0].latency.p50_ms,
+            peers[0].latency.max_ms
+        ),
+        (20, 30, 40)
+    );
+    assert_eq!(
+        (peers[1].host.as_str(), peers[1].pings, peers[1].uptime_pct),
+        ("relay2", 1, 100.0)
+    );
+}
+
+#[test]
+fn test_pooltool_config_network_magic() {
+    // Pools are on mainnet unless they set a network magic
+    let pooltool_config: PooltoolConfig = serde_json::from_str(
+        "{\"api_key\":\"key\",\"pools\":[\
+        {\"name\":\"BEEF\",\"pool_id\":\"00beef0a\",\"host\":\"127.0.0.1\",\"port\":3001},\
+        {\"name\":\"PREVIEW\",\"pool_id\":\"feedbeef\",\"host\":\"127.0.0.1\",\"port\":3002,\"network_magic\":2}]}",
+    )
+    .unwrap();
+    let network_magics: Vec<u32> = pooltool_config.pools.iter().map(|pool| pool.network_magic).collect();
+    assert_eq!(network_magics, vec![764824073, 2]);
+}
+
+#[test]
+fn test_sendtip_relays() {
+    let pool = |pool_name: &str, host: &str, network_magic: u32| SendtipPool {
+        pool_name: pool_name.to_string(),
+        pool_id: "00beef0a".to_string(),
+        host: host.to_string(),
+        port: 3001,
+        network_magic,
+        api_key: "key".to_string(),
+        node_version_sources: vec![],
+    };
+    // Pools on the same relay and network share a connection
+    let relays = group_by_relay(vec![
+        pool("BEEF", "relay1", 764824073),
+        pool("PREVIEW", "relay1", 2),
+        pool("FEED", "relay1", 764824073),
+        pool("CAFE", "relay2", 764824073),
+    ]);
+    let relays: Vec<(String, u32, Vec<String>)> = relays
+        .into_iter()
+        .map(|((host, _, network_magic), pools)| {
+            (
+                host,
+                network_magic,
+                pools.into_iter().map(|pool| pool.pool_name).collect(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        relays,
+        vec![
+            ("relay1".to_string(), 2, vec!["PREVIEW".to_string()]),
+            (
+                "relay1".to_string(),
+                764824073,
+                vec!["BEEF".to_string(), "FEED".to_string()]
+            ),
+            ("relay2".to_string(), 764824073, vec!["CAFE".to_string()]),
+        ]
+    );
+}
+
+#[test]
+fn test_webhook_payload() {
+    let event = WebhookEvent {
+        event: BLOCK_MINTED.to_string(),
+        block_number: 6421337,
+        slot_number: 43587312,
+        hash: "abcd".to_string(),
+        pool_id: Some("00beef0a".to_string()),
+        behind_secs: None,
+        at: "2021-10-15T21:35:12.000Z".to_string(),
+    };
+
+    let slack: Webhook =
+        serde_json::from_str("{\"url\":\"http://localhost\",\"format\":\"slack\",\"events\":[\"block_minted\"]}")
+            .unwrap();
+    assert!(slack.accepts(&event));
+    assert_eq!(
+        slack.payload(&event),
+        "{\"text\":\"Pool 00beef0a minted block 6421337, slot 43587312, hash abcd\"}"
+    );
+
+    let telegram: Webhook = serde_json::from_str(
+        "{\"url\":\"http://localhost\",\"format\":\"telegram\",\"chat_id\":\"-100123\",\
+        \"pool_ids\":[\"feedbeef\"],\"template\":\"{event} {block_number}\"}",
+    )
+    .unwrap();
+    assert!(!telegram.accepts(&event));
+    let tip = WebhookEvent {
+        event: TIP.to_string(),
+        block_number: 6421337,
+        slot_number: 43587312,
+        hash: "abcd".to_string(),
+        pool_id: None,
+        behind_secs: None,
+        at: "2021-10-15T21:35:12.000Z".to_string(),
+    };
+    // Tips are opt-in, a hook without events only gets the other events
+    assert!(!telegram.accepts(&tip));
+    assert_eq!(
+        telegram.payload(&tip),
+        "{\"chat_id\":\"-100123\",\"text\":\"tip 6421337\"}"
+    );
+
+    let json: Webhook = serde_json::from_str("{\"url\":\"http://localhost\",\"events\":[\"tip\"]}").unwrap();
+    assert!(json.accepts(&tip) && !json.accepts(&event));
+    assert_eq!(
+        json.payload(&event),
+        "{\"event\":\"block_minted\",\"blockNumber\":6421337,\"slotNumber\":43587312,\"hash\":\"abcd\",\
+        \"poolId\":\"00beef0a\",\"at\":\"2021-10-15T21:35:12.000Z\"}"
+    );
+}
+
+#[test]
+fn test_webhook_block_minted() {
+    let test_db = TestDb::new("webhook-minted");
+    test_db.insert(&TestBlock::new(1, 5356800));
+    let (url, requests) = mock_pooltool_server("{}");
+    let our_block = signed_header(2);
+    let config: WebhookConfig = serde_json::from_str(&format!(
+        "{{\"pool_ids\":[\"{}\"],\"timeout\":1,\"hooks\":[{{\"url\":\"{}/minted\"}}]}}",
+        hex::encode(pool_id(&our_block.node_vkey)),
+        url
+    ))
+    .unwrap();
+    let webhook_notifier = WebhookNotifier::start(config, &test_db.path).unwrap();
+
+    // Our blocks are announced as they are saved, not only when they are the tip
+    let mut other_block = signed_header(3);
+    other_block.node_vkey = vec![0x22; 32];
+    let mut block_store = SqLiteBlockStore::new(&test_db.path)
+        .unwrap()
+        .with_webhook_notifier(webhook_notifier.clone());
+    let mut pending_blocks = vec![our_block, other_block];
+    block_store.save_block(&mut pending_blocks, 764824073).unwrap();
+    webhook_notifier.flush();
+    let (request_line, body) = requests.try_recv().unwrap();
+    assert_eq!(request_line, "POST /minted HTTP/1.1");
+    assert!(body.starts_with("{\"event\":\"block_minted\",\"blockNumber\":2,\"slotNumber\":5356900,"));
+}
+
+#[test]
+fn test_propagation_delays() {
+    let mut window = DelayWindow::new(100);
+    assert_eq!(window.stats(), DelayStats::default());
+
+    // The oldest delays drop out once the window is full
+    for delay_ms in (1..=150).rev() {
+        window.push(delay_ms * 10);
+    }
+    assert_eq!(
+        window.stats(),
+        DelayStats {
+            count: 100,
+            last_ms: 10,
+            min_ms: 10,
+            p50_ms: 500,
+            p90_ms: 900,
+            p95_ms: 950,
+            p99_ms: 990,
+            max_ms: 1000,
+        }
+    );
+}
+
+#[test]
+fn test_stake_estimate() {
+    // A pool with 1% of the stake leads ~0.05% of slots at f = 0.05
+    let sigma = sigma_from_leader_probability(216.0 / 432000.0, 0.05);
+    assert!((sigma - 0.009750).abs() < 0.000001);
+    assert_eq!(sigma_from_leader_probability(0.0, 0.05), 0.0);
+
+    let (low, high) = wilson_interval(216, 432000, 1.96);
+    assert!(low < 216.0 / 432000.0 && high > 216.0 / 432000.0);
+    assert!((low - 0.000438).abs() < 0.000001);
+    assert!((high - 0.000571).abs() < 0.000001);
+}
+
 #[test]
 fn test_eps() {
     let eps = BigDecimal::from_str("1.E-24").unwrap();